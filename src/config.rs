@@ -0,0 +1,569 @@
+//! The config file, `$XDG_CONFIG_HOME/roll/config.toml` or
+//! `~/.config/roll/config.toml` unless `--config` names another, or
+//! `--no-config` skips it. Its `[defaults]` table sets flags left off the
+//! command line, its `[macros]` table names expressions to roll by name, and
+//! its `[step]` table replaces the die sizes `stepN` moves along:
+//!
+//! ```toml
+//! [defaults]
+//! style = "rounded"
+//! verbose = true
+//!
+//! [step]
+//! chain = [4, 6, 8, 10, 12, 20]
+//!
+//! [macros]
+//! attack = "1d20+7"
+//! damage = "2d6+4"
+//! turn = "attack damage"
+//! ```
+
+use crate::{
+    Args, ColorChoice, Comparison, Sort, TableStyle, is_word, parse_dice_expression, parse_pasted,
+    parse_threshold,
+};
+use anyhow::{Result, anyhow, bail};
+use clap::{ArgMatches, ValueEnum, parser::ValueSource};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use toml::Spanned;
+
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    /// Where it was read from, if there was a file
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) macros: BTreeMap<String, String>,
+    pub(crate) defaults: Defaults,
+    /// The die sizes `stepN` moves along, smallest first, if not the usual
+    pub(crate) step_chain: Option<Vec<i64>>,
+}
+
+/// The file as written, before its names and choices are checked.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    macros: BTreeMap<Spanned<String>, String>,
+    #[serde(default)]
+    defaults: RawDefaults,
+    #[serde(default)]
+    step: RawStep,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawStep {
+    chain: Option<Spanned<Vec<i64>>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawDefaults {
+    format: Option<Spanned<String>>,
+    style: Option<Spanned<String>>,
+    color: Option<Spanned<String>>,
+    sort: Option<Spanned<String>>,
+    totals: Option<bool>,
+    verbose: Option<bool>,
+    stats: Option<bool>,
+    fancy: Option<bool>,
+    samples: Option<Spanned<u64>>,
+    crit: Option<Spanned<String>>,
+}
+
+/// How to print rolls, for the `format` default. Each is the flag of the
+/// same name.
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+pub(crate) enum Format {
+    Table,
+    Plain,
+    Json,
+    Jsonl,
+    Csv,
+    Markdown,
+    Sum,
+}
+
+/// The `[defaults]` table: a value for each flag that a command line
+/// leaves out, and a `crit` range for every d20 without its own `cs`.
+///
+/// ```toml
+/// [defaults]
+/// format = "plain"
+/// style = "rounded"
+/// color = "never"
+/// totals = false
+/// crit = ">=19"
+/// ```
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Defaults {
+    pub(crate) format: Option<Format>,
+    pub(crate) style: Option<TableStyle>,
+    pub(crate) color: Option<ColorChoice>,
+    pub(crate) sort: Option<Sort>,
+    /// False for --no-total
+    pub(crate) totals: Option<bool>,
+    pub(crate) verbose: Option<bool>,
+    pub(crate) stats: Option<bool>,
+    pub(crate) fancy: Option<bool>,
+    pub(crate) samples: Option<u64>,
+    /// The crit range of a d20 term with no `cs` of its own
+    pub(crate) crit: Option<Comparison>,
+}
+
+impl Defaults {
+    /// Fills in `args` from these defaults wherever `matches` shows the
+    /// command line didn't give the flag. A flag that conflicts with a
+    /// default, as --no-total does with `format = "sum"`, wins over it too.
+    pub(crate) fn apply(&self, args: &mut Args, matches: &ArgMatches) {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        let formats = ["table", "plain", "json", "jsonl", "csv", "markdown", "sum"];
+        if let Some(format) = self.format
+            && !formats.iter().any(|id| given(id))
+            && !(format == Format::Sum && given("no_total"))
+            && !(matches!(format, Format::Sum | Format::Jsonl) && given("stats"))
+        {
+            match format {
+                Format::Table => args.table = true,
+                Format::Plain => args.plain = true,
+                Format::Json => args.json = true,
+                Format::Jsonl => args.jsonl = true,
+                Format::Csv => args.csv = true,
+                Format::Markdown => args.markdown = true,
+                Format::Sum => args.sum = true,
+            }
+        }
+        if let Some(style) = self.style.filter(|_| !given("style")) {
+            args.style = style;
+        }
+        if let Some(color) = self.color.filter(|_| !given("color")) {
+            args.color = color;
+        }
+        if let Some(sort) = self.sort.filter(|_| !given("sort")) {
+            args.sort = Some(sort);
+        }
+        if let Some(totals) = self.totals.filter(|_| !given("no_total") && !given("sum")) {
+            args.no_total = !totals;
+        }
+        if let Some(verbose) = self.verbose.filter(|_| !given("verbose")) {
+            args.verbose = verbose;
+        }
+        if let Some(stats) = self
+            .stats
+            .filter(|_| !given("stats") && !given("jsonl") && !given("sum"))
+        {
+            args.stats = stats;
+        }
+        if let Some(fancy) = self.fancy.filter(|_| !given("fancy")) {
+            args.fancy = fancy;
+        }
+        if let Some(samples) = self.samples.filter(|_| !given("samples")) {
+            args.samples = samples;
+        }
+    }
+}
+
+/// Reads one of the flag values named by `T`, such as a style, the same
+/// way the flag does.
+fn choice<T: ValueEnum>(
+    value: Option<Spanned<String>>,
+    key: &str,
+    at: impl Fn(Range<usize>, &str) -> anyhow::Error,
+) -> Result<Option<T>> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    T::from_str(value.get_ref(), true).map(Some).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|possible| possible.get_name().to_string())
+            .collect();
+        let message = format!(
+            "'{}' is not a {}; expected one of {}",
+            value.get_ref(),
+            key,
+            names.join(", ")
+        );
+        at(value.span(), &message)
+    })
+}
+
+/// Where the config file is looked for when `--config` isn't given:
+/// `roll/config.toml` in `XDG_CONFIG_HOME` if that is set to an absolute
+/// path, as the spec asks, or else in `~/.config`.
+fn default_path(xdg_config_home: Option<&str>, home: Option<&str>) -> Option<PathBuf> {
+    let dir = match xdg_config_home {
+        Some(dir) if Path::new(dir).is_absolute() => PathBuf::from(dir),
+        _ => Path::new(home.filter(|home| !home.is_empty())?).join(".config"),
+    };
+    Some(dir.join("roll").join("config.toml"))
+}
+
+impl Config {
+    /// Reads the config at `path`, or at the default path if `None`. Only
+    /// a file asked for by name has to exist.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => {
+                let xdg = env::var("XDG_CONFIG_HOME").ok();
+                let home = env::var("HOME").ok();
+                match default_path(xdg.as_deref(), home.as_deref()) {
+                    Some(path) => (path, false),
+                    None => return Ok(Config::default()),
+                }
+            }
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            }
+            Err(e) => bail!("Error: Can't read '{}': {}", path.display(), e),
+        };
+        Config::parse(&contents, path)
+    }
+
+    fn parse(contents: &str, path: PathBuf) -> Result<Config> {
+        let at = |span: Range<usize>, message: &str| {
+            // Lines and columns count from 1, as editors show them.
+            let before = &contents[..span.start];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            anyhow!("Error: {}:{}:{}: {}", path.display(), line, column, message)
+        };
+        let file: File = toml::from_str(contents).map_err(|e| match e.span() {
+            Some(span) => at(span, e.message().trim_end()),
+            None => anyhow!("Error: {}: {}", path.display(), e.message().trim_end()),
+        })?;
+        let mut macros = BTreeMap::new();
+        for (name, expression) in file.macros {
+            if !is_word(name.get_ref()) {
+                let message = format!(
+                    "'{}' is not a valid macro name; use letters, digits and underscores",
+                    name.get_ref()
+                );
+                return Err(at(name.span(), &message));
+            }
+            if parse_dice_expression(name.get_ref()).is_ok_and(|(rest, _)| rest.is_empty()) {
+                let message = format!(
+                    "'{}' looks like a die and cannot be a macro name",
+                    name.get_ref()
+                );
+                return Err(at(name.span(), &message));
+            }
+            macros.insert(name.into_inner(), expression);
+        }
+        let raw = file.defaults;
+        if let Some(format) = &raw.format
+            && format.get_ref().eq_ignore_ascii_case("sum")
+            && raw.totals == Some(false)
+        {
+            return Err(at(
+                format.span(),
+                "format = \"sum\" prints only the total, so totals can't be false",
+            ));
+        }
+        if let Some(format) = &raw.format
+            && ["sum", "jsonl"]
+                .iter()
+                .any(|name| format.get_ref().eq_ignore_ascii_case(name))
+            && raw.stats == Some(true)
+        {
+            return Err(at(
+                format.span(),
+                &format!(
+                    "format = \"{}\" doesn't show stats, so stats can't be true",
+                    format.get_ref()
+                ),
+            ));
+        }
+        let defaults = Defaults {
+            format: choice(raw.format, "format", at)?,
+            style: choice(raw.style, "style", at)?,
+            color: choice(raw.color, "color", at)?,
+            sort: choice(raw.sort, "sort", at)?,
+            totals: raw.totals,
+            verbose: raw.verbose,
+            stats: raw.stats,
+            fancy: raw.fancy,
+            samples: match raw.samples {
+                Some(samples) if *samples.get_ref() == 0 => {
+                    return Err(at(samples.span(), "samples must be at least 1"));
+                }
+                samples => samples.map(Spanned::into_inner),
+            },
+            crit: match raw.crit {
+                Some(crit) => Some(
+                    parse_threshold(crit.get_ref())
+                        .map_err(|e| at(crit.span(), &format!("crit {}", e)))?,
+                ),
+                None => None,
+            },
+        };
+        let step_chain = match file.step.chain {
+            Some(chain) if chain.get_ref().is_empty() => {
+                return Err(at(chain.span(), "the step chain needs at least one die"));
+            }
+            Some(chain)
+                if chain.get_ref()[0] <= 0
+                    || chain.get_ref().windows(2).any(|pair| pair[0] >= pair[1]) =>
+            {
+                return Err(at(
+                    chain.span(),
+                    "the step chain must list die sizes from smallest to largest",
+                ));
+            }
+            chain => chain.map(Spanned::into_inner),
+        };
+        Ok(Config {
+            path: Some(path),
+            macros,
+            defaults,
+            step_chain,
+        })
+    }
+
+    /// `arg` with any macros in it expanded, if it doesn't already parse as
+    /// dice. A macro is a whole word between spaces and commas, and its
+    /// expression can name other macros, but never itself.
+    pub(crate) fn expand(&self, arg: &str) -> Result<String> {
+        if self.macros.is_empty() || parse_pasted(0, arg).is_ok() {
+            return Ok(arg.to_string());
+        }
+        self.expand_words(arg, &mut Vec::new())
+    }
+
+    /// Expands the macros in `text`, with `stack` those being expanded.
+    fn expand_words<'a>(&'a self, text: &str, stack: &mut Vec<&'a str>) -> Result<String> {
+        let is_break = |c: char| c.is_whitespace() || c == ',';
+        let mut output = String::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let start = rest.find(|c| !is_break(c)).unwrap_or(rest.len());
+            output.push_str(&rest[..start]);
+            rest = &rest[start..];
+            // Nothing in a comment is expanded.
+            if rest.starts_with('#') {
+                output.push_str(rest);
+                break;
+            }
+            let end = rest.find(is_break).unwrap_or(rest.len());
+            let word = &rest[..end];
+            match self.macros.get_key_value(word) {
+                Some((name, expression)) => {
+                    if let Some(i) = stack.iter().position(|&open| open == name) {
+                        let mut cycle = stack[i..].to_vec();
+                        cycle.push(name);
+                        bail!(
+                            "Error: Macro '{}' refers back to itself: {}.",
+                            name,
+                            cycle.join(" -> ")
+                        );
+                    }
+                    stack.push(name);
+                    output.push_str(&self.expand_words(expression, stack)?);
+                    stack.pop();
+                }
+                None => output.push_str(word),
+            }
+            rest = &rest[end..];
+        }
+        Ok(output)
+    }
+
+    /// The `--list-macros` table, drawn with `table`.
+    pub(crate) fn list_macros(&self, mut table: comfy_table::Table) -> String {
+        if self.macros.is_empty() {
+            return match &self.path {
+                Some(path) => format!("No macros are defined in {}.", path.display()),
+                None => "No macros are defined: there is no config file.".to_string(),
+            };
+        }
+        table.set_header(vec!["Macro", "Expression"]);
+        for (name, expression) in &self.macros {
+            table.add_row(vec![name, expression]);
+        }
+        table.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn parse(contents: &str) -> Result<Config> {
+        Config::parse(contents, PathBuf::from("config.toml"))
+    }
+
+    #[test]
+    fn test_default_path() {
+        let path = |xdg, home| default_path(xdg, home).map(|path| path.display().to_string());
+        assert_eq!(
+            path(Some("/xdg"), Some("/home/me")).as_deref(),
+            Some("/xdg/roll/config.toml")
+        );
+        // A relative XDG_CONFIG_HOME is ignored.
+        assert_eq!(
+            path(Some("xdg"), Some("/home/me")).as_deref(),
+            Some("/home/me/.config/roll/config.toml")
+        );
+        assert_eq!(path(None, None), None);
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = env::temp_dir().join(format!("roll-config-{}", std::process::id()));
+        fs::create_dir_all(dir.join("roll")).unwrap();
+        let path = dir.join("roll").join("config.toml");
+        fs::write(&path, "[macros]\nattack = \"1d20+7\"\n").unwrap();
+        let config = Config::load(Some(&path)).unwrap();
+        assert_eq!(config.macros["attack"], "1d20+7");
+        assert!(Config::load(Some(&dir.join("missing.toml"))).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Errors give the file, line and column.
+        let error = |contents| parse(contents).unwrap_err().to_string();
+        assert_eq!(
+            error("[macros]\nattack = 17\n"),
+            "Error: config.toml:2:10: invalid type: integer `17`, expected a string"
+        );
+        assert_eq!(
+            error("[macros]\nd20 = \"1d20\"\n"),
+            "Error: config.toml:2:1: 'd20' looks like a die and cannot be a macro name"
+        );
+        assert_eq!(
+            error("[macros\n"),
+            "Error: config.toml:1:8: unclosed table, expected `]`"
+        );
+        assert_eq!(
+            error("[defaults]\nstyle = \"fancy\"\n"),
+            "Error: config.toml:2:9: 'fancy' is not a style; \
+             expected one of heavy, light, rounded, ascii, borderless"
+        );
+        assert!(error("[defaults]\ncolour = \"never\"\n").starts_with("Error: config.toml:2:1: "));
+        assert_eq!(
+            error("[defaults]\ncrit = \"high\"\n"),
+            "Error: config.toml:2:8: crit expected a number or a comparison like '>=15', got 'high'"
+        );
+        assert_eq!(
+            error("[step]\nchain = [6, 4]\n"),
+            "Error: config.toml:2:9: the step chain must list die sizes from smallest to largest"
+        );
+        assert_eq!(
+            error("[step]\nchain = []\n"),
+            "Error: config.toml:2:9: the step chain needs at least one die"
+        );
+    }
+
+    #[test]
+    fn test_step_chain() {
+        let config = parse("[step]\nchain = [4, 6, 8, 10, 12, 20]\n").unwrap();
+        assert_eq!(config.step_chain, Some(vec![4, 6, 8, 10, 12, 20]));
+        assert_eq!(parse("").unwrap().step_chain, None);
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = parse(
+            "[defaults]\nformat = \"plain\"\nstyle = \"rounded\"\ncolor = \"never\"\n\
+             totals = false\nsort = \"desc\"\ncrit = \"19\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.defaults,
+            Defaults {
+                format: Some(Format::Plain),
+                style: Some(TableStyle::Rounded),
+                color: Some(ColorChoice::Never),
+                sort: Some(Sort::Descending),
+                totals: Some(false),
+                crit: Some(parse_threshold(">=19").unwrap()),
+                ..Defaults::default()
+            }
+        );
+
+        let apply = |config: &Config, argv: &[&str]| {
+            let matches = Args::command().try_get_matches_from(argv).unwrap();
+            let mut args = Args::from_arg_matches(&matches).unwrap();
+            config.defaults.apply(&mut args, &matches);
+            args
+        };
+        let args = |argv: &[&str]| apply(&config, argv);
+        let applied = args(&["roll", "1d6"]);
+        assert!(applied.plain && applied.no_total);
+        assert_eq!(applied.style, TableStyle::Rounded);
+        assert_eq!(applied.color, ColorChoice::Never);
+        assert_eq!(applied.sort, Some(Sort::Descending));
+        // The command line always wins.
+        let overridden = args(&[
+            "roll",
+            "--json",
+            "--style",
+            "ascii",
+            "--color=always",
+            "--sort=asc",
+            "1d6",
+        ]);
+        assert!(overridden.json && !overridden.plain);
+        assert_eq!(overridden.style, TableStyle::Ascii);
+        assert_eq!(overridden.color, ColorChoice::Always);
+        assert_eq!(overridden.sort, Some(Sort::Ascending));
+
+        // A flag also beats a default it conflicts with.
+        let sum = parse("[defaults]\nformat = \"sum\"\n").unwrap();
+        let applied = apply(&sum, &["roll", "--no-total", "1d6"]);
+        assert!(applied.no_total && !applied.sum);
+        let no_total = parse("[defaults]\ntotals = false\n").unwrap();
+        let applied = apply(&no_total, &["roll", "--sum", "1d6"]);
+        assert!(applied.sum && !applied.no_total);
+        assert_eq!(
+            parse("[defaults]\nformat = \"sum\"\ntotals = false\n")
+                .unwrap_err()
+                .to_string(),
+            "Error: config.toml:2:10: format = \"sum\" prints only the total, so totals can't be false"
+        );
+        let jsonl = parse("[defaults]\nformat = \"jsonl\"\n").unwrap();
+        let applied = apply(&jsonl, &["roll", "--stats", "1d6"]);
+        assert!(applied.stats && !applied.jsonl);
+        let stats = parse("[defaults]\nstats = true\n").unwrap();
+        let applied = apply(&stats, &["roll", "--sum", "1d6"]);
+        assert!(applied.sum && !applied.stats);
+        assert_eq!(
+            parse("[defaults]\nformat = \"jsonl\"\nstats = true\n")
+                .unwrap_err()
+                .to_string(),
+            "Error: config.toml:2:10: format = \"jsonl\" doesn't show stats, so stats can't be true"
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let config = parse(
+            "[macros]\nattack = \"1d20+7\"\ndamage = \"2d6+4\"\nturn = \"attack, damage\"\n\
+             loop = \"again\"\nagain = \"1d4 loop\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.expand("attack").unwrap(), "1d20+7");
+        assert_eq!(config.expand("turn").unwrap(), "1d20+7, 2d6+4");
+        assert_eq!(
+            config.expand("attack 1d4 # attack").unwrap(),
+            "1d20+7 1d4 # attack"
+        );
+        // Dice and unknown words are left for the parser.
+        assert_eq!(config.expand("2d6").unwrap(), "2d6");
+        assert_eq!(config.expand("1d20+prof").unwrap(), "1d20+prof");
+        let err = config.expand("loop").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Macro 'loop' refers back to itself: loop -> again -> loop."
+        );
+    }
+}
@@ -1,381 +1,9409 @@
 use anyhow::{Result, anyhow, bail};
-use clap::Parser;
-use comfy_table::Table;
+use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use comfy_table::{
+    Attribute, Cell, Color, Table,
+    modifiers::UTF8_ROUND_CORNERS,
+    presets::{ASCII_FULL, ASCII_NO_BORDERS},
+};
 use nom::{
     IResult,
     branch::alt,
-    bytes::complete::tag,
-    character::complete::digit1,
-    combinator::{map_res, opt},
-    sequence::pair,
+    bytes::complete::{is_not, tag, tag_no_case, take_while1},
+    character::complete::{digit1, one_of, space0},
+    combinator::{map, not, opt, rest, value, verify},
+    error::ErrorKind,
+    multi::{many0, separated_list0},
+    sequence::{delimited, pair, preceded, terminated},
 };
 use rand::Rng;
-use std::process;
+use serde_json::json;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{self, IsTerminal, Write},
+    iter,
+    ops::Range,
+    path::{Path, PathBuf},
+    process,
+    time::Instant,
+};
+
+mod config;
+mod repl;
+mod simulate;
+mod stats;
+
+use config::Config;
 
 /// A simple CLI to roll dice
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(group(ArgGroup::new("simulated").args(["histogram", "chance"]).multiple(true)))]
 struct Args {
-    /// Dice expressions (e.g. 1d20, 4d8)
-    #[arg(required = true)]
+    /// Dice expressions (e.g. 1d20, 4d8), or - to read them from stdin
+    #[arg(required_unless_present_any = ["file", "interactive", "list_macros"],
+          allow_negative_numbers = true)]
     dice: Vec<String>,
+
+    /// Roll the expressions in FILE, one per line, before any others; give
+    /// it more than once to roll several files in turn
+    #[arg(short, long, value_name = "FILE")]
+    file: Vec<PathBuf>,
+
+    /// Prompt for expressions and roll each as it is entered, until quit
+    #[arg(short, long, conflicts_with_all = ["dice", "file", "dc"])]
+    interactive: bool,
+
+    /// With -i, keep the rolls in FILE, so that history and !N reach back
+    /// into earlier sessions
+    #[arg(long, value_name = "FILE", requires = "interactive")]
+    history_file: Option<PathBuf>,
+
+    /// Define a variable for use in expressions (e.g. --set prof=3)
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_variable)]
+    set: Vec<(String, i64)>,
+
+    /// Print each expression in canonical form instead of rolling it
+    #[arg(long)]
+    normalize: bool,
+
+    /// Print each expression's exact average instead of rolling it, with
+    /// the rounded-down average a stat block would list
+    #[arg(long, conflicts_with = "normalize")]
+    expected: bool,
+
+    /// Roll each expression many times and chart how often each total
+    /// came up
+    #[arg(long, conflicts_with_all = ["normalize", "expected"])]
+    histogram: bool,
+
+    /// Print the chance of each expression's total meeting a target: a
+    /// bare number means at least that much, or give a comparison like '<=5'
+    #[arg(long, value_name = "TARGET", value_parser = parse_threshold,
+          allow_negative_numbers = true, conflicts_with_all = ["normalize", "expected", "histogram"])]
+    chance: Option<Comparison>,
+
+    /// Roll each expression N times and print the spread of its totals:
+    /// range, mean, standard deviation, median and percentiles
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..),
+          conflicts_with_all = ["normalize", "expected", "histogram", "chance"])]
+    simulate: Option<u64>,
+
+    /// How many times --histogram rolls each expression, and --chance one
+    /// it can't work out exactly, as far as 2,000,000 dice
+    #[arg(long, value_name = "N", default_value_t = 100_000, requires = "simulated",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    samples: u64,
+
+    /// Print the rolls, or any error, as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Print one JSON object per die as it is rolled, then a summary line.
+    /// Plain sums aren't held in memory, so they may pass 10,000 dice
+    #[arg(long, conflicts_with = "json")]
+    jsonl: bool,
+
+    /// Print one CSV row per die, ending with a `# total` comment row
+    #[arg(long, conflicts_with_all = ["json", "jsonl"])]
+    csv: bool,
+
+    /// Leave out the grand total: the Total row, the JSON `total` and
+    /// `successes`, or the `# total` row of --csv
+    #[arg(long, conflicts_with = "sum")]
+    no_total: bool,
+
+    /// Print the table as GitHub-flavored markdown
+    #[arg(long, conflicts_with_all = ["json", "jsonl", "csv"])]
+    markdown: bool,
+
+    /// Print one line per expression and a total line, with no table. This
+    /// is the default when stdout isn't a terminal
+    #[arg(long, conflicts_with_all = ["json", "jsonl", "csv", "markdown"])]
+    plain: bool,
+
+    /// Print the table even when stdout isn't a terminal
+    #[arg(long, conflicts_with_all = ["json", "jsonl", "csv", "markdown", "plain"])]
+    table: bool,
+
+    /// Print only the grand total, as a bare number
+    #[arg(short, long, conflicts_with_all = ["json", "jsonl", "csv", "markdown", "plain", "table"])]
+    sum: bool,
+
+    /// With --sum, print each expression's total on its own line
+    #[arg(long, requires = "sum")]
+    separate: bool,
+
+    /// Roll everything N times, each round with its own total
+    #[arg(short = 'n', long, value_name = "N", default_value_t = 1,
+          value_parser = clap::value_parser!(i64).range(1..=MAX_REPEAT))]
+    repeat: i64,
+
+    /// Sort the dice of each term by value, dropped dice last
+    #[arg(long, value_enum, value_name = "ORDER", num_args = 0..=1, default_missing_value = "asc")]
+    sort: Option<Sort>,
+
+    /// With --sort, also order the expressions by their totals
+    #[arg(long, requires = "sort", conflicts_with_all = ["jsonl", "csv"])]
+    sort_all: bool,
+
+    /// Add a Rolls column listing every face thrown, including rerolled,
+    /// exploded and dropped ones
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Add Min, Mean and Max columns with each expression's theoretical
+    /// range and average (a note in --plain, fields in --json and --csv)
+    #[arg(long, conflicts_with_all = ["jsonl", "sum"])]
+    stats: bool,
+
+    /// When to color the table: auto colors only in a terminal, and then
+    /// follows NO_COLOR and CLICOLOR_FORCE
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Count successes in every expression: dice meeting the target, such
+    /// as 5 or '>=5', instead of their sum
+    #[arg(long, value_name = "TARGET", value_parser = parse_threshold,
+          allow_negative_numbers = true)]
+    target: Option<Comparison>,
+
+    /// Show each d6's faces as pips (⚀-⚅) next to its result
+    #[arg(long)]
+    fancy: bool,
+
+    /// How to draw the table's borders; ascii never uses box-drawing
+    /// characters
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t = TableStyle::Ascii)]
+    style: TableStyle,
+
+    /// After rolling, exit with status 0 if the grand total meets N and 10
+    /// if it falls short, for `roll --dc 15 1d20+5 && echo hit`
+    #[arg(long, value_name = "N", allow_negative_numbers = true,
+          conflicts_with_all = ["normalize", "expected", "histogram", "chance", "simulate"])]
+    dc: Option<i64>,
+
+    /// With --dc, every expression must meet it rather than the grand total
+    #[arg(long, requires = "dc")]
+    all: bool,
+
+    /// With --dc, print nothing and leave the result to the exit status
+    #[arg(short, long, requires = "dc")]
+    quiet: bool,
+
+    /// Read the config file, with its [defaults] and [macros], from FILE
+    /// instead of ~/.config/roll/config.toml
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Ignore the config file, for scripts that mustn't depend on it
+    #[arg(long, conflicts_with = "config")]
+    no_config: bool,
+
+    /// List the macros defined in the config file, and roll nothing
+    #[arg(long, conflicts_with_all = ["dice", "file", "interactive"])]
+    list_macros: bool,
+
+    /// Roll with a fixed seed, so the same seed and expressions always give
+    /// the same result
+    #[arg(long, value_name = "U64")]
+    seed: Option<u64>,
+}
+
+impl Args {
+    /// The dice expressions, with pieces of an unquoted expression such as
+    /// `2d6 + 3` joined back together.
+    fn expressions(&self) -> Vec<String> {
+        join_pieces(&self.dice)
+    }
+
+    /// Whether the expressions come from stdin, as with `roll -`.
+    fn reads_stdin(&self) -> bool {
+        self.dice == ["-"]
+    }
+
+    /// The `--set` variables. A name set twice takes its last value.
+    fn variables(&self) -> HashMap<String, i64> {
+        self.set.iter().cloned().collect()
+    }
+
+    /// The options for this command line. Whether to color comes from
+    /// `main`, which looks at the terminal and environment once.
+    fn options(&self, color: bool) -> Options {
+        Options {
+            rounds: self.repeat,
+            sort: self.sort,
+            sort_all: self.sort_all,
+            verbose: self.verbose,
+            stats: self.stats,
+            no_total: self.no_total,
+            color,
+            style: self.style,
+            fancy: self.fancy,
+            target: self.target,
+            crit: None,
+            seed: self.seed,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color, given the NO_COLOR and CLICOLOR_FORCE variables and
+    /// whether stdout is a terminal. Only `auto` looks at any of them, and a
+    /// non-empty NO_COLOR beats CLICOLOR_FORCE.
+    fn enabled(self, no_color: Option<&str>, force: Option<&str>, terminal: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto if no_color.is_some_and(|value| !value.is_empty()) => false,
+            ColorChoice::Auto if force.is_some_and(|value| !value.is_empty() && value != "0") => {
+                true
+            }
+            ColorChoice::Auto => terminal,
+        }
+    }
+}
+
+/// The border presets for --style. `ascii` is comfy_table's default, the
+/// look the tables have always had.
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+enum TableStyle {
+    Heavy,
+    Light,
+    Rounded,
+    Ascii,
+    Borderless,
+}
+
+/// Thick box-drawing lines throughout, in comfy_table's preset order.
+const HEAVY: &str = "┃┃━━┣━╋┫┃━╋┣┫┳┻┏┓┗┛";
+
+/// Thin box-drawing lines throughout, in comfy_table's preset order.
+const LIGHT: &str = "││──├─┼┤│─┼├┤┬┴┌┐└┘";
+
+impl TableStyle {
+    /// An empty table drawn in this style.
+    fn table(self) -> Table {
+        let mut table = Table::new();
+        match self {
+            TableStyle::Heavy => table.load_preset(HEAVY),
+            TableStyle::Light => table.load_preset(LIGHT),
+            TableStyle::Rounded => table.load_preset(LIGHT).apply_modifier(UTF8_ROUND_CORNERS),
+            TableStyle::Ascii => table.load_preset(ASCII_FULL),
+            TableStyle::Borderless => table.load_preset(ASCII_NO_BORDERS),
+        };
+        table
+    }
+}
+
+/// How the rolls are made and laid out, whatever the output format.
+#[derive(Clone, Copy)]
+struct Options {
+    /// How many times to roll the whole command line, from --repeat
+    rounds: i64,
+    /// The order to list each term's dice in, from --sort
+    sort: Option<Sort>,
+    /// Whether to order the expressions by total as well
+    sort_all: bool,
+    /// Whether the table lists every face thrown, from --verbose
+    verbose: bool,
+    /// Whether the table shows each expression's range and average, from --stats
+    stats: bool,
+    /// Whether to leave out the grand total, from --no-total
+    no_total: bool,
+    /// Whether the table may color its cells, decided once in `main`
+    color: bool,
+    /// How every table is drawn, from --style
+    style: TableStyle,
+    /// Whether d6 results show their pips too, from --fancy
+    fancy: bool,
+    /// A success target for every dice term, from --target
+    target: Option<Comparison>,
+    /// The crit range of every d20 term without one, from the config file
+    crit: Option<Comparison>,
+    /// The seed the rolls come from, from --seed, so --json can report it
+    seed: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            rounds: 1,
+            sort: None,
+            sort_all: false,
+            verbose: false,
+            stats: false,
+            no_total: false,
+            color: false,
+            style: TableStyle::Ascii,
+            fancy: false,
+            target: None,
+            crit: None,
+            seed: None,
+        }
+    }
+}
+
+/// Joins argument tokens that can't stand alone onto the expression before
+/// them: an operator, anything starting with one, and anything following an
+/// operator. `2d6 3d8` stays two expressions.
+fn join_pieces(tokens: &[String]) -> Vec<String> {
+    let is_operator = |c: char| matches!(c, '+' | '-' | '*' | '/');
+    let mut expressions: Vec<String> = Vec::new();
+    for token in tokens {
+        let trimmed = token.trim();
+        match expressions.last_mut() {
+            Some(previous)
+                if trimmed.starts_with(is_operator)
+                    || previous.trim_end().ends_with(['+', '-', '*', '/', '^']) =>
+            {
+                previous.push(' ');
+                previous.push_str(trimmed);
+            }
+            _ => expressions.push(token.clone()),
+        }
+    }
+    expressions
+}
+
+/// Reads expressions for `roll -`, split on whitespace as if each piece
+/// had been its own argument. Blank lines are skipped, as is anything from
+/// a word starting with `#` to the end of its line.
+fn read_expressions(reader: impl io::BufRead) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let words = line.split_whitespace();
+        tokens.extend(
+            words
+                .take_while(|word| !word.starts_with('#'))
+                .map(String::from),
+        );
+    }
+    if tokens.is_empty() {
+        bail!("Error: No dice expressions on stdin.");
+    }
+    Ok(join_pieces(&tokens))
+}
+
+/// Reads `--file`, one expression per line with its macros expanded and
+/// variables filled in, skipping blank lines and comments. Each line is
+/// checked here so that an error can give the file and line it is on.
+fn read_file(
+    path: &Path,
+    variables: &HashMap<String, i64>,
+    config: &Config,
+) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| anyhow!("Error: Can't read '{}': {}", path.display(), err))?;
+    let mut expressions = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let place = format!("{}, line {}", path.display(), i + 1);
+        let cite = |err: anyhow::Error| {
+            let message = err.to_string();
+            match message.strip_prefix("Error: ") {
+                Some(rest) if !rest.starts_with(&place) => anyhow!("Error: {}: {}", place, rest),
+                _ => err,
+            }
+        };
+        let expression = config
+            .expand(line)
+            .and_then(|line| substitute_variables(&line, variables))
+            .map_err(cite)?;
+        parse_pasted_at(&place, &expression).map_err(cite)?;
+        expressions.push(expression);
+    }
+    if expressions.is_empty() {
+        bail!("Error: No dice expressions in '{}'.", path.display());
+    }
+    Ok(expressions)
+}
+
+/// Every expression to roll: those from `--file` first, then the
+/// arguments or stdin, with macros expanded and variables filled in.
+fn gather_expressions(
+    args: &Args,
+    variables: &HashMap<String, i64>,
+    config: &Config,
+) -> Result<Vec<String>> {
+    let mut dice = Vec::new();
+    for path in &args.file {
+        dice.extend(read_file(path, variables, config)?);
+    }
+    let expressions = if args.reads_stdin() {
+        read_expressions(io::stdin().lock())?
+    } else {
+        args.expressions()
+    };
+    for expression in expressions {
+        dice.push(substitute_variables(
+            &config.expand(&expression)?,
+            variables,
+        )?);
+    }
+    Ok(dice)
+}
+
+/// Whether `name` can name a variable or macro: letters, digits and
+/// underscores, not starting with a digit.
+fn is_word(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses a `--set` argument such as `prof=3` or `penalty=-2`.
+fn parse_variable(s: &str) -> Result<(String, i64), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", s))?;
+    if !is_word(name) {
+        return Err(format!(
+            "'{}' is not a valid name; use letters, digits and underscores",
+            name
+        ));
+    }
+    if parse_dice_expression(name).is_ok_and(|(rest, _)| rest.is_empty()) {
+        return Err(format!("'{}' looks like a die and cannot be a name", name));
+    }
+    let value = value
+        .parse()
+        .map_err(|_| format!("'{}' is not an integer", value))?;
+    Ok((name.to_string(), value))
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum RollMode {
     Normal,
-    Advantage,
-    Disadvantage,
+    /// `aN`: roll N times and keep the highest; a bare `a` rolls twice
+    Advantage(i64),
+    /// `dN`: roll N times and keep the lowest; a bare `d` rolls twice
+    Disadvantage(i64),
+}
+
+/// How a die re-rolls when it shows its maximum face.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Explode {
+    /// `!`: each extra roll is added as its own link in the chain
+    Standard,
+    /// `!!`: extra rolls merge into the original die's value
+    Compound,
+    /// `!p`: like `!`, but each extra roll counts one less
+    Penetrating,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+}
+
+/// A test against a single die face, e.g. the `<3` in `4d6r<3`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Comparison {
+    op: CompareOp,
+    value: i64,
+}
+
+impl Comparison {
+    fn matches(self, n: i64) -> bool {
+        match self.op {
+            CompareOp::Lt => n < self.value,
+            CompareOp::Le => n <= self.value,
+            CompareOp::Gt => n > self.value,
+            CompareOp::Ge => n >= self.value,
+            CompareOp::Eq => n == self.value,
+        }
+    }
+
+    /// Whether every face in `low..=high` matches. Each operator matches a
+    /// contiguous range, so checking the endpoints is enough.
+    fn matches_all(self, low: i64, high: i64) -> bool {
+        self.matches(low) && self.matches(high)
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+            CompareOp::Eq => "=",
+        };
+        write!(f, "{}{}", op, self.value)
+    }
+}
+
+/// Re-rolls a die's initial face when it matches. Rerolls resolve before
+/// any keep rule looks at the dice.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Reroll {
+    /// `roN`: re-roll a matching face once and keep the new result
+    Once(Comparison),
+    /// `rN`: keep re-rolling while the die matches
+    Recursive(Comparison),
+    /// `raN`: roll once more on a matching face and add it on top
+    Add(Comparison),
+}
+
+/// Display order for a term's dice, from `s` or `sd`, or for every term
+/// from `--sort asc` or `--sort desc`.
+#[derive(Debug, PartialEq, Clone, Copy, ValueEnum)]
+enum Sort {
+    #[value(name = "asc")]
+    Ascending,
+    #[value(name = "desc")]
+    Descending,
+}
+
+/// Selects which dice of a pool count toward the total.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Keep {
+    Highest(i64),
+    Lowest(i64),
+    DropLowest(i64),
+    DropHighest(i64),
+    /// Keeps the middle N, dropping as many dice from the top as the bottom
+    Middle(i64),
+}
+
+impl Keep {
+    /// Number of dice that count toward the total out of `rolled`.
+    fn kept(self, rolled: i64) -> i64 {
+        match self {
+            Keep::Highest(n) | Keep::Lowest(n) | Keep::Middle(n) => n,
+            Keep::DropLowest(n) | Keep::DropHighest(n) => rolled - n,
+        }
+    }
 }
 
+/// An arithmetic operator joining two parts of an expression.
 #[derive(Debug, PartialEq, Clone, Copy)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    /// `/`: divides, rounding down
+    Div,
+    /// `/^`: divides, rounding up
+    DivUp,
+}
+
+impl BinaryOp {
+    /// `*` and `/` bind tighter than `+` and `-`.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 1,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::DivUp => 2,
+        }
+    }
+
+    fn apply(self, lhs: i64, rhs: i64) -> i64 {
+        match self {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => floor_div(lhs, rhs),
+            BinaryOp::DivUp => -floor_div(-lhs, rhs),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "×",
+            BinaryOp::Div => "/",
+            BinaryOp::DivUp => "/^",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+/// Integer division rounding toward negative infinity.
+fn floor_div(lhs: i64, rhs: i64) -> i64 {
+    let quotient = lhs / rhs;
+    if lhs % rhs != 0 && (lhs < 0) != (rhs < 0) {
+        quotient - 1
+    } else {
+        quotient
+    }
+}
+
+/// The set of faces a die can show.
+#[derive(Debug, PartialEq, Clone)]
+enum Faces {
+    /// `1..=sides`
+    Standard,
+    /// `d10z`: `0..sides`, for dice read from 0
+    ZeroBased,
+    /// `d100c`: a tens d10 and a units d10, where `00` and `0` read as 100
+    Percentile,
+    /// `dF`: −1, 0 or +1
+    Fate,
+    /// `d66`, `d666`, ...: one `d<base>` per digit, read as digits rather
+    /// than summed
+    Digits { base: i64, digits: u32 },
+    /// `d{0,0,1,2}`: an explicit list, rolled uniformly by position so
+    /// repeated entries weight the outcome
+    Custom(Vec<i64>),
+    /// `d{yes,no,maybe}`: faces that are words rather than numbers
+    Text(Vec<String>),
+}
+
+impl Faces {
+    /// Sides written as two or more 6s or 8s, like `66` or `888`, name a
+    /// digit die. Everything else, `d22` and `d44` included, is an ordinary
+    /// die.
+    fn from_sides(sides: i64) -> Faces {
+        let text = sides.to_string();
+        let base = sides % 10;
+        if text.len() >= 2
+            && (base == 6 || base == 8)
+            && text.bytes().all(|b| b == text.as_bytes()[0])
+        {
+            Faces::Digits {
+                base,
+                digits: text.len() as u32,
+            }
+        } else {
+            Faces::Standard
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 struct DiceRequest {
+    /// Ignored when `count_roll` is set
     count: i64,
+    /// A die rolled first to decide the count, from `(1d4)d6`
+    count_roll: Option<Box<DiceRequest>>,
     sides: i64,
+    faces: Faces,
     mode: RollMode,
-    modifier: i64,
+    explode: Option<Explode>,
+    /// The faces that explode, from `!>=5`, instead of just the highest
+    explode_on: Option<Comparison>,
+    /// The most times one die may explode, from `!3`
+    explode_cap: Option<i64>,
+    keep: Option<Keep>,
+    reroll: Option<Reroll>,
+    /// Counts dice meeting the target instead of summing them, e.g. `>=7`
+    success: Option<Comparison>,
+    /// In a pool, dice matching this subtract a success, e.g. `f1`
+    failure: Option<Comparison>,
+    /// Raises any die below this to it, from `minN`
+    minimum: Option<i64>,
+    /// Lowers any die above this to it, from `maxN`
+    maximum: Option<i64>,
+    /// Added to every die after clamping, from `e+1` or `e-1`
+    each: Option<i64>,
+    /// Kept dice matching this are flagged as critical hits, from `cs>=19`
+    crit_success: Option<Comparison>,
+    /// Kept dice matching this are flagged as fumbles, from `cf<=1`
+    crit_failure: Option<Comparison>,
+    /// Sides of a Savage Worlds wild die rolled alongside, from `w` or `w8`
+    wild: Option<i64>,
+    /// Report sets of dice showing the same face, from `m` for the largest
+    /// set or `mN` for every set of at least N
+    matching: Option<Option<i64>>,
+    /// Halfling Luck: a natural 1 is rerolled once, from `lucky`
+    lucky: bool,
+    /// Every kept die showing its maximum adds one bonus die, from `b`
+    brutal: bool,
+    /// Dice repeating an earlier die's value are rerolled, from `u`
+    unique: bool,
+    /// Dice are listed in this order instead of roll order
+    sort: Option<Sort>,
+}
+
+impl DiceRequest {
+    /// A plain `NdS` with no suffixes.
+    fn plain(count: i64, sides: i64) -> DiceRequest {
+        DiceRequest {
+            count,
+            count_roll: None,
+            sides,
+            faces: Faces::from_sides(sides),
+            mode: RollMode::Normal,
+            explode: None,
+            explode_on: None,
+            explode_cap: None,
+            keep: None,
+            reroll: None,
+            success: None,
+            failure: None,
+            minimum: None,
+            maximum: None,
+            each: None,
+            crit_success: None,
+            crit_failure: None,
+            wild: None,
+            matching: None,
+            lucky: false,
+            brutal: false,
+            unique: false,
+            sort: None,
+        }
+    }
+
+    fn min_face(&self) -> i64 {
+        match &self.faces {
+            Faces::Standard => 1,
+            Faces::ZeroBased => 0,
+            Faces::Percentile => 1,
+            Faces::Fate => -1,
+            Faces::Digits { digits, .. } => repeat_digit(1, *digits),
+            Faces::Custom(faces) => faces.iter().copied().min().unwrap_or(0),
+            Faces::Text(_) => 0,
+        }
+    }
+
+    fn max_face(&self) -> i64 {
+        match &self.faces {
+            Faces::Standard => self.sides,
+            Faces::ZeroBased => self.sides - 1,
+            Faces::Percentile => 100,
+            Faces::Fate => 1,
+            Faces::Digits { base, digits } => repeat_digit(*base, *digits),
+            Faces::Custom(faces) => faces.iter().copied().max().unwrap_or(0),
+            Faces::Text(faces) => faces.len() as i64 - 1,
+        }
+    }
+
+    /// How many different values a single throw can show.
+    fn distinct_faces(&self) -> i64 {
+        match &self.faces {
+            Faces::Standard | Faces::ZeroBased => self.sides,
+            Faces::Percentile => 100,
+            Faces::Fate => 3,
+            Faces::Digits { base, digits } => base.checked_pow(*digits).unwrap_or(i64::MAX),
+            Faces::Custom(faces) => {
+                let mut faces = faces.clone();
+                faces.sort_unstable();
+                faces.dedup();
+                faces.len() as i64
+            }
+            Faces::Text(faces) => faces.len() as i64,
+        }
+    }
+
+    fn explodes_on(&self, face: i64) -> bool {
+        match self.explode_on {
+            Some(condition) => condition.matches(face),
+            None => face == self.max_face(),
+        }
+    }
+
+    /// Digit and text dice are table lookups, so adding them to a total is
+    /// meaningless.
+    fn is_lookup(&self) -> bool {
+        matches!(self.faces, Faces::Digits { .. } | Faces::Text(_))
+    }
+
+    /// The largest magnitude this term's total could reach, or `None` if
+    /// that would overflow.
+    fn bound(&self) -> Option<i64> {
+        let count = match &self.count_roll {
+            Some(count_roll) => count_roll.bound()?,
+            None => self.count,
+        };
+        let per_die = self
+            .max_face()
+            .abs()
+            .max(self.min_face().abs())
+            .max(self.wild.unwrap_or(1))
+            .checked_add(self.each.unwrap_or(0).checked_abs()?)?;
+        let chain = match (self.explode, self.explode_cap) {
+            (Some(_), Some(cap)) => cap.min(MAX_EXPLOSIONS as i64) + 1,
+            (Some(_), None) => MAX_EXPLOSIONS as i64 + 1,
+            (None, _) => 1,
+        };
+        let bonus = if self.brutal { 2 } else { 1 };
+        count
+            .checked_mul(per_die)?
+            .checked_mul(chain)?
+            .checked_mul(bonus)
+    }
+}
+
+/// `repeat_digit(6, 2)` is 66.
+fn repeat_digit(digit: i64, digits: u32) -> i64 {
+    (0..digits).fold(0, |acc, _| acc * 10 + digit)
+}
+
+/// A parsed expression tree, e.g. `(1d4+1)*3`.
+#[derive(Debug, PartialEq, Clone)]
+enum Node {
+    Dice(Box<DiceRequest>),
+    Constant(i64),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    /// A parenthesised sub-expression, kept so the formula reads as typed
+    Group(Box<Node>),
+}
+
+impl Node {
+    /// Every dice term, in the same order as `dice`, for changing in place.
+    fn dice_mut(&mut self) -> Vec<&mut DiceRequest> {
+        match self {
+            Node::Dice(request) => vec![request.as_mut()],
+            Node::Constant(_) => Vec::new(),
+            Node::Binary(_, lhs, rhs) => {
+                let mut dice = lhs.dice_mut();
+                dice.extend(rhs.dice_mut());
+                dice
+            }
+            Node::Group(inner) => inner.dice_mut(),
+        }
+    }
+
+    /// Visits every node, parents before children and left before right.
+    fn walk<'a>(&'a self, visit: &mut impl FnMut(&'a Node)) {
+        visit(self);
+        match self {
+            Node::Binary(_, lhs, rhs) => {
+                lhs.walk(visit);
+                rhs.walk(visit);
+            }
+            Node::Group(inner) => inner.walk(visit),
+            Node::Dice(_) | Node::Constant(_) => {}
+        }
+    }
+
+    /// The dice terms in the order they are rolled.
+    fn dice(&self) -> Vec<&DiceRequest> {
+        let mut dice = Vec::new();
+        self.walk(&mut |node| {
+            if let Node::Dice(request) = node {
+                dice.push(request.as_ref());
+            }
+        });
+        dice
+    }
+
+    /// Evaluates the tree, taking each dice term's value from `values` in
+    /// roll order.
+    fn evaluate(&self, values: &mut impl Iterator<Item = i64>) -> i64 {
+        match self {
+            Node::Dice(_) => values.next().expect("a value for every dice term"),
+            Node::Constant(n) => *n,
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(values);
+                op.apply(lhs, rhs.evaluate(values))
+            }
+            Node::Group(inner) => inner.evaluate(values),
+        }
+    }
+
+    /// Like `evaluate`, but shows the working, e.g. `(3 + 1) × 3`.
+    fn formula(&self, values: &mut impl Iterator<Item = i64>) -> String {
+        match self {
+            Node::Dice(_) => values
+                .next()
+                .expect("a value for every dice term")
+                .to_string(),
+            Node::Constant(n) => n.to_string(),
+            Node::Binary(op, lhs, rhs) => {
+                let lhs = lhs.formula(values);
+                format!("{} {} {}", lhs, op, rhs.formula(values))
+            }
+            Node::Group(inner) => format!("({})", inner.formula(values)),
+        }
+    }
+
+    /// The constants of a plain sum such as `1d8+2d6+4`, folded into one
+    /// modifier, or `None` when the expression needs its formula shown.
+    fn modifier(&self) -> Option<i64> {
+        match self {
+            Node::Dice(_) => Some(0),
+            Node::Constant(n) => Some(*n),
+            Node::Binary(BinaryOp::Add, lhs, rhs) => Some(lhs.modifier()? + rhs.modifier()?),
+            Node::Binary(BinaryOp::Sub, lhs, rhs) => Some(lhs.modifier()? - rhs.modifier()?),
+            Node::Binary(..) => None,
+            Node::Group(inner) => inner.modifier(),
+        }
+    }
+
+    /// Whether each dice term, in roll order, is subtracted in a plain sum
+    /// such as `1d20+5-1d4`.
+    fn negated_dice(&self) -> Vec<bool> {
+        fn visit(node: &Node, negated: bool, out: &mut Vec<bool>) {
+            match node {
+                Node::Dice(_) => out.push(negated),
+                Node::Constant(_) => {}
+                Node::Binary(BinaryOp::Sub, lhs, rhs) => {
+                    visit(lhs, negated, out);
+                    visit(rhs, !negated, out);
+                }
+                Node::Binary(_, lhs, rhs) => {
+                    visit(lhs, negated, out);
+                    visit(rhs, negated, out);
+                }
+                Node::Group(inner) => visit(inner, negated, out),
+            }
+        }
+        let mut out = Vec::new();
+        visit(self, false, &mut out);
+        out
+    }
+
+    /// The largest magnitude the expression could reach, or `None` if that
+    /// would overflow.
+    fn bound(&self) -> Option<i64> {
+        match self {
+            Node::Dice(request) => request.bound(),
+            Node::Constant(n) => n.checked_abs(),
+            Node::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.bound()?, rhs.bound()?);
+                match op {
+                    BinaryOp::Add | BinaryOp::Sub => lhs.checked_add(rhs),
+                    BinaryOp::Mul => lhs.checked_mul(rhs),
+                    BinaryOp::Div | BinaryOp::DivUp => Some(lhs),
+                }
+            }
+            Node::Group(inner) => inner.bound(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Expression {
+    /// How many independent times to roll, from an `Nx` prefix like `6x(4d6dl1)`
+    repeat: i64,
+    root: Node,
+    /// A name shown alongside the rows, from a `:name` suffix like `1d20+5:attack`
+    label: Option<String>,
+    /// A target the total is checked against, from a suffix like `>=15`
+    check: Option<Comparison>,
+    /// Which side of an opposed roll like `1d20+7 vs 1d20+4` this is
+    side: Option<Side>,
+    /// The lowest final value, from a `~0` or `floor` suffix
+    floor: Option<i64>,
+    /// Rolls several attempts and keeps one, from `bo2(...)` or `wo2(...)`
+    pick: Option<Pick>,
+}
+
+/// Which of several attempts at a whole expression counts.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Pick {
+    /// `boN`: the highest total of N attempts
+    Best(i64),
+    /// `woN`: the lowest total of N attempts
+    Worst(i64),
+}
+
+impl Pick {
+    fn attempts(self) -> i64 {
+        match self {
+            Pick::Best(n) | Pick::Worst(n) => n,
+        }
+    }
+}
+
+impl fmt::Display for Pick {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pick::Best(n) => write!(f, "bo{}", n),
+            Pick::Worst(n) => write!(f, "wo{}", n),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Side {
+    A,
+    B,
+}
+
+/// A single die as shown in one table row.
+struct DieRoll {
+    value: i64,
+    /// Every roll summed into `value`, more than one when exploding. Penetrating
+    /// re-rolls are stored after their −1 adjustment.
+    rolls: Vec<i64>,
+    /// The word shown by a text die, whose `value` is the face's position
+    text: Option<String>,
+    /// Initial faces thrown away by a reroll rule
+    rerolled: Vec<i64>,
+    /// The die's value before clamping, when clamping changed it
+    unclamped: Option<i64>,
+    /// The rolls discarded by advantage or disadvantage
+    alternates: Vec<i64>,
+    /// Values thrown away by `u` for repeating an earlier die
+    duplicates: Vec<i64>,
+    /// Whether the trait and wild dice both came up a natural 1
+    snake_eyes: bool,
+    /// Whether `lucky` rerolled a natural 1 in any of this die's throws
+    luck: bool,
+    /// Whether this is an extra die added by `b` rather than part of the pool
+    bonus: bool,
+    /// Whether the chain stopped only because it hit the `!N` cap
+    capped: bool,
+    /// Whether this die counts toward the total after any keep rule
+    kept: bool,
+    /// Every face thrown for this die, in order, including the ones that
+    /// were rerolled or lost to advantage
+    throws: Vec<(i64, Throw)>,
+}
+
+/// What became of one face thrown for a die.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Throw {
+    Counted,
+    /// Counted, and set off another roll
+    Exploded,
+    Rerolled,
+    /// Lost to advantage, disadvantage or a wild die
+    Dropped,
+}
+
+impl Throw {
+    fn name(self) -> &'static str {
+        match self {
+            Throw::Counted => "counted",
+            Throw::Exploded => "exploded",
+            Throw::Rerolled => "rerolled",
+            Throw::Dropped => "dropped",
+        }
+    }
 }
 
+/// All dice rolled for a single `DiceRequest`.
 struct RollResult {
-    sides: i64,
-    mode: RollMode,
-    kept: i64,
-    dropped: Option<i64>,
-    modifier: i64,
+    request: DiceRequest,
+    /// The roll that decided how many dice to throw, for `(1d4)d6`
+    count_roll: Option<Box<RollResult>>,
+    dice: Vec<DieRoll>,
+    /// The matching sets asked for by `m`, as (dice, face), biggest first
+    sets: Vec<(usize, i64)>,
+    /// What the dice handed on as they were rolled added up to, when the
+    /// term was streamed rather than kept in `dice`
+    streamed: i64,
 }
 
-fn parse_i64(input: &str) -> IResult<&str, i64> {
-    map_res(digit1, str::parse)(input)
+impl DieRoll {
+    /// How many times this die matches `condition`. Each link of a plain or
+    /// penetrating exploding chain is its own die, adjusted by the per-die
+    /// modifier `each`; anything else is judged on its final value, which
+    /// already includes it.
+    fn count_matching(&self, condition: Comparison, explode: Option<Explode>, each: i64) -> i64 {
+        match explode {
+            Some(Explode::Standard | Explode::Penetrating) => self
+                .rolls
+                .iter()
+                .filter(|&&roll| condition.matches(roll + each))
+                .count() as i64,
+            _ => i64::from(condition.matches(self.value)),
+        }
+    }
+
+    /// Successes minus failures scored by this die in a pool.
+    fn net_successes(&self, req: &DiceRequest) -> i64 {
+        let each = req.each.unwrap_or(0);
+        let count = |condition: Option<Comparison>| {
+            condition.map_or(0, |c| self.count_matching(c, req.explode, each))
+        };
+        count(req.success) - count(req.failure)
+    }
+
+    /// What this die adds to its term: its value, or its net successes in a
+    /// pool.
+    fn score(&self, req: &DiceRequest) -> i64 {
+        if req.success.is_some() {
+            self.net_successes(req)
+        } else {
+            self.value
+        }
+    }
+}
+
+impl RollResult {
+    /// The sum of the kept dice, or their net success count for a pool.
+    fn total(&self) -> i64 {
+        let kept = self.dice.iter().filter(|die| die.kept);
+        self.streamed + kept.map(|die| die.score(&self.request)).sum::<i64>()
+    }
 }
 
-fn parse_dice_expression(input: &str) -> IResult<&str, DiceRequest> {
-    let (input, count) = opt(parse_i64)(input)?;
-    let (input, _) = tag("d")(input)?;
-    let (input, sides) = parse_i64(input)?;
-    let (input, mode_char) = opt(alt((tag("a"), tag("d"))))(input)?;
-    let (input, mod_pair) = opt(pair(alt((tag("+"), tag("-"))), parse_i64))(input)?;
+/// All dice rolled for one expression, combined by walking its tree.
+struct ExpressionResult {
+    root: Node,
+    rolls: Vec<RollResult>,
+    /// Which roll this is, counting from 1, when the expression is repeated
+    repetition: Option<i64>,
+    /// Which attempt this is, counting from 1, under `bo2(...)` or `wo2(...)`
+    attempt: Option<i64>,
+    /// Which pass over the whole command line this is, under `--repeat`
+    round: Option<i64>,
+    /// Whether this attempt lost out to a better (or worse) one
+    discarded: bool,
+    label: Option<String>,
+    check: Option<Check>,
+    side: Option<Side>,
+    floor: Option<i64>,
+}
+
+/// The outcome of comparing an expression's total with its check target.
+struct Check {
+    target: Comparison,
+    passed: bool,
+    /// How far the total beat the target by, negative when it fell short
+    margin: i64,
+    /// A kept d20 showed its natural 20 or 1, noted whatever the arithmetic says
+    natural: Option<i64>,
+}
+
+impl ExpressionResult {
+    /// The final value, after any floor.
+    fn total(&self) -> i64 {
+        self.floored(self.raw_total())
+    }
+
+    fn raw_total(&self) -> i64 {
+        self.root
+            .evaluate(&mut self.rolls.iter().map(RollResult::total))
+    }
+
+    fn floored(&self, value: i64) -> i64 {
+        self.floor.map_or(value, |floor| value.max(floor))
+    }
+
+    /// The contribution to the grand total, counting table lookups as 0.
+    fn summable_total(&self) -> i64 {
+        self.floored(self.root.evaluate(&mut self.rolls.iter().map(|res| {
+            if res.request.is_lookup() {
+                0
+            } else {
+                res.total()
+            }
+        })))
+    }
+
+    fn formula(&self) -> String {
+        self.root
+            .formula(&mut self.rolls.iter().map(RollResult::total))
+    }
+
+    /// Whether any term counts successes rather than summing.
+    fn is_pool(&self) -> bool {
+        self.rolls.iter().any(|res| res.request.success.is_some())
+    }
+
+    fn has_summable(&self) -> bool {
+        let mut has_constant = false;
+        self.root.walk(&mut |node| {
+            has_constant |= matches!(node, Node::Constant(_));
+        });
+        has_constant || self.rolls.iter().any(|res| !res.request.is_lookup())
+    }
+}
+
+/// Parses an unsigned integer. Digits that don't fit are a failure, so the
+/// error can say so rather than blame the syntax.
+fn parse_i64(input: &str) -> IResult<&str, i64> {
+    let (rest, digits) = digit1(input)?;
+    match digits.parse() {
+        Ok(n) => Ok((rest, n)),
+        Err(_) => Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            ErrorKind::TooLarge,
+        ))),
+    }
+}
+
+fn parse_signed_i64(input: &str) -> IResult<&str, i64> {
+    map(pair(opt(tag("-")), parse_i64), |(sign, val)| {
+        if sign.is_some() { -val } else { val }
+    })(input)
+}
+
+/// Parses a brace-delimited face list such as `{0,0,1,1,2,4}` or
+/// `{yes,no,"maybe, later"}`. The list is numeric only if every face is an
+/// integer.
+fn parse_face_list(input: &str) -> IResult<&str, Faces> {
+    let face = alt((
+        delimited(tag("\""), is_not("\""), tag("\"")),
+        is_not(",}\""),
+    ));
+    let (input, faces) = delimited(tag("{"), separated_list0(tag(","), face), tag("}"))(input)?;
+    let numbers: Option<Vec<i64>> = faces
+        .iter()
+        .map(|face| match parse_signed_i64(face) {
+            Ok(("", val)) => Some(val),
+            _ => None,
+        })
+        .collect();
+    let faces = match numbers {
+        Some(numbers) => Faces::Custom(numbers),
+        None => Faces::Text(faces.into_iter().map(String::from).collect()),
+    };
+    Ok((input, faces))
+}
+
+fn parse_compare_op(input: &str) -> IResult<&str, CompareOp> {
+    alt((
+        value(CompareOp::Le, tag("<=")),
+        value(CompareOp::Ge, tag(">=")),
+        value(CompareOp::Lt, tag("<")),
+        value(CompareOp::Gt, tag(">")),
+        value(CompareOp::Eq, tag("=")),
+    ))(input)
+}
+
+/// Parses a comparison such as `<3` or `>=5`. A bare number means `=N`.
+fn parse_comparison(input: &str) -> IResult<&str, Comparison> {
+    let (input, op) = opt(parse_compare_op)(input)?;
+    let (input, value) = parse_i64(input)?;
+    Ok((
+        input,
+        Comparison {
+            op: op.unwrap_or(CompareOp::Eq),
+            value,
+        },
+    ))
+}
+
+/// Parses a comparison whose operator is required, for suffixes where a bare
+/// number would be ambiguous.
+fn parse_target(input: &str) -> IResult<&str, Comparison> {
+    map(pair(parse_compare_op, parse_i64), |(op, value)| {
+        Comparison { op, value }
+    })(input)
+}
+
+/// Skips spaces and tabs before `parser`, so `2d6 + 3` parses like `2d6+3`.
+fn ws<'a, O>(
+    parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    preceded(space0, parser)
+}
+
+/// Parses a keep or drop suffix: `khN`, `klN`, `kmN`, `dl[N]` or `dh[N]`.
+fn parse_keep(input: &str) -> IResult<&str, Keep> {
+    alt((
+        map(preceded(tag_no_case("kh"), parse_i64), Keep::Highest),
+        map(preceded(tag_no_case("kl"), parse_i64), Keep::Lowest),
+        map(preceded(tag_no_case("km"), parse_i64), Keep::Middle),
+        map(preceded(tag_no_case("dl"), opt(parse_i64)), |n| {
+            Keep::DropLowest(n.unwrap_or(1))
+        }),
+        map(preceded(tag_no_case("dh"), opt(parse_i64)), |n| {
+            Keep::DropHighest(n.unwrap_or(1))
+        }),
+    ))(input)
+}
+
+/// One suffix of a dice term, such as the `kh3` of `4d6kh3`.
+#[derive(Clone, Copy)]
+enum Suffix {
+    Explode(Explode, Option<i64>, Option<Comparison>),
+    Mode(RollMode),
+    Lucky,
+    Wild(Option<i64>),
+    Keep(Keep),
+    Reroll(Reroll),
+    Minimum(i64),
+    Maximum(i64),
+    Each(i64),
+    Success(Comparison),
+    Failure(Comparison),
+    CritSuccess(Option<Comparison>),
+    CritFailure(Option<Comparison>),
+    Matching(Option<i64>),
+    Brutal,
+    Unique,
+    Sort(Sort),
+}
+
+impl Suffix {
+    /// What the suffix is called in errors; a die can have one of each.
+    fn name(&self) -> &'static str {
+        match self {
+            Suffix::Explode(..) => "explosion",
+            Suffix::Mode(_) => "advantage or disadvantage",
+            Suffix::Lucky => "lucky",
+            Suffix::Wild(_) => "wild die",
+            Suffix::Keep(_) => "keep or drop",
+            Suffix::Reroll(_) => "reroll",
+            Suffix::Minimum(_) => "minimum",
+            Suffix::Maximum(_) => "maximum",
+            Suffix::Each(_) => "per-die modifier",
+            Suffix::Success(_) => "success target",
+            Suffix::Failure(_) => "failure target",
+            Suffix::CritSuccess(_) => "crit success",
+            Suffix::CritFailure(_) => "crit failure",
+            Suffix::Matching(_) => "match",
+            Suffix::Brutal => "brutal",
+            Suffix::Unique => "unique",
+            Suffix::Sort(_) => "sort",
+        }
+    }
+}
+
+/// Parses one suffix of a dice term. `single` is whether the term is a
+/// single die, which `parse_mode` needs to read a bare `dN`.
+fn parse_suffix(input: &str, single: bool) -> IResult<&str, Suffix> {
+    if let (rest, Some(mode)) = parse_mode(input, single)? {
+        return Ok((rest, Suffix::Mode(mode)));
+    }
+    ws(alt((
+        // Right after `!` comes an optional cap and then a comparison for
+        // the explode threshold, so a success target on an exploding die
+        // has to follow them, e.g. `8d10!>=10>=7`.
+        map(
+            pair(
+                alt((
+                    value(Explode::Compound, tag("!!")),
+                    value(Explode::Penetrating, tag_no_case("!p")),
+                    value(Explode::Standard, tag("!")),
+                )),
+                pair(opt(parse_i64), opt(parse_target)),
+            ),
+            |(kind, (cap, on))| Suffix::Explode(kind, cap, on),
+        ),
+        value(Suffix::Lucky, tag_no_case("lucky")),
+        map(preceded(tag_no_case("w"), opt(parse_i64)), Suffix::Wild),
+        map(parse_keep, Suffix::Keep),
+        map(
+            alt((
+                // Great Weapon Fighting: reroll 1s and 2s once.
+                value(
+                    Reroll::Once(Comparison {
+                        op: CompareOp::Le,
+                        value: 2,
+                    }),
+                    tag_no_case("gwf"),
+                ),
+                map(preceded(tag_no_case("ro"), parse_comparison), Reroll::Once),
+                map(preceded(tag_no_case("ra"), parse_comparison), Reroll::Add),
+                map(
+                    preceded(tag_no_case("r"), parse_comparison),
+                    Reroll::Recursive,
+                ),
+            )),
+            Suffix::Reroll,
+        ),
+        map(
+            preceded(tag_no_case("min"), parse_signed_i64),
+            Suffix::Minimum,
+        ),
+        map(
+            preceded(tag_no_case("max"), parse_signed_i64),
+            Suffix::Maximum,
+        ),
+        map(
+            preceded(tag_no_case("e"), pair(one_of("+-"), parse_i64)),
+            |(sign, n)| Suffix::Each(if sign == '-' { -n } else { n }),
+        ),
+        map(
+            alt((
+                // On a single die a bare comparison is the expression's check,
+                // as in `1d20>=15`, so only a pool takes it as a target.
+                verify(parse_target, |_| !single),
+                // `tN` is shorthand for `>=N`, as typed by pool-system players,
+                // and `t<=N` and the like give any other comparison.
+                map(
+                    preceded(tag_no_case("t"), pair(opt(parse_compare_op), parse_i64)),
+                    |(op, value)| Comparison {
+                        op: op.unwrap_or(CompareOp::Ge),
+                        value,
+                    },
+                ),
+            )),
+            Suffix::Success,
+        ),
+        map(
+            preceded(tag_no_case("f"), parse_comparison),
+            Suffix::Failure,
+        ),
+        map(
+            preceded(tag_no_case("cs"), opt(parse_comparison)),
+            Suffix::CritSuccess,
+        ),
+        map(
+            preceded(tag_no_case("cf"), opt(parse_comparison)),
+            Suffix::CritFailure,
+        ),
+        map(preceded(tag_no_case("m"), opt(parse_i64)), Suffix::Matching),
+        value(Suffix::Brutal, tag_no_case("b")),
+        value(Suffix::Unique, tag_no_case("u")),
+        map(
+            alt((
+                value(Sort::Descending, tag_no_case("sd")),
+                value(
+                    Sort::Ascending,
+                    terminated(tag_no_case("s"), not(tag_no_case("tep"))),
+                ),
+            )),
+            Suffix::Sort,
+        ),
+    )))(input)
+}
+
+/// Parses a single dice term. Letters may be upper or lower case, so `2D6A`
+/// is `2d6a`.
+fn parse_dice_expression(input: &str) -> IResult<&str, DiceRequest> {
+    let (input, count_roll) =
+        opt(ws(delimited(tag("("), parse_dice_expression, ws(tag(")")))))(input)?;
+    let (input, count) = match count_roll {
+        Some(_) => (input, None),
+        None => opt(ws(parse_i64))(input)?,
+    };
+    // Legend of the Five Rings' roll-and-keep: `5k3` rolls five exploding
+    // d10s and keeps the best three.
+    if let (None, Some(rolled)) = (&count_roll, count)
+        && let Ok((input, kept)) = preceded(tag_no_case("k"), parse_i64)(input)
+    {
+        let request = DiceRequest {
+            explode: Some(Explode::Standard),
+            keep: Some(Keep::Highest(kept)),
+            ..DiceRequest::plain(rolled, 10)
+        };
+        return Ok((input, request));
+    }
+    let (input, _) = ws(tag_no_case("d"))(input)?;
+    let (input, (sides, faces)) = ws(alt((
+        value((3, Faces::Fate), tag_no_case("F")),
+        value((100, Faces::Standard), tag("%")),
+        // Not to be confused with a `cs` or `cf` crit on a plain d100.
+        value(
+            (100, Faces::Percentile),
+            terminated(tag_no_case("100c"), not(one_of("sfSF"))),
+        ),
+        map(parse_face_list, |faces| {
+            let sides = match &faces {
+                Faces::Custom(faces) => faces.len(),
+                Faces::Text(faces) => faces.len(),
+                _ => 0,
+            };
+            (sides as i64, faces)
+        }),
+        map(terminated(parse_i64, tag_no_case("z")), |sides| {
+            (sides, Faces::ZeroBased)
+        }),
+        map(parse_i64, |sides| (sides, Faces::from_sides(sides))),
+    )))(input)?;
+    let single = count_roll.is_none() && count.unwrap_or(1) == 1;
+    // Suffixes may come in any order, as in `4d6r1kh3` or `4d6kh3r1`, but
+    // each only once.
+    let mut input = input;
+    let mut seen: Vec<&'static str> = Vec::new();
+    let (mut explode, mut explode_cap, mut explode_on) = (None, None, None);
+    let (mut mode, mut lucky, mut wild, mut keep, mut reroll) = (None, false, None, None, None);
+    let (mut minimum, mut maximum, mut each) = (None, None, None);
+    let (mut success, mut failure, mut crit_success, mut crit_failure) = (None, None, None, None);
+    let (mut matching, mut brutal, mut unique, mut sort) = (None, false, false, None);
+    loop {
+        let (rest, suffix) = match parse_suffix(input, single) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Error(_)) => break,
+            Err(err) => return Err(err),
+        };
+        if seen.contains(&suffix.name()) {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                input.trim_start(),
+                ErrorKind::Permutation,
+            )));
+        }
+        seen.push(suffix.name());
+        match suffix {
+            Suffix::Explode(kind, cap, on) => {
+                (explode, explode_cap, explode_on) = (Some(kind), cap, on);
+            }
+            Suffix::Mode(m) => mode = Some(m),
+            Suffix::Lucky => lucky = true,
+            Suffix::Wild(sides) => wild = Some(sides.unwrap_or(6)),
+            Suffix::Keep(k) => keep = Some(k),
+            Suffix::Reroll(r) => reroll = Some(r),
+            Suffix::Minimum(n) => minimum = Some(n),
+            Suffix::Maximum(n) => maximum = Some(n),
+            Suffix::Each(n) => each = Some(n),
+            Suffix::Success(target) => success = Some(target),
+            Suffix::Failure(target) => failure = Some(target),
+            Suffix::CritSuccess(c) => crit_success = Some(c),
+            Suffix::CritFailure(c) => crit_failure = Some(c),
+            Suffix::Matching(size) => matching = Some(size),
+            Suffix::Brutal => brutal = true,
+            Suffix::Unique => unique = true,
+            Suffix::Sort(order) => sort = Some(order),
+        }
+        input = rest;
+    }
+
+    let mut request = DiceRequest {
+        count: count.unwrap_or(1),
+        count_roll: count_roll.map(Box::new),
+        sides,
+        faces,
+        mode: mode.unwrap_or(RollMode::Normal),
+        explode,
+        explode_on,
+        explode_cap,
+        keep,
+        reroll,
+        success,
+        failure,
+        minimum,
+        maximum,
+        each,
+        crit_success: None,
+        crit_failure: None,
+        wild,
+        matching,
+        lucky,
+        brutal,
+        unique,
+        sort,
+    };
+    // A bare `cs` crits on the highest face, and a bare `cf` on the lowest.
+    let highest = Comparison {
+        op: CompareOp::Eq,
+        value: request.max_face(),
+    };
+    let lowest = Comparison {
+        op: CompareOp::Eq,
+        value: request.min_face(),
+    };
+    request.crit_success = crit_success.map(|c| c.unwrap_or(highest));
+    request.crit_failure = crit_failure.map(|c| c.unwrap_or(lowest));
+    Ok((input, request))
+}
+
+fn parse_binary_op(input: &str) -> IResult<&str, BinaryOp> {
+    ws(alt((
+        value(BinaryOp::Add, tag("+")),
+        value(BinaryOp::Sub, tag("-")),
+        value(BinaryOp::Mul, tag("*")),
+        value(BinaryOp::DivUp, tag("/^")),
+        value(BinaryOp::Div, tag("/")),
+    )))(input)
+}
+
+/// The die sizes `stepN` moves along, smallest first, unless the config
+/// file's `[step]` table gives its own.
+const STEP_CHAIN: &[i64] = &[4, 6, 8, 10, 12];
+
+thread_local! {
+    /// The config file's chain, set by `main` before anything is parsed.
+    static CONFIG_STEP_CHAIN: RefCell<Option<Vec<i64>>> = const { RefCell::new(None) };
+}
+
+/// The chain `stepN` moves along: the config file's, or else `STEP_CHAIN`.
+fn step_chain() -> Vec<i64> {
+    CONFIG_STEP_CHAIN.with_borrow(|chain| chain.clone().unwrap_or_else(|| STEP_CHAIN.to_vec()))
+}
+
+/// Moves a die `steps` places along `chain`. Stepping past either end stays
+/// on the end die and adds or subtracts 1 per die for each extra step, so a
+/// d12 stepped up twice is d12+2 and a d4 stepped down once is d4-1.
+fn step_die(mut request: DiceRequest, steps: i64, chain: &[i64]) -> Option<Node> {
+    let position = chain.iter().position(|&sides| sides == request.sides)? as i64;
+    let last = chain.len() as i64 - 1;
+    let target = position.saturating_add(steps);
+    let offset = target.saturating_sub(target.clamp(0, last));
+    request.sides = chain[target.clamp(0, last) as usize];
+    let dice = Node::Dice(Box::new(request.clone()));
+    let op = if offset > 0 {
+        BinaryOp::Add
+    } else {
+        BinaryOp::Sub
+    };
+    Some(match offset.checked_mul(request.count)?.checked_abs()? {
+        0 => dice,
+        n => Node::Group(Box::new(Node::Binary(
+            op,
+            Box::new(dice),
+            Box::new(Node::Constant(n)),
+        ))),
+    })
+}
+
+/// Parses an optional advantage or disadvantage suffix.
+fn parse_mode(input: &str, single: bool) -> IResult<&str, Option<RollMode>> {
+    // Suffixes starting with `d` are matched longest first: `dl` and `dh`
+    // always drop, `dis` is disadvantage, and only then is a bare `d`
+    // disadvantage. A bare `d` or `dN` could mean dropping dice, so it is
+    // only accepted on a single die with at least 2 throws, like `1d20d3`;
+    // pools spell it `dis`.
+    let mode_input = input.trim_start();
+    let (input, mode) = opt(ws(alt((
+        map(
+            preceded(alt((tag_no_case("adv"), tag_no_case("a"))), opt(parse_i64)),
+            |n| RollMode::Advantage(n.unwrap_or(2)),
+        ),
+        map(
+            preceded(
+                alt((
+                    tag_no_case("dis"),
+                    terminated(tag_no_case("d"), not(one_of("lhLH"))),
+                )),
+                opt(parse_i64),
+            ),
+            |n| RollMode::Disadvantage(n.unwrap_or(2)),
+        ),
+    ))))(input)?;
+    let bare = !mode_input.to_ascii_lowercase().starts_with("dis");
+    if let Some(RollMode::Disadvantage(n)) = mode
+        && bare
+        && (!single || n < 2)
+    {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            mode_input,
+            ErrorKind::Not,
+        )));
+    }
+    Ok((input, mode))
+}
+
+/// Parses a dice term and any `stepN` suffix. Stepping a die that isn't on
+/// the chain, or one with a rolled count, is a hard failure reported at the
+/// die.
+fn parse_dice_term(input: &str) -> IResult<&str, Node> {
+    let (rest, request) = parse_dice_expression(input)?;
+    let (rest, steps) = opt(ws(preceded(tag_no_case("step"), opt(parse_signed_i64))))(rest)?;
+    let Some(steps) = steps else {
+        return Ok((rest, Node::Dice(Box::new(request))));
+    };
+    let stepped = (request.faces == Faces::Standard && request.count_roll.is_none())
+        .then(|| step_die(request, steps.unwrap_or(1), &step_chain()))
+        .flatten();
+    match stepped {
+        Some(node) => Ok((rest, node)),
+        None => Err(nom::Err::Failure(nom::error::Error::new(
+            input.trim_start(),
+            ErrorKind::Verify,
+        ))),
+    }
+}
+
+/// Parses a dice term, a constant or a parenthesised group. A group missing
+/// its `)` is a hard failure reported at the opening parenthesis.
+fn parse_operand(input: &str) -> IResult<&str, Node> {
+    let (input, _) = space0(input)?;
+    let open: IResult<&str, &str> = tag("(")(input);
+    let Ok((inner, _)) = open else {
+        return alt((parse_dice_term, map(parse_signed_i64, Node::Constant)))(input);
+    };
+    // `(1d4)d6` is a dice term with a rolled count, not a group.
+    match parse_dice_term(input) {
+        Err(nom::Err::Error(_)) => {}
+        parsed => return parsed,
+    }
+    let (rest, node) = parse_binary(inner, 0)?;
+    let close: IResult<&str, &str> = ws(tag(")"))(rest);
+    match close {
+        Ok((rest, _)) => Ok((rest, Node::Group(Box::new(node)))),
+        Err(_) => Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            ErrorKind::Char,
+        ))),
+    }
+}
+
+/// Precedence climbing: parses operands joined by operators binding at
+/// least as tightly as `min_precedence`, grouping to the left.
+fn parse_binary(input: &str, min_precedence: u8) -> IResult<&str, Node> {
+    let (mut input, mut lhs) = parse_operand(input)?;
+    while let Ok((rest, op)) = parse_binary_op(input) {
+        if op.precedence() < min_precedence {
+            break;
+        }
+        // A dangling operator is left for the caller to report as unparsed.
+        let (rest, rhs) = match parse_binary(rest, op.precedence() + 1) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Error(_)) => break,
+            Err(err) => return Err(err),
+        };
+        lhs = Node::Binary(op, Box::new(lhs), Box::new(rhs));
+        input = rest;
+    }
+    Ok((input, lhs))
+}
+
+/// Labels are letters, digits, hyphens and underscores.
+/// Parses a label: a bare word like `off-hand_2`, or anything in double
+/// quotes with `""` for a quote, like `"Bob's ""big"" axe, swung"`.
+fn parse_label(input: &str) -> IResult<&str, String> {
+    alt((
+        map(take_while1(is_label_char), String::from),
+        verify(
+            delimited(
+                tag("\""),
+                map(
+                    many0(alt((value("\"", tag("\"\"")), is_not("\"")))),
+                    |parts| parts.concat(),
+                ),
+                tag("\""),
+            ),
+            |label: &str| !label.is_empty(),
+        ),
+    ))(input)
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_'
+}
+
+fn parse_expression(input: &str) -> IResult<&str, Expression> {
+    let start = input;
+    // `3#1d20` is the Roll20 spelling of `3x1d20`. The `#` has to follow the
+    // number directly, or it would start a comment.
+    let (input, repeat) = opt(alt((
+        terminated(ws(parse_i64), ws(tag_no_case("x"))),
+        terminated(ws(parse_i64), tag("#")),
+    )))(input)?;
+    let (input, pick) = opt(ws(parse_pick))(input)?;
+    let (input, mut root) = match pick {
+        Some(_) => delimited(ws(tag("(")), |input| parse_binary(input, 0), ws(tag(")")))(input)?,
+        None => parse_binary(input, 0)?,
+    };
+    // With a single dice term, a mode may follow the modifiers, so
+    // `1d20+3a` is `1d20a+3`. A bare `d` right after a number reads as a die
+    // missing its sides, so `2d6+1d` stays an error.
+    let mut input = input;
+    let mut check = None;
+    let mode_input = input.trim_start();
+    let after_number = mode_input.len() == input.len()
+        && start[..start.len() - input.len()].ends_with(|c: char| c.is_ascii_digit());
+    let bare_d =
+        mode_input.starts_with(['d', 'D']) && !mode_input.to_ascii_lowercase().starts_with("dis");
+    if let [request] = root.dice_mut().as_mut_slice()
+        && !(after_number && bare_d)
+    {
+        let (rest, mode) = parse_mode(input, is_single_die(request))?;
+        if let Some(mode) = mode {
+            if request.mode != RollMode::Normal {
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    mode_input,
+                    ErrorKind::Permutation,
+                )));
+            }
+            request.mode = mode;
+        }
+        input = rest;
+    }
+    // A comparison ending a longer expression is its check, even after a
+    // pool: `1d20+1d4>=15` adds the d4 before comparing. Pools there spell
+    // their target `tN`.
+    if !matches!(root, Node::Dice(_))
+        && let Some(request) = root.dice_mut().pop()
+        && let Some(target) = request.success
+        && start[..start.len() - input.len()]
+            .trim_end()
+            .ends_with(&target.to_string())
+    {
+        request.success = None;
+        check = Some(target);
+    }
+    let (input, floor) = opt(ws(alt((
+        preceded(tag("~"), parse_signed_i64),
+        value(0, tag_no_case("floor")),
+    ))))(input)?;
+    let (input, check) = match check {
+        Some(_) => (input, check),
+        None => opt(ws(parse_target))(input)?,
+    };
+    let (input, label) = opt(preceded(ws(tag(":")), ws(parse_label)))(input)?;
+    // Everything after a `#` is a comment. Face lists are parsed whole, so a
+    // `#` inside `d{...}` never gets here.
+    let (input, _) = opt(preceded(ws(tag("#")), rest))(input)?;
+    let (input, _) = space0(input)?;
+    Ok((
+        input,
+        Expression {
+            repeat: repeat.unwrap_or(1),
+            root,
+            label,
+            check,
+            side: None,
+            floor,
+            pick,
+        },
+    ))
+}
+
+/// Parses the `bo2` or `wo2` before a parenthesised expression.
+fn parse_pick(input: &str) -> IResult<&str, Pick> {
+    alt((
+        map(preceded(tag_no_case("bo"), parse_i64), Pick::Best),
+        map(preceded(tag_no_case("wo"), parse_i64), Pick::Worst),
+    ))(input)
+}
+
+/// Repeats `input` with a caret under the start of `rest`, its unparsed tail,
+/// followed by `note` if there is one.
+fn point_at(input: &str, rest: &str, note: &str) -> String {
+    let column = input[..input.len() - rest.len()].chars().count();
+    let note = if note.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", note)
+    };
+    format!("  {}\n  {}^{}", input, " ".repeat(column), note)
+}
+
+/// Works out what went wrong where parsing stopped at `rest`, returning the
+/// position to point at and a short description.
+fn diagnose<'a>(input: &'a str, rest: &'a str) -> (&'a str, String) {
+    let before = input[..input.len() - rest.len()].trim_end();
+    let mut chars = rest.chars();
+    match chars.next() {
+        // A die that didn't parse leaves its `d` behind.
+        Some(c @ ('d' | 'D'))
+            if before.is_empty() || before.ends_with(|c: char| c.is_ascii_digit()) =>
+        {
+            (
+                chars.as_str(),
+                format!("expected number of sides after '{}'", c),
+            )
+        }
+        Some(c @ ('+' | '-' | '*' | '/')) => {
+            let after = chars.as_str().trim_start_matches('^').trim_start();
+            (after, format!("expected a number or dice after '{}'", c))
+        }
+        Some(')') => (rest, "no '(' to close".to_string()),
+        Some(c) => (rest, format!("unexpected '{}'", c)),
+        None => (rest, "unexpected end of expression".to_string()),
+    }
+}
+
+/// Replaces each variable in `expression` with its value, ahead of parsing.
+/// A variable is a word where an operand belongs, like `prof` in
+/// `1d20+prof`; words that are dice, labels, comments and face lists are
+/// left alone.
+fn substitute_variables(expression: &str, variables: &HashMap<String, i64>) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = expression;
+    // A Roll20 repeat prefix like `3#` isn't a comment.
+    let digits = rest.trim_start().find(|c: char| !c.is_ascii_digit());
+    if let Some(end) = digits
+        && end > 0
+        && rest.trim_start()[end..].starts_with('#')
+    {
+        let prefix = rest.len() - rest.trim_start().len() + end + 1;
+        output.push_str(&rest[..prefix]);
+        rest = &rest[prefix..];
+    }
+    // Whether the next token starts an operand, as at the start or after an
+    // operator.
+    let mut operand = true;
+    while let Some(c) = rest.chars().next() {
+        if c == ':' || c == '#' {
+            output.push_str(rest);
+            break;
+        }
+        if c == '{' {
+            let end = face_list_end(rest);
+            output.push_str(&rest[..end]);
+            rest = &rest[end..];
+            operand = false;
+            continue;
+        }
+        // The `vs` of an opposed roll starts a fresh operand.
+        if output.ends_with(char::is_whitespace)
+            && rest
+                .get(..2)
+                .is_some_and(|word| word.eq_ignore_ascii_case("vs"))
+            && rest[2..].starts_with(char::is_whitespace)
+        {
+            output.push_str(&rest[..2]);
+            rest = &rest[2..];
+            operand = true;
+            continue;
+        }
+        // `bo2(` and `wo2(` are best-of and worst-of, not variables.
+        if operand
+            && let Ok((after, _)) = parse_pick(rest)
+            && after.trim_start().starts_with('(')
+        {
+            output.push_str(&rest[..rest.len() - after.len()]);
+            rest = after;
+            continue;
+        }
+        if operand && (c.is_ascii_alphabetic() || c == '_') {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            if let Some(value) = variables.get(word) {
+                output.push_str(&value.to_string());
+                rest = &rest[len..];
+                operand = false;
+                continue;
+            }
+            if parse_dice_expression(rest).is_err() {
+                bail!(
+                    "Error: Unknown variable '{}' in '{}'. Define it with --set {}=N.",
+                    word,
+                    expression,
+                    word
+                );
+            }
+        }
+        operand = matches!(c, '+' | '-' | '*' | '/' | '^' | '(' | '<' | '>' | '=')
+            || (operand && c.is_whitespace());
+        output.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(output)
+}
+
+/// The length of the face list that `input` starts with, up to and
+/// including its closing brace, skipping braces inside quotes.
+fn face_list_end(input: &str) -> usize {
+    let mut quoted = false;
+    for (i, c) in input.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '}' if !quoted => return i + 1,
+            _ => {}
+        }
+    }
+    input.len()
+}
+
+/// The length of the quoted label that `input` starts with, up to and
+/// including its closing quote. A doubled `""` reads as closing one quoted
+/// run and opening the next.
+fn quoted_end(input: &str) -> usize {
+    match input[1..].find('"') {
+        Some(end) => end + 2,
+        None => input.len(),
+    }
+}
+
+/// Splits one argument on the commas that separate independent expressions,
+/// leaving commas inside face lists and quoted labels alone.
+fn split_items(arg: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while let Some(c) = arg[i..].chars().next() {
+        match c {
+            '{' => i += face_list_end(&arg[i..]),
+            '"' => i += quoted_end(&arg[i..]),
+            ',' => {
+                items.push(&arg[start..i]);
+                i += 1;
+                start = i;
+            }
+            _ => i += c.len_utf8(),
+        }
+    }
+    items.push(&arg[start..]);
+    items
+}
+
+/// Parses the `index`th argument. A pasted string such as `1d20+5 2d6+3`,
+/// where one expression ends at a space and another follows, is split on
+/// whitespace and read as if each piece had been its own argument.
+fn parse_pasted(index: usize, arg: &str) -> Result<Vec<Expression>> {
+    parse_pasted_at(&format!("argument {}", index + 1), arg)
+}
+
+/// As `parse_pasted`, with `place` naming where `arg` came from in the
+/// error for a bad piece, e.g. `argument 2` or `rolls.txt, line 3`.
+fn parse_pasted_at(place: &str, arg: &str) -> Result<Vec<Expression>> {
+    let err = match parse_argument(arg) {
+        Ok(expressions) => return Ok(expressions),
+        Err(err) => err,
+    };
+    let pasted = match parse_expression(arg) {
+        Ok((rest, _)) => {
+            !rest.is_empty() && arg[..arg.len() - rest.len()].ends_with(char::is_whitespace)
+        }
+        Err(_) => false,
+    };
+    if !pasted || split_items(arg).len() > 1 || split_versus(arg).is_some() {
+        return Err(err);
+    }
+    let tokens: Vec<String> = arg.split_whitespace().map(String::from).collect();
+    let pieces = join_pieces(&tokens);
+    if pieces.len() < 2 {
+        return Err(err);
+    }
+    let mut expressions = Vec::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        let parsed = parse_argument(piece).map_err(|err| {
+            let message = err.to_string();
+            anyhow!(
+                "Error: {}, piece {}: {}",
+                place,
+                i + 1,
+                message.strip_prefix("Error: ").unwrap_or(&message)
+            )
+        })?;
+        expressions.extend(parsed);
+    }
+    Ok(expressions)
+}
+
+/// Parses every expression in one argument, numbering errors by item when
+/// the argument holds a comma-separated list.
+fn parse_argument(arg: &str) -> Result<Vec<Expression>> {
+    let items = split_items(arg);
+    if items.len() == 1 {
+        return parse_item(arg);
+    }
+    let items = items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            parse_item(item.trim()).map_err(|err| {
+                let message = err.to_string();
+                anyhow!(
+                    "Error: item {}: {}",
+                    i + 1,
+                    message.strip_prefix("Error: ").unwrap_or(&message)
+                )
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(items.into_iter().flatten().collect())
+}
+
+/// Finds the `vs` word splitting an opposed roll, outside face lists,
+/// quoted labels and comments.
+fn split_versus(item: &str) -> Option<(&str, &str)> {
+    let mut i = 0;
+    let mut boundary = true;
+    while let Some(c) = item[i..].chars().next() {
+        match c {
+            '{' => i += face_list_end(&item[i..]),
+            '"' => i += quoted_end(&item[i..]),
+            '#' => return None,
+            _ if boundary && item[i..].to_ascii_lowercase().starts_with("vs") => {
+                let after = &item[i + 2..];
+                if after.starts_with(char::is_whitespace) {
+                    return Some((&item[..i], after));
+                }
+                i += 1;
+            }
+            _ => i += c.len_utf8(),
+        }
+        boundary = c.is_whitespace();
+    }
+    None
+}
+
+/// Parses one expression, or both sides of an opposed roll.
+fn parse_item(item: &str) -> Result<Vec<Expression>> {
+    let Some((a, b)) = split_versus(item) else {
+        return Ok(vec![parse_and_validate(item)?]);
+    };
+    [(a, Side::A), (b, Side::B)]
+        .into_iter()
+        .map(|(side_str, side)| {
+            let mut expression = parse_and_validate(side_str.trim())?;
+            if expression.repeat > 1 {
+                bail!("Error: Each side of '{}' must be rolled once.", item);
+            }
+            expression.side = Some(side);
+            Ok(expression)
+        })
+        .collect()
+}
+
+fn parse_and_validate(s: &str) -> Result<Expression> {
+    validate_expression(s).map_err(|err| match suggest(s) {
+        Some(suggestion) => anyhow!("{}\nDid you mean '{}'?", err, suggestion),
+        None => err,
+    })
+}
+
+/// Tries a few cheap repairs for common typos, returning the first that
+/// makes `s` valid. The repair is only ever offered, never applied.
+fn suggest(s: &str) -> Option<String> {
+    // Swaps `from` for `to` wherever it sits next to a digit on the left and
+    // `digit_after` allows the right.
+    fn swap_near_digits(s: &str, from: &[char], to: char, digit_after: bool) -> String {
+        let chars: Vec<char> = s.chars().collect();
+        (0..chars.len())
+            .map(|i| {
+                let digit_before = i == 0 || chars[i - 1].is_ascii_digit();
+                let next_digit = chars.get(i + 1).is_some_and(char::is_ascii_digit);
+                if from.contains(&chars[i]) && digit_before && (next_digit || !digit_after) {
+                    to
+                } else {
+                    chars[i]
+                }
+            })
+            .collect()
+    }
+    let repairs: [fn(&str) -> String; 4] = [
+        |s| swap_near_digits(s, &['s', 'S'], 'd', true),
+        |s| swap_near_digits(s, &['o', 'O'], '0', false),
+        |s| s.chars().filter(|c| !c.is_whitespace()).collect(),
+        |s| s.to_lowercase(),
+    ];
+    let all = repairs.iter().fold(s.to_string(), |s, repair| repair(&s));
+    repairs
+        .iter()
+        .map(|repair| repair(s))
+        .chain(iter::once(all))
+        .find(|candidate| candidate != s && validate_expression(candidate).is_ok())
+}
+
+fn validate_expression(s: &str) -> Result<Expression> {
+    let (remainder, expression) = parse_expression(s).map_err(|err| match err {
+        nom::Err::Failure(err) if err.code == ErrorKind::Not => {
+            let number: String = err.input[1..]
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect();
+            // Disadvantage needs at least 2 throws, so `d1` can only be a drop.
+            let suggestion = if number.parse::<i64>().is_ok_and(|n| n < 2) {
+                format!("write dl{} to drop dice", number)
+            } else {
+                format!(
+                    "write dl{} to drop dice or dis{} for disadvantage",
+                    number, number
+                )
+            };
+            anyhow!(
+                "Error: 'd{}' in '{}' is ambiguous: {}.\n{}",
+                number,
+                s,
+                suggestion,
+                point_at(s, err.input, "")
+            )
+        }
+        nom::Err::Failure(err) if err.code == ErrorKind::Verify => anyhow!(
+            "Error: Only a fixed number of {} can be stepped in '{}'.\n{}",
+            step_chain()
+                .iter()
+                .map(|sides| format!("d{}", sides))
+                .collect::<Vec<_>>()
+                .join(", "),
+            s,
+            point_at(s, err.input, "")
+        ),
+        nom::Err::Failure(err) if err.code == ErrorKind::Permutation => {
+            match parse_suffix(err.input, true) {
+                Ok((_, Suffix::Mode(_))) | Err(_) => anyhow!(
+                    "Error: Conflicting modes in '{}': a die can only have one advantage or disadvantage.\n{}",
+                    s,
+                    point_at(s, err.input, "second mode")
+                ),
+                Ok((_, suffix)) => anyhow!(
+                    "Error: Conflicting suffixes in '{}': a die can only have one {}.\n{}",
+                    s,
+                    suffix.name(),
+                    point_at(s, err.input, &format!("second {}", suffix.name()))
+                ),
+            }
+        }
+        nom::Err::Failure(err) if err.code == ErrorKind::TooLarge => anyhow!(
+            "Error: Number too large in '{}' (max {}).\n{}",
+            s,
+            i64::MAX,
+            point_at(s, err.input, "number too large")
+        ),
+        nom::Err::Failure(err) => anyhow!(
+            "Error: Unclosed parenthesis in '{}'.\n{}",
+            s,
+            point_at(s, err.input, "")
+        ),
+        nom::Err::Error(err) => {
+            let (at, note) = diagnose(s, err.input);
+            anyhow!(
+                "Error: Failed to parse dice expression '{}'. Expected format 'NdS' (e.g. 1d20, 4d8).\n{}",
+                s,
+                point_at(s, at, &note)
+            )
+        }
+        nom::Err::Incomplete(_) => anyhow!("Error: Failed to parse dice expression '{}'.", s),
+    })?;
+
+    if !remainder.is_empty() {
+        let (at, note) = diagnose(s, remainder);
+        bail!(
+            "Error: Invalid dice format '{}'. Unparsed content: '{}'\n{}",
+            s,
+            remainder,
+            point_at(s, at, &note)
+        );
+    }
+
+    if expression.repeat <= 0 {
+        bail!("Error: Must repeat at least once.");
+    }
+    if expression.repeat > MAX_REPEAT {
+        bail!("Error: Cannot repeat more than {} times.", MAX_REPEAT);
+    }
+    if let Some(pick) = expression.pick {
+        if pick.attempts() < 2 {
+            bail!(
+                "Error: '{}' needs at least 2 attempts to choose from.",
+                pick
+            );
+        }
+        if pick.attempts() > MAX_REPEAT {
+            bail!("Error: Cannot make more than {} attempts.", MAX_REPEAT);
+        }
+    }
+
+    let dice = expression.root.dice();
+    if dice.is_empty() {
+        bail!("Error: '{}' has no dice to roll.", s);
+    }
+
+    for request in &dice {
+        validate_request(request, s)?;
+    }
+
+    // A count of successes and a sum don't add up to anything.
+    if dice.iter().any(|req| req.success.is_some())
+        && let Some(summed) = dice
+            .iter()
+            .find(|req| req.success.is_none() && !req.is_lookup())
+    {
+        bail!(
+            "Error: '{}' adds the sum of {} to a count of successes; roll them as separate expressions.",
+            s,
+            summed
+        );
+    }
+
+    // Bound the largest value the expression could produce, so totals can
+    // never overflow.
+    let bound = expression.root.bound();
+    if bound
+        .and_then(|n| n.checked_mul(expression.repeat))
+        .is_none()
+    {
+        bail!("Error: '{}' is too large to total without overflowing.", s);
+    }
+
+    let mut divisors = Vec::new();
+    expression.root.walk(&mut |node| {
+        if let Node::Binary(BinaryOp::Div | BinaryOp::DivUp, _, divisor) = node {
+            divisors.push(divisor);
+        }
+    });
+    for divisor in divisors {
+        if !divisor.dice().is_empty() {
+            bail!("Error: Can only divide by a constant, e.g. 8d6/2.");
+        }
+        if divisor.evaluate(&mut iter::empty()) == 0 {
+            bail!("Error: Cannot divide by zero.");
+        }
+    }
+
+    Ok(expression)
+}
+
+/// Checks one dice term, including any die that rolls its count.
+fn validate_request(request: &DiceRequest, s: &str) -> Result<()> {
+    if request.faces == Faces::Custom(Vec::new()) {
+        bail!("Error: A custom die needs at least one face.");
+    }
+
+    if request.sides <= 0 {
+        bail!("Error: Dice cannot have 0 or fewer sides.");
+    }
+
+    match &request.count_roll {
+        Some(count_roll) => {
+            validate_request(count_roll, s)?;
+            if count_roll.min_face() < 0 {
+                bail!("Error: A dice count cannot be negative.");
+            }
+            let most = count_roll.bound().unwrap_or(i64::MAX);
+            if most > MAX_DICE {
+                bail!(
+                    "Error: '{}' could roll up to {} dice; the limit is {}.",
+                    s,
+                    most,
+                    MAX_DICE
+                );
+            }
+        }
+        None => {
+            // `0d6` is allowed, so generated expressions can zero out a term.
+            if request.count < 0 {
+                bail!("Error: A dice count cannot be negative.");
+            }
+            // A plain sum can be streamed by --jsonl, so it may go further;
+            // everywhere else `check_held` still holds it to `MAX_DICE`.
+            let limit = if streams(request) {
+                MAX_STREAMED_DICE
+            } else {
+                MAX_DICE
+            };
+            if request.count > limit {
+                bail!("Error: Cannot roll more than {} dice at once.", limit);
+            }
+        }
+    }
+
+    if request.is_lookup() && request.explode.is_some() {
+        bail!("Error: {} dice cannot explode.", die_label(request));
+    }
+
+    if request.is_lookup() && matches!(request.reroll, Some(Reroll::Add(_))) {
+        bail!("Error: {} dice cannot add a reroll.", die_label(request));
+    }
+
+    if request.explode_cap.is_some_and(|cap| cap <= 0) {
+        bail!("Error: An explosion cap must be at least 1, e.g. 1d6!3.");
+    }
+
+    // A capped chain always ends, so only an uncapped one needs a face that
+    // doesn't explode.
+    if let Some(condition) = request.explode_on
+        && request.explode_cap.is_none()
+        && condition.matches_all(request.min_face(), request.max_face())
+    {
+        bail!(
+            "Error: Exploding on '{}' would explode every face of a {}.",
+            condition,
+            die_label(&DiceRequest {
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                ..request.clone()
+            })
+        );
+    }
+
+    match request.mode {
+        RollMode::Advantage(n) if n < 2 => {
+            bail!("Error: Advantage needs at least 2 dice to choose from, e.g. 1d20a3.");
+        }
+        RollMode::Disadvantage(n) if n < 2 => {
+            bail!("Error: Disadvantage needs at least 2 dice to choose from, e.g. 1d20d3.");
+        }
+        _ => {}
+    }
+
+    if matches!(request.faces, Faces::Text(_))
+        && (request.keep.is_some()
+            || request.reroll.is_some()
+            || request.minimum.is_some()
+            || request.maximum.is_some()
+            || request.each.is_some()
+            || request.mode != RollMode::Normal)
+    {
+        bail!(
+            "Error: Text dice cannot be kept, dropped, rerolled, clamped, modified or rolled with advantage."
+        );
+    }
+
+    let unclamped = || {
+        die_label(&DiceRequest {
+            minimum: None,
+            maximum: None,
+            ..request.clone()
+        })
+    };
+    if let Some(min) = request.minimum
+        && min > request.max_face()
+    {
+        bail!(
+            "Error: Minimum {} is higher than every face of a {}.",
+            min,
+            unclamped()
+        );
+    }
+    if let Some(max) = request.maximum {
+        if max < request.min_face() {
+            bail!(
+                "Error: Maximum {} is lower than every face of a {}.",
+                max,
+                unclamped()
+            );
+        }
+        // An exploding die can exceed its sides, so only the cap on a
+        // plain die is bounded by them.
+        if max > request.max_face() && request.explode.is_none() {
+            bail!(
+                "Error: Maximum {} is higher than every face of a {}.",
+                max,
+                unclamped()
+            );
+        }
+        if let Some(min) = request.minimum
+            && min > max
+        {
+            bail!("Error: Minimum {} is higher than maximum {}.", min, max);
+        }
+    }
+
+    match request.keep {
+        Some(Keep::Highest(n) | Keep::Lowest(n) | Keep::Middle(n)) => {
+            if n <= 0 {
+                bail!("Error: Must keep at least one die.");
+            }
+            if request.count_roll.is_none() && n > request.count {
+                bail!(
+                    "Error: Cannot keep {} dice when only {} are rolled.",
+                    n,
+                    request.count
+                );
+            }
+            if let Some(Keep::Middle(n)) = request.keep
+                && request.count_roll.is_none()
+                && (request.count - n) % 2 != 0
+            {
+                bail!(
+                    "Error: {} dice have no middle {}: the dropped dice must split evenly between highest and lowest.",
+                    request.count,
+                    n
+                );
+            }
+        }
+        Some(Keep::DropLowest(n) | Keep::DropHighest(n)) => {
+            if n <= 0 {
+                bail!("Error: Must drop at least one die.");
+            }
+            if request.count_roll.is_none() && n >= request.count {
+                bail!(
+                    "Error: Cannot drop {} dice when only {} are rolled.",
+                    n,
+                    request.count
+                );
+            }
+        }
+        None => {}
+    }
+
+    if let Some(sides) = request.wild {
+        if request.count != 1 || request.count_roll.is_some() {
+            bail!("Error: A wild die goes with a single trait die, e.g. 1d8!w.");
+        }
+        if sides <= 0 {
+            bail!("Error: A wild die must have more than 0 sides.");
+        }
+        if request.mode != RollMode::Normal || request.faces != Faces::Standard {
+            bail!(
+                "Error: A wild die only works with a plain trait die, not a {}.",
+                die_label(request)
+            );
+        }
+    }
+
+    if let Some(Some(size)) = request.matching
+        && size < 2
+    {
+        bail!("Error: A matching set needs at least 2 dice, not {}.", size);
+    }
+
+    if request.lucky && request.faces != Faces::Standard {
+        bail!(
+            "Error: Luck only rerolls natural 1s on numbered dice, not a {}.",
+            die_label(request)
+        );
+    }
+
+    if request.brutal && (request.explode.is_some() || request.is_lookup()) {
+        bail!(
+            "Error: Brutal bonus dice need a plain die that doesn't explode, not a {}.",
+            die_label(request)
+        );
+    }
+
+    if request.unique && request.count_roll.is_none() && request.count > request.distinct_faces() {
+        bail!(
+            "Error: Cannot roll {} unique values on a {}, which has only {} faces.",
+            request.count,
+            die_label(&DiceRequest {
+                unique: false,
+                ..request.clone()
+            }),
+            request.distinct_faces()
+        );
+    }
+
+    if request.failure.is_some() && request.success.is_none() {
+        bail!("Error: A failure clause needs a success target, e.g. 8d10>=7f1.");
+    }
+
+    if let Some(Reroll::Recursive(condition)) = request.reroll
+        && condition.matches_all(request.min_face(), request.max_face())
+    {
+        bail!(
+            "Error: Reroll condition '{}' matches every face of a {}.",
+            condition,
+            die_label(&DiceRequest {
+                reroll: None,
+                ..request.clone()
+            })
+        );
+    }
+
+    Ok(())
+}
+
+/// Upper bound on re-rolls for a single exploding die, so `d1!` terminates.
+const MAX_EXPLOSIONS: usize = 100;
+
+/// Upper bound on re-rolls for a single die, so `d1r1` terminates.
+const MAX_REROLLS: usize = 100;
+
+/// Upper bound on the dice thrown for one term, including rolled counts.
+const MAX_DICE: i64 = 10_000;
+
+/// Upper bound on the dice of a plain sum under --jsonl, which writes each
+/// die out as it is rolled instead of holding them all.
+const MAX_STREAMED_DICE: i64 = 1_000_000_000;
+
+/// Upper bound on the `Nx` repeat prefix.
+const MAX_REPEAT: i64 = 100;
+
+/// Source of die faces. Implemented for every `rand::Rng`, and scripted in
+/// tests to force specific results.
+trait DieRng {
+    /// Returns a value in `low..=high`.
+    fn roll(&mut self, low: i64, high: i64) -> i64;
+}
+
+impl<R: Rng> DieRng for R {
+    fn roll(&mut self, low: i64, high: i64) -> i64 {
+        self.gen_range(low..=high)
+    }
+}
+
+/// SplitMix64, the generator behind `--seed`. It is written out here rather
+/// than taken from `rand` so that a seed gives the same rolls in every
+/// version: changing the algorithm or how it maps onto a range would break
+/// every recorded seed.
+struct SeededRng(u64);
+
+impl SeededRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl DieRng for SeededRng {
+    /// Takes the next output modulo the size of the range, drawing again
+    /// when it falls in the uneven end of `u64` so every face is as likely.
+    fn roll(&mut self, low: i64, high: i64) -> i64 {
+        let span = high.abs_diff(low).wrapping_add(1);
+        if span == 0 {
+            return self.next_u64() as i64;
+        }
+        let limit = u64::MAX - u64::MAX % span;
+        loop {
+            let draw = self.next_u64();
+            if draw < limit {
+                return low.wrapping_add((draw % span) as i64);
+            }
+        }
+    }
+}
+
+/// Rolls the expression once, ignoring its repeat count.
+fn roll_expression(expression: &Expression, rng: &mut impl DieRng) -> ExpressionResult {
+    let mut result = ExpressionResult {
+        rolls: roll_dice(&expression.root.dice(), rng),
+        ..unrolled(expression)
+    };
+    result.check = check_total(expression, &result);
+    result
+}
+
+/// The result of `expression` before any of its dice are rolled.
+fn unrolled(expression: &Expression) -> ExpressionResult {
+    ExpressionResult {
+        root: expression.root.clone(),
+        rolls: Vec::new(),
+        repetition: None,
+        attempt: None,
+        round: None,
+        discarded: false,
+        label: expression
+            .label
+            .clone()
+            .or_else(|| expression.side.map(|side| format!("Side {:?}", side))),
+        check: None,
+        side: expression.side,
+        floor: expression.floor,
+    }
+}
+
+/// Compares the total of `result` with the check of `expression`, if it
+/// has one.
+fn check_total(expression: &Expression, result: &ExpressionResult) -> Option<Check> {
+    expression.check.map(|target| {
+        let total = result.total();
+        let margin = match target.op {
+            CompareOp::Lt | CompareOp::Le => target.value - total,
+            _ => total - target.value,
+        };
+        let natural = result
+            .rolls
+            .iter()
+            .filter(|res| res.request.faces == Faces::Standard && res.request.sides == 20)
+            .flat_map(|res| res.dice.iter().filter(|die| die.kept))
+            .map(|die| die.rolls[0])
+            .find(|&face| face == 20 || face == 1);
+        Check {
+            target,
+            passed: target.matches(total),
+            margin,
+            natural,
+        }
+    })
+}
+
+fn roll_dice(requests: &[&DiceRequest], rng: &mut impl DieRng) -> Vec<RollResult> {
+    requests
+        .iter()
+        .map(|&req| {
+            let count_roll = req
+                .count_roll
+                .as_ref()
+                .map(|count_req| Box::new(roll_dice(&[count_req], rng).remove(0)));
+            let count = count_roll
+                .as_ref()
+                .map_or(req.count, |res| res.total().max(0));
+            let mut dice: Vec<DieRoll> = Vec::new();
+            for _ in 0..count {
+                let mut die = roll_die(req, rng);
+                let mut duplicates = Vec::new();
+                let mut earlier = Vec::new();
+                while req.unique
+                    && duplicates.len() < MAX_REROLLS
+                    && dice.iter().any(|other: &DieRoll| other.value == die.value)
+                {
+                    duplicates.push(die.value);
+                    earlier.extend(die.throws.iter().map(|&(face, _)| (face, Throw::Rerolled)));
+                    die = roll_die(req, rng);
+                }
+                die.duplicates = duplicates;
+                earlier.append(&mut die.throws);
+                die.throws = earlier;
+                dice.push(die);
+            }
+            if let Some(keep) = req.keep {
+                apply_keep(&mut dice, keep);
+            }
+            // Bonus dice come after keep, so they are never dropped, and they
+            // never chain into further bonus dice.
+            if req.brutal {
+                let bonus_req = DiceRequest {
+                    brutal: false,
+                    ..req.clone()
+                };
+                let maximums = dice
+                    .iter()
+                    .filter(|die| die.kept && die.unclamped.unwrap_or(die.value) == req.max_face())
+                    .count();
+                for _ in 0..maximums {
+                    let mut die = roll_die(&bonus_req, rng);
+                    die.bonus = true;
+                    dice.push(die);
+                }
+            }
+            // Sorting happens after keep so ties are still broken in roll order.
+            match req.sort {
+                Some(Sort::Ascending) => dice.sort_by_key(|die| die.value),
+                Some(Sort::Descending) => dice.sort_by_key(|die| Reverse(die.value)),
+                None => {}
+            }
+            let sets = req
+                .matching
+                .map_or(Vec::new(), |size| matching_sets(&dice, size));
+            RollResult {
+                request: req.clone(),
+                count_roll,
+                dice,
+                sets,
+                streamed: 0,
+            }
+        })
+        .collect()
+}
+
+/// Rolls one die, or several under advantage and disadvantage, keeping the
+/// best. Ties go to the earliest roll. Luck rerolls each natural 1 before the
+/// best is picked, as the rules have it.
+fn roll_die(req: &DiceRequest, rng: &mut impl DieRng) -> DieRoll {
+    let throws = match req.mode {
+        RollMode::Normal => 1,
+        RollMode::Advantage(n) | RollMode::Disadvantage(n) => n,
+    };
+    let mut luck = false;
+    let mut chains: Vec<DieRoll> = (0..throws)
+        .map(|_| {
+            let chain = roll_chain(req, rng);
+            if req.lucky && chain.rolls[0] == 1 {
+                luck = true;
+                let mut lucky = roll_chain(req, rng);
+                lucky.throws = chain
+                    .throws
+                    .iter()
+                    .map(|&(face, _)| (face, Throw::Rerolled))
+                    .chain(lucky.throws)
+                    .collect();
+                lucky
+            } else {
+                chain
+            }
+        })
+        .collect();
+    // Every throw is kept in order, with the losing ones marked dropped.
+    let sequence = |chains: &[&DieRoll], winner: usize| -> Vec<(i64, Throw)> {
+        chains
+            .iter()
+            .enumerate()
+            .flat_map(|(i, chain)| {
+                chain.throws.iter().map(move |&(face, throw)| {
+                    if i == winner || throw == Throw::Rerolled {
+                        (face, throw)
+                    } else {
+                        (face, Throw::Dropped)
+                    }
+                })
+            })
+            .collect()
+    };
+    let best = match req.mode {
+        RollMode::Advantage(_) => chains
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, die)| Reverse(die.value)),
+        _ => chains.iter().enumerate().min_by_key(|(_, die)| die.value),
+    }
+    .map_or(0, |(i, _)| i);
+    let throws = sequence(&chains.iter().collect::<Vec<_>>(), best);
+    let mut die = chains.remove(best);
+    die.alternates = chains.iter().map(|other| other.value).collect();
+    die.throws = throws;
+    die.luck = luck;
+    if let Some(sides) = req.wild {
+        let wild_req = DiceRequest {
+            sides,
+            faces: Faces::Standard,
+            ..req.clone()
+        };
+        let mut wild = roll_chain(&wild_req, rng);
+        die.snake_eyes = die.rolls[0] == 1 && wild.rolls[0] == 1;
+        // The trait die wins ties, so the wild die only shows when it's higher.
+        let throws = sequence(&[&die, &wild], usize::from(wild.value > die.value));
+        if wild.value > die.value {
+            wild.snake_eyes = die.snake_eyes;
+            std::mem::swap(&mut die, &mut wild);
+        }
+        die.alternates = vec![wild.value];
+        die.throws = throws;
+    }
+    die
+}
+
+/// Rolls one die, applying any reroll rule to its first face, then
+/// re-rolling and accumulating while an exploding die shows its maximum, and
+/// finally clamping the result.
+fn roll_chain(req: &DiceRequest, rng: &mut impl DieRng) -> DieRoll {
+    let mut face = roll_face(req, rng);
+    let mut rerolled = Vec::new();
+    let mut rolls = Vec::new();
+    let mut throws = Vec::new();
+    match req.reroll {
+        Some(Reroll::Once(condition)) if condition.matches(face) => {
+            rerolled.push(face);
+            throws.push((face, Throw::Rerolled));
+            face = roll_face(req, rng);
+        }
+        // The added roll joins the die's chain, so it shows as a sum.
+        Some(Reroll::Add(condition)) if condition.matches(face) => {
+            rolls.push(face);
+            throws.push((face, Throw::Counted));
+            face = roll_face(req, rng);
+        }
+        Some(Reroll::Recursive(condition)) => {
+            while rerolled.len() < MAX_REROLLS && condition.matches(face) {
+                rerolled.push(face);
+                throws.push((face, Throw::Rerolled));
+                face = roll_face(req, rng);
+            }
+        }
+        _ => {}
+    }
+
+    rolls.push(face);
+    let first = rolls.len();
+    let mut capped = false;
+    if let Some(explode) = req.explode {
+        let limit = req
+            .explode_cap
+            .map_or(MAX_EXPLOSIONS, |cap| (cap as usize).min(MAX_EXPLOSIONS));
+        while req.explodes_on(face) {
+            if rolls.len() - first >= limit {
+                capped = req.explode_cap.is_some();
+                break;
+            }
+            throws.push((face, Throw::Exploded));
+            face = roll_face(req, rng);
+            rolls.push(match explode {
+                Explode::Penetrating => face - 1,
+                Explode::Standard | Explode::Compound => face,
+            });
+        }
+    }
+
+    throws.push((face, Throw::Counted));
+
+    // Clamping comes last, after any rerolls and explosions, so a capped
+    // die still explodes on its highest face.
+    let raw: i64 = rolls.iter().sum();
+    let value = req.minimum.map_or(raw, |min| raw.max(min));
+    let value = req.maximum.map_or(value, |max| value.min(max));
+    let value = value + req.each.unwrap_or(0);
+    let text = match &req.faces {
+        Faces::Text(faces) => Some(faces[raw as usize].clone()),
+        _ => None,
+    };
+    DieRoll {
+        value,
+        rolls,
+        text,
+        rerolled,
+        unclamped: (value != raw).then_some(raw),
+        alternates: Vec::new(),
+        duplicates: Vec::new(),
+        snake_eyes: false,
+        luck: false,
+        bonus: false,
+        capped,
+        kept: true,
+        throws,
+    }
+}
+
+fn roll_face(req: &DiceRequest, rng: &mut impl DieRng) -> i64 {
+    match &req.faces {
+        Faces::Digits { base, digits } => {
+            (0..*digits).fold(0, |acc, _| acc * 10 + rng.roll(1, *base))
+        }
+        Faces::Custom(faces) => faces[rng.roll(0, faces.len() as i64 - 1) as usize],
+        Faces::Text(_) => rng.roll(req.min_face(), req.max_face()),
+        Faces::Percentile => match rng.roll(0, 9) * 10 + rng.roll(0, 9) {
+            0 => 100,
+            value => value,
+        },
+        Faces::Standard | Faces::ZeroBased | Faces::Fate => {
+            rng.roll(req.min_face(), req.max_face())
+        }
+    }
+}
+
+/// Groups the kept dice by face, returning the largest sets, or with a
+/// `min_size` every set at least that big.
+fn matching_sets(dice: &[DieRoll], min_size: Option<i64>) -> Vec<(usize, i64)> {
+    let mut counts: HashMap<i64, usize> = HashMap::new();
+    for die in dice.iter().filter(|die| die.kept) {
+        *counts.entry(die.value).or_default() += 1;
+    }
+    let mut sets: Vec<(usize, i64)> = counts.into_iter().map(|(face, n)| (n, face)).collect();
+    sets.sort_by_key(|&(n, face)| (Reverse(n), face));
+    let threshold = match min_size {
+        Some(size) => size as usize,
+        None => sets.first().map_or(0, |&(n, _)| n).max(2),
+    };
+    sets.retain(|&(n, _)| n >= threshold);
+    sets
+}
+
+/// Marks every die outside the keep rule as dropped. Ties are broken in
+/// roll order, so the earlier of two equal dice is kept.
+fn apply_keep(dice: &mut [DieRoll], keep: Keep) {
+    let mut order: Vec<usize> = (0..dice.len()).collect();
+    match keep {
+        Keep::Highest(_) | Keep::DropLowest(_) => order.sort_by_key(|&i| Reverse(dice[i].value)),
+        Keep::Lowest(_) | Keep::DropHighest(_) | Keep::Middle(_) => {
+            order.sort_by_key(|&i| dice[i].value)
+        }
+    }
+    // A rolled count can leave fewer dice than the rule names.
+    let kept = keep.kept(dice.len() as i64).max(0) as usize;
+    // The middle skips past the lowest dice first. A rolled count that can't
+    // split evenly drops the odd die from the top.
+    let skipped = match keep {
+        Keep::Middle(_) => dice.len().saturating_sub(kept) / 2,
+        _ => 0,
+    };
+    for (rank, &i) in order.iter().enumerate() {
+        if rank < skipped || rank >= skipped + kept {
+            dice[i].kept = false;
+        }
+    }
+}
+
+/// Makes every dice term of `expression` count successes against the
+/// --target. Table lookups have no value to compare, so they are left alone,
+/// and a term with a target of its own is an error rather than a guess at
+/// which one was meant.
+fn apply_target(expression: &mut Expression, target: Comparison, input: &str) -> Result<()> {
+    for request in expression.root.dice_mut() {
+        if request.success.is_some() {
+            bail!(
+                "Error: '{}' already counts successes; give --target or its own target, not both.",
+                input
+            );
+        }
+        if !request.is_lookup() {
+            request.success = Some(target);
+        }
+    }
+    Ok(())
+}
+
+/// Rolls every attempt of a `bo2(...)` or `wo2(...)` expression and marks
+/// all but the picked one as discarded. Ties go to the earlier attempt.
+fn roll_attempts(
+    expression: &Expression,
+    pick: Pick,
+    rng: &mut impl DieRng,
+) -> Vec<ExpressionResult> {
+    let mut attempts: Vec<ExpressionResult> = (1..=pick.attempts())
+        .map(|n| ExpressionResult {
+            attempt: Some(n),
+            ..roll_expression(expression, rng)
+        })
+        .collect();
+    let totals = attempts.iter().map(ExpressionResult::total).enumerate();
+    let picked = match pick {
+        Pick::Best(_) => totals.reduce(|best, next| if next.1 > best.1 { next } else { best }),
+        Pick::Worst(_) => totals.reduce(|worst, next| if next.1 < worst.1 { next } else { worst }),
+    };
+    let picked = picked.map_or(0, |(i, _)| i);
+    for (i, attempt) in attempts.iter_mut().enumerate() {
+        attempt.discarded = i != picked;
+    }
+    attempts
+}
+
+fn execute_roll_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<String> {
+    Ok(format_table(
+        roll_arguments(dice_args, options, rng)?,
+        options,
+    ))
+}
+
+fn execute_markdown_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<String> {
+    Ok(format_markdown(
+        roll_arguments(dice_args, options, rng)?,
+        options,
+    ))
+}
+
+fn execute_json_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<String> {
+    Ok(format_json(
+        &roll_arguments(dice_args, options, rng)?,
+        options,
+    ))
+}
+
+/// Everything rolled for one command line.
+struct Rolled {
+    /// The argument each result came from, parallel to `results`
+    inputs: Vec<String>,
+    /// The canonical form of each result's expression, parallel to `results`
+    expressions: Vec<String>,
+    results: Vec<ExpressionResult>,
+}
+
+fn roll_arguments(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<Rolled> {
+    let mut rolled = Rolled {
+        inputs: Vec::new(),
+        expressions: Vec::new(),
+        results: Vec::new(),
+    };
+    roll_each(dice_args, options, rng, |input, expression, result| {
+        rolled.inputs.push(input.to_string());
+        rolled.expressions.push(expression.to_string());
+        rolled.results.push(result);
+        Ok(())
+    })?;
+    if let Some(order) = options.sort.filter(|_| options.sort_all) {
+        sort_expressions(&mut rolled, order);
+    }
+    Ok(rolled)
+}
+
+/// Lists every term's kept dice in `order` for --sort, followed by the
+/// dropped ones, leaving the totals as they were.
+fn sort_dice(result: &mut ExpressionResult, order: Sort) {
+    for roll in &mut result.rolls {
+        match order {
+            Sort::Ascending => roll.dice.sort_by_key(|die| (!die.kept, die.value)),
+            Sort::Descending => roll.dice.sort_by_key(|die| (!die.kept, Reverse(die.value))),
+        }
+    }
+}
+
+/// Orders the expressions of each round by total for --sort-all. The sides
+/// of an opposed roll and the attempts of a `bo2(...)` stay together, placed
+/// by the first side or the picked attempt.
+fn sort_expressions(rolled: &mut Rolled, order: Sort) {
+    let rows = rolled
+        .inputs
+        .drain(..)
+        .zip(rolled.expressions.drain(..))
+        .zip(rolled.results.drain(..));
+    let mut groups: Vec<Vec<((String, String), ExpressionResult)>> = Vec::new();
+    for row in rows {
+        let res = &row.1;
+        match groups.last_mut() {
+            Some(group) if res.side == Some(Side::B) || res.attempt.is_some_and(|n| n > 1) => {
+                group.push(row);
+            }
+            _ => groups.push(vec![row]),
+        }
+    }
+    let key = |group: &Vec<((String, String), ExpressionResult)>| {
+        let res = group.iter().map(|(_, res)| res).find(|res| !res.discarded);
+        let total = res.map_or(0, ExpressionResult::total);
+        let total = match order {
+            Sort::Ascending => total,
+            Sort::Descending => -total,
+        };
+        (group[0].1.round, total)
+    };
+    groups.sort_by_key(key);
+    for ((input, expression), res) in groups.into_iter().flatten() {
+        rolled.inputs.push(input);
+        rolled.expressions.push(expression);
+        rolled.results.push(res);
+    }
+}
+
+/// Arguments parsed ahead of rolling, so that they can be rolled again
+/// without parsing them twice, as the interactive mode's history does.
+#[derive(Debug, Clone)]
+pub(crate) struct Parsed {
+    inputs: Vec<String>,
+    /// What each of `inputs` parsed to, with any --target and default crit
+    /// range applied
+    expressions: Vec<Vec<Expression>>,
+}
+
+impl Parsed {
+    fn new(dice_args: &[String], options: &Options) -> Result<Parsed> {
+        let mut expressions = dice_args
+            .iter()
+            .enumerate()
+            .map(|(i, arg)| parse_pasted(i, arg))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(target) = options.target {
+            for (arg, expressions) in dice_args.iter().zip(&mut expressions) {
+                for expression in expressions {
+                    apply_target(expression, target, arg)?;
+                }
+            }
+        }
+        if let Some(crit) = options.crit {
+            for request in expressions
+                .iter_mut()
+                .flatten()
+                .flat_map(|e| e.root.dice_mut())
+            {
+                if request.faces == Faces::Standard
+                    && request.sides == 20
+                    && request.crit_success.is_none()
+                {
+                    request.crit_success = Some(crit);
+                }
+            }
+        }
+        Ok(Parsed {
+            inputs: dice_args.to_vec(),
+            expressions,
+        })
+    }
+}
+
+/// Something to roll: the arguments as typed, or already `Parsed`.
+trait Arguments {
+    fn inputs(&self) -> &[String];
+
+    fn parsed(&self, options: &Options) -> Result<Cow<'_, Parsed>>;
+}
+
+impl<T: AsRef<[String]> + ?Sized> Arguments for T {
+    fn inputs(&self) -> &[String] {
+        self.as_ref()
+    }
+
+    fn parsed(&self, options: &Options) -> Result<Cow<'_, Parsed>> {
+        Parsed::new(self.as_ref(), options).map(Cow::Owned)
+    }
+}
+
+impl Arguments for Parsed {
+    fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    fn parsed(&self, _options: &Options) -> Result<Cow<'_, Parsed>> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+/// Parses every argument, then hands each result to `visit` as soon as it is
+/// rolled, along with its argument and canonical expression. With more than
+/// one round, every argument is rolled again for each.
+fn roll_each(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+    visit: impl FnMut(&str, &str, ExpressionResult) -> Result<()>,
+) -> Result<()> {
+    roll_each_with(dice_args, options, rng, None, visit)
+}
+
+/// Takes each die as it is rolled: its argument, canonical expression and
+/// result, the term and die numbers, and the die. A streamed result has no
+/// dice of its own yet.
+type OnDie<'a> = dyn FnMut(&str, &str, &ExpressionResult, (usize, usize), &DiceRequest, &DieRoll) -> Result<()>
+    + 'a;
+
+/// As `roll_each`, also handing every die to `on_die`. An expression that
+/// `can_stream` is rolled one die at a time with only its sums kept, so its
+/// terms may go past `MAX_DICE` and `visit` gets its result without dice.
+fn stream_each(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+    on_die: &mut OnDie,
+    visit: impl FnMut(&str, &str, ExpressionResult) -> Result<()>,
+) -> Result<()> {
+    roll_each_with(dice_args, options, rng, Some(on_die), visit)
+}
+
+fn roll_each_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+    mut on_die: Option<&mut OnDie>,
+    mut visit: impl FnMut(&str, &str, ExpressionResult) -> Result<()>,
+) -> Result<()> {
+    let parsed = dice_args.parsed(options)?;
+    let streaming = on_die.is_some();
+    for expression in parsed.expressions.iter().flatten() {
+        if !(streaming && can_stream(expression, options)) {
+            check_held(expression)?;
+        }
+    }
+    for round in 1..=options.rounds {
+        for (arg, expressions) in parsed.inputs.iter().zip(&parsed.expressions) {
+            for expression in expressions {
+                let canonical = expression.to_string();
+                let streamed = streaming && can_stream(expression, options);
+                for n in 1..=expression.repeat {
+                    let number = |result: &mut ExpressionResult| {
+                        if expression.repeat > 1 {
+                            result.repetition = Some(n);
+                        }
+                        if options.rounds > 1 {
+                            result.round = Some(round);
+                        }
+                    };
+                    if let Some(on_die) = on_die.as_deref_mut().filter(|_| streamed) {
+                        let mut result = unrolled(expression);
+                        number(&mut result);
+                        for (term, req) in expression.root.dice().into_iter().enumerate() {
+                            let mut total = 0;
+                            for index in 0..req.count as usize {
+                                let die = roll_die(req, rng);
+                                total += die.score(req);
+                                on_die(arg, &canonical, &result, (term, index), req, &die)?;
+                            }
+                            result.rolls.push(RollResult {
+                                request: req.clone(),
+                                count_roll: None,
+                                dice: Vec::new(),
+                                sets: Vec::new(),
+                                streamed: total,
+                            });
+                        }
+                        result.check = check_total(expression, &result);
+                        visit(arg, &canonical, result)?;
+                        continue;
+                    }
+                    let mut attempts = match expression.pick {
+                        Some(pick) => roll_attempts(expression, pick, rng),
+                        None => vec![roll_expression(expression, rng)],
+                    };
+                    for result in &mut attempts {
+                        if let Some(order) = options.sort {
+                            sort_dice(result, order);
+                        }
+                        number(result);
+                    }
+                    for result in attempts {
+                        if let Some(on_die) = on_die.as_deref_mut() {
+                            for (term, roll) in result.rolls.iter().enumerate() {
+                                for (index, die) in roll.dice.iter().enumerate() {
+                                    on_die(
+                                        arg,
+                                        &canonical,
+                                        &result,
+                                        (term, index),
+                                        &roll.request,
+                                        die,
+                                    )?;
+                                }
+                            }
+                        }
+                        visit(arg, &canonical, result)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a term's dice can be rolled and let go one at a time, keeping
+/// only their sum: nothing about it weighs one die against the others or
+/// waits on a rolled count.
+fn streams(req: &DiceRequest) -> bool {
+    req.keep.is_none()
+        && req.sort.is_none()
+        && !req.unique
+        && req.matching.is_none()
+        && !req.brutal
+        && req.count_roll.is_none()
+}
+
+/// Whether `stream_each` can roll `expression` one die at a time: a single
+/// attempt at terms that all `streams`, with no --sort to wait for.
+fn can_stream(expression: &Expression, options: &Options) -> bool {
+    expression.pick.is_none()
+        && options.sort.is_none()
+        && expression.root.dice().into_iter().all(streams)
+}
+
+/// Holds `expression` to `MAX_DICE` a term, for everything that keeps its
+/// dice rather than streaming them.
+fn check_held(expression: &Expression) -> Result<()> {
+    if expression
+        .root
+        .dice()
+        .iter()
+        .any(|req| req.count > MAX_DICE)
+    {
+        bail!(
+            "Error: Cannot roll more than {} dice at once, except as a plain sum with --jsonl.",
+            MAX_DICE
+        );
+    }
+    Ok(())
+}
+
+/// The grand totals, kept apart for sums and success pools as in the table.
+#[derive(Default)]
+struct Totals {
+    /// The sum of everything summable, if anything was
+    total: Option<i64>,
+    /// The successes across every pool, if there were any
+    successes: Option<i64>,
+}
+
+impl Totals {
+    fn add(&mut self, res: &ExpressionResult) {
+        // Opposed rolls are compared with each other, so they stay out.
+        if res.side.is_some() || res.discarded {
+            return;
+        }
+        if res.is_pool() {
+            *self.successes.get_or_insert(0) += res.total();
+        } else if res.has_summable() {
+            *self.total.get_or_insert(0) += res.summable_total();
+        }
+    }
+}
+
+/// A round's totals in one cell, e.g. `17` or `12; 3 successes`.
+fn format_totals(totals: &Totals) -> String {
+    match (totals.total, totals.successes) {
+        (Some(total), Some(successes)) => format!("{}; {}", total, format_successes(successes)),
+        (Some(total), None) => total.to_string(),
+        (None, Some(successes)) => format_successes(successes),
+        (None, None) => "-".to_string(),
+    }
+}
+
+/// The cells of the output table, shared by every table-shaped format.
+struct TableRows {
+    header: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+    /// How many of the last rows are grand totals
+    totals: usize,
+    /// How to color the Roll cell of each die's row, by row number
+    highlights: HashMap<usize, Highlight>,
+    /// The part of a kept die's Roll cell listing the dice its advantage
+    /// left over, by row number, dimmed like dropped dice
+    leftovers: HashMap<usize, Range<usize>>,
+}
+
+/// What a die's Roll cell is colored for in a terminal.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Highlight {
+    /// A kept die that came up its highest face
+    Maximum,
+    /// A kept die that came up its lowest face
+    Minimum,
+    Dropped,
+}
+
+impl Highlight {
+    /// Picks the highlight for a die, judged on its first, natural face.
+    /// Text faces have no highest or lowest, so only dropping shows.
+    fn of(die: &DieRoll, req: &DiceRequest) -> Option<Highlight> {
+        let natural = die.rolls.first().copied();
+        if !die.kept {
+            Some(Highlight::Dropped)
+        } else if matches!(req.faces, Faces::Text(_)) {
+            None
+        } else if natural == Some(req.max_face()) {
+            Some(Highlight::Maximum)
+        } else if natural == Some(req.min_face()) {
+            Some(Highlight::Minimum)
+        } else {
+            None
+        }
+    }
+
+    fn apply(self, cell: Cell) -> Cell {
+        match self {
+            Highlight::Maximum => cell.fg(Color::Green),
+            Highlight::Minimum => cell.fg(Color::Red),
+            Highlight::Dropped => cell.add_attribute(Attribute::Dim),
+        }
+    }
+
+    /// Colors just `span` of a cell's text, which comfy_table can only
+    /// style whole.
+    fn apply_span(self, text: &str, span: Range<usize>) -> String {
+        let (start, end) = match self {
+            Highlight::Maximum => ("\x1b[32m", "\x1b[39m"),
+            Highlight::Minimum => ("\x1b[31m", "\x1b[39m"),
+            Highlight::Dropped => ("\x1b[2m", "\x1b[22m"),
+        };
+        format!(
+            "{}{}{}{}{}",
+            &text[..span.start],
+            start,
+            &text[span.clone()],
+            end,
+            &text[span.end..]
+        )
+    }
+}
+
+fn format_table(rolled: Rolled, options: &Options) -> String {
+    let TableRows {
+        header,
+        rows,
+        highlights,
+        mut leftovers,
+        ..
+    } = table_rows(rolled, options);
+    let roll_column = header.iter().position(|&cell| cell == "Roll");
+    let mut table = options.style.table();
+    table.set_header(header);
+    // Styling is decided by `options.color` alone, never by comfy_table.
+    if options.color {
+        table.enforce_styling();
+    } else {
+        table.force_no_tty();
+    }
+    for (i, row) in rows.into_iter().enumerate() {
+        let highlight = highlights.get(&i).filter(|_| options.color);
+        let leftover = leftovers.remove(&i).filter(|_| options.color);
+        table.add_row(row.into_iter().enumerate().map(|(column, text)| {
+            if Some(column) != roll_column {
+                return Cell::new(text);
+            }
+            let cell = match leftover.clone() {
+                Some(span) => Cell::new(Highlight::Dropped.apply_span(&text, span)),
+                None => Cell::new(text),
+            };
+            match highlight {
+                Some(highlight) => highlight.apply(cell),
+                None => cell,
+            }
+        }));
+    }
+    table.to_string()
+}
+
+/// The table as GitHub-flavored markdown, for pasting into chat or notes.
+/// Rolls are right-aligned and the totals are bold.
+fn format_markdown(rolled: Rolled, options: &Options) -> String {
+    let TableRows {
+        header,
+        rows,
+        totals,
+        ..
+    } = table_rows(
+        rolled,
+        // Markdown is never colored, and would show the escape codes.
+        &Options {
+            color: false,
+            ..*options
+        },
+    );
+    let line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+    let mut lines = vec![line(header.iter().map(|cell| cell.to_string()).collect())];
+    lines.push(line(
+        header
+            .iter()
+            .map(|&cell| if cell == "Roll" { "---:" } else { ":---" }.to_string())
+            .collect(),
+    ));
+    let body = rows.len() - totals;
+    for (i, row) in rows.into_iter().enumerate() {
+        let cells = row.iter().map(|cell| {
+            let cell = cell.replace('|', "\\|");
+            if i >= body && !cell.is_empty() {
+                format!("**{}**", cell)
+            } else {
+                cell
+            }
+        });
+        lines.push(line(cells.collect()));
+    }
+    lines.join("\n")
+}
+
+/// With --verbose, a last Rolls column lists every face thrown for each die.
+fn table_rows(rolled: Rolled, options: &Options) -> TableRows {
+    let verbose = options.verbose;
+    let Rolled {
+        expressions,
+        results,
+        ..
+    } = rolled;
+    // 3. Format Output
+    // Every row starts with a label cell, dropped unless something is labelled.
+    // Past two expressions it becomes an Expression column naming each group
+    // on its first row, by label or else by the expression itself.
+    // Check rows carry a fourth cell, and the column only appears for them.
+    let grouped = results.len() > 2;
+    let labelled = grouped || results.iter().any(|res| res.label.is_some());
+    let checked = results.iter().any(|res| res.check.is_some());
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    // The Rolls cell of each die's row, by row number
+    let mut throws: HashMap<usize, String> = HashMap::new();
+    let mut highlights: HashMap<usize, Highlight> = HashMap::new();
+    let mut leftovers: HashMap<usize, Range<usize>> = HashMap::new();
+    // With --stats, the Min, Mean and Max cells on each expression's last
+    // row, worked out once for each distinct expression
+    let mut ranges: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut summaries = Summaries::default();
+
+    // Sums and success counts don't mix, so pools get their own total.
+    // Opposed rolls are compared with each other, so they stay out of the totals.
+    let (pools, sums): (Vec<_>, Vec<_>) = results
+        .iter()
+        .filter(|expr| expr.side.is_none() && !expr.discarded)
+        .partition(|expr| expr.is_pool());
+    let total_sum: i64 = sums.iter().map(|expr| expr.summable_total()).sum();
+    let has_summable = sums.iter().any(|expr| expr.has_summable());
+    let pool_total: i64 = pools.iter().map(|expr| expr.total()).sum();
+    let pool_count = pools.len();
+    let show_subtotals = results.len() > 1;
+    let mut row_count = 0;
+    let mut side_a: Option<(String, i64)> = None;
+    // Under --repeat, each round ends with a row of its own totals.
+    let mut round: Option<(i64, Totals)> = None;
+    let round_row = |(n, totals): (i64, Totals)| {
+        vec![
+            String::new(),
+            format!("Round {}", n),
+            format_totals(&totals),
+        ]
+    };
+
+    for (expr, expression) in results.into_iter().zip(expressions) {
+        if let Some(n) = expr.round {
+            if round.as_ref().is_some_and(|(current, _)| *current != n) {
+                rows.extend(round.take().map(round_row));
+            }
+            round
+                .get_or_insert_with(|| (n, Totals::default()))
+                .1
+                .add(&expr);
+        }
+        let subtotal = expr.total();
+        // A floored total shows what it was raised from.
+        let raw = expr.raw_total();
+        let subtotal_str = if raw == subtotal {
+            subtotal.to_string()
+        } else {
+            format!("{} → {}", raw, subtotal)
+        };
+        let is_pool = expr.is_pool();
+        let modifier = expr.root.modifier();
+        let expr_rows = expr
+            .rolls
+            .iter()
+            .map(|res| res.dice.len().max(1) + usize::from(res.count_roll.is_some()))
+            .sum::<usize>()
+            + usize::from(modifier != Some(0));
+        let name = match (&expr.label, expr.repetition) {
+            (Some(label), Some(n)) => format!("{} {}", label, n),
+            (Some(label), None) => label.clone(),
+            (None, Some(n)) if grouped => format!("{} {}", expression, n),
+            (None, None) if grouped => expression,
+            (None, _) => String::new(),
+        };
+        let group_start = rows.len();
+        // In a plain sum, subtracted dice are marked so the rows still add up.
+        let negated = match modifier {
+            Some(_) => expr.root.negated_dice(),
+            None => vec![false; expr.rolls.len()],
+        };
+        for (res, &negated) in expr.rolls.iter().zip(&negated) {
+            if let Some(count) = &res.count_roll {
+                rows.push(vec![
+                    name.clone(),
+                    "count".to_string(),
+                    format!(
+                        "{}{} → {}",
+                        count.request.count,
+                        die_label(&count.request),
+                        count.total()
+                    ),
+                ]);
+                row_count += 1;
+            }
+            let label = format!(
+                "{}{}",
+                if negated { "-" } else { "" },
+                die_label(&res.request)
+            );
+            if res.dice.is_empty() {
+                rows.push(vec![name.clone(), label.clone(), format!("0{} → 0", label)]);
+                row_count += 1;
+            }
+            for die in &res.dice {
+                let mut value_str = format_die_value(die, &res.request);
+                let fancy =
+                    options.fancy && res.request.faces == Faces::Standard && res.request.sides == 6;
+                if fancy {
+                    value_str = format!("{} {}", value_str, format_pips(die));
+                }
+                if !die.rerolled.is_empty() {
+                    value_str = format!("{} ({})", value_str, format_rerolls(&die.rerolled));
+                }
+                if die.capped {
+                    value_str = format!(
+                        "{} (capped at {})",
+                        value_str,
+                        res.request.explode_cap.unwrap_or_default()
+                    );
+                }
+                if die.luck {
+                    value_str = format!("{} (luck: rerolled 1)", value_str);
+                }
+                if !die.duplicates.is_empty() {
+                    value_str = format!("{} ({})", value_str, format_duplicates(&die.duplicates));
+                }
+                if let Some(target) = res.request.success {
+                    let each = res.request.each.unwrap_or(0);
+                    let hits = die.count_matching(target, res.request.explode, each);
+                    let misses = res
+                        .request
+                        .failure
+                        .map_or(0, |c| die.count_matching(c, res.request.explode, each));
+                    if hits + misses > 0 {
+                        value_str = format!(
+                            "{} {}{}",
+                            value_str,
+                            "✓".repeat(hits as usize),
+                            "✗".repeat(misses as usize)
+                        );
+                    }
+                }
+                let roll_str = if !die.kept {
+                    format!("({})", value_str)
+                } else if die.alternates.is_empty() {
+                    value_str
+                } else {
+                    let alternates: Vec<String> = die
+                        .alternates
+                        .iter()
+                        .map(|&face| match pip(face).filter(|_| fancy) {
+                            Some(pip) => format!("{} {}", face, pip),
+                            None => face.to_string(),
+                        })
+                        .collect();
+                    let dropped = format!("({})", alternates.join(", "));
+                    // The dice left over from advantage are in the kept
+                    // die's cell, so only they are dimmed.
+                    let start = value_str.len() + 1;
+                    leftovers.insert(rows.len(), start..start + dropped.len());
+                    format!("{} {}", value_str, dropped)
+                };
+                let roll_str = match crit_tag(die, &res.request) {
+                    Some(tag) => format!("{} {}", roll_str, tag),
+                    None => roll_str,
+                };
+                let roll_str = if die.snake_eyes {
+                    format!("{} SNAKE EYES", roll_str)
+                } else {
+                    roll_str
+                };
+                let die_str = if die.bonus {
+                    format!("{}*", label)
+                } else {
+                    label.clone()
+                };
+                if verbose {
+                    throws.insert(rows.len(), format_throws(die));
+                }
+                if let Some(highlight) = Highlight::of(die, &res.request) {
+                    highlights.insert(rows.len(), highlight);
+                }
+                rows.push(vec![name.clone(), die_str, roll_str]);
+                row_count += 1;
+            }
+            if res.request.matching.is_some() {
+                rows.push(vec![
+                    name.clone(),
+                    "Matches".to_string(),
+                    format_sets(&res.sets),
+                ]);
+            }
+        }
+        match modifier {
+            Some(0) => {}
+            Some(modifier) => {
+                rows.push(vec![
+                    name.clone(),
+                    "Modifier".to_string(),
+                    format_modifier(modifier),
+                ]);
+                row_count += 1;
+            }
+            None => {
+                rows.push(vec![
+                    name.clone(),
+                    "Formula".to_string(),
+                    format!("{} = {}", expr.formula(), subtotal_str),
+                ]);
+                row_count += 1;
+            }
+        }
+        // Repeated rolls and attempts are numbered and always get a subtotal
+        // line, and an attempt that wasn't picked is shown in parentheses.
+        let number = match (expr.repetition, expr.attempt) {
+            (Some(n), Some(attempt)) => format!(" {}.{}", n, attempt),
+            (Some(n), None) | (None, Some(n)) => format!(" {}", n),
+            (None, None) => String::new(),
+        };
+        let mark = |total: String| {
+            if expr.discarded {
+                format!("({})", total)
+            } else {
+                total
+            }
+        };
+        if let Some(check) = &expr.check {
+            let total = if is_pool {
+                format_successes(subtotal)
+            } else {
+                subtotal_str.clone()
+            };
+            rows.push(vec![
+                name.clone(),
+                format!("Check{} {}", number, check.target),
+                mark(total),
+                format_check(check),
+            ]);
+        } else if is_pool {
+            rows.push(vec![
+                name.clone(),
+                format!("Successes{}", number),
+                mark(format_successes(subtotal)),
+            ]);
+        } else if expr.attempt.is_some() {
+            rows.push(vec![
+                name.clone(),
+                format!("Attempt{}", number),
+                mark(subtotal_str),
+            ]);
+        } else if expr.repetition.is_some()
+            || expr.side.is_some()
+            || raw != subtotal
+            || (show_subtotals && expr_rows > 1)
+        {
+            // With several expressions, each subtotal names the one it adds
+            // up, unless it is already numbered as a repetition.
+            let subtotal_label = if show_subtotals && number.is_empty() {
+                format!("Subtotal ({})", expr.root)
+            } else {
+                format!("Subtotal{}", number)
+            };
+            rows.push(vec![name.clone(), subtotal_label, subtotal_str]);
+        }
+        if options.stats {
+            ranges.insert(rows.len() - 1, format_summary(summaries.get(&expr)));
+        }
+        if grouped {
+            for row in rows.iter_mut().skip(group_start + 1) {
+                row[0].clear();
+            }
+        }
+        if expr.discarded {
+            continue;
+        }
+        match expr.side {
+            Some(Side::A) => side_a = Some((name.clone(), subtotal)),
+            Some(Side::B) => {
+                if let Some((a_name, a_total)) = side_a.take() {
+                    let outcome = format_outcome((&a_name, a_total), (&name, subtotal));
+                    rows.push(vec![String::new(), "Outcome".to_string(), outcome]);
+                }
+            }
+            None => {}
+        }
+    }
+
+    rows.extend(round.take().map(round_row));
+
+    let body = rows.len();
+    if row_count > 1 && has_summable && !options.no_total {
+        rows.push(vec![
+            String::new(),
+            "Total".to_string(),
+            total_sum.to_string(),
+        ]);
+    }
+    if pool_count > 1 && !options.no_total {
+        let label = if has_summable {
+            "Total successes"
+        } else {
+            "Total"
+        };
+        rows.push(vec![
+            String::new(),
+            label.to_string(),
+            format_successes(pool_total),
+        ]);
+    }
+
+    let totals = rows.len() - body;
+    let mut header = vec![if grouped { "Expression" } else { "Label" }, "Die", "Roll"];
+    if checked {
+        header.push("Check");
+    }
+    if options.stats {
+        header.extend(["Min", "Mean", "Max"]);
+    }
+    if verbose {
+        header.push("Rolls");
+    }
+    if !labelled {
+        header.remove(0);
+    }
+    for (i, row) in rows.iter_mut().enumerate() {
+        if checked && row.len() < 4 {
+            row.push(String::new());
+        }
+        if options.stats {
+            row.extend(ranges.remove(&i).unwrap_or_else(|| vec![String::new(); 3]));
+        }
+        if verbose {
+            row.push(throws.remove(&i).unwrap_or_default());
+        }
+        if !labelled {
+            row.remove(0);
+        }
+    }
+    TableRows {
+        header,
+        rows,
+        totals,
+        highlights,
+        leftovers,
+    }
+}
+
+/// The --stats range and average of each distinct expression, worked out
+/// once however often it is rolled.
+#[derive(Default)]
+struct Summaries(HashMap<String, Option<stats::Summary>>);
+
+impl Summaries {
+    fn get(&mut self, res: &ExpressionResult) -> Option<stats::Summary> {
+        let key = format!("{}~{:?}", res.root, res.floor);
+        *self
+            .0
+            .entry(key)
+            .or_insert_with(|| stats::summarize(&res.root, res.floor))
+    }
+}
+
+/// The Min, Mean and Max cells for --stats. An estimate from simulation is
+/// marked with `~`, and a range with no end, from an uncapped exploding
+/// die, shows `∞`.
+fn format_summary(summary: Option<stats::Summary>) -> Vec<String> {
+    let Some(summary) = summary else {
+        return vec![String::new(); 3];
+    };
+    let mark = if summary.exact { "" } else { "~" };
+    let mean = format_mean(summary.mean);
+    vec![
+        summary
+            .min
+            .map_or("-∞".to_string(), |min| format!("{}{}", mark, min)),
+        format!("{}{}", mark, mean),
+        summary
+            .max
+            .map_or("∞".to_string(), |max| format!("{}{}", mark, max)),
+    ]
+}
+
+/// A mean to at most two decimal places, e.g. `10.5` or `13.83`.
+fn format_mean(mean: f64) -> String {
+    // Nudged so a mean like 18.825, stored a hair under, still rounds up.
+    let mean = format!("{:.2}", mean + mean.signum() * 1e-9);
+    mean.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Who won an opposed roll, given each side's name and total.
+fn format_outcome(a: (&str, i64), b: (&str, i64)) -> String {
+    match a.1.cmp(&b.1) {
+        std::cmp::Ordering::Greater => format!("{} wins by {}", a.0, a.1 - b.1),
+        std::cmp::Ordering::Less => format!("{} wins by {}", b.0, b.1 - a.1),
+        std::cmp::Ordering::Equal => format!("Tie at {}", b.1),
+    }
+}
+
+/// One line per expression, such as `1d20a: 17 (dropped 4)`, named by its
+/// label when it has one, and then `total:` and `successes:` lines. Meant for
+/// scripts, so nothing is ever wrapped or aligned.
+fn execute_plain_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<String> {
+    let mut lines = Vec::new();
+    let mut totals = Totals::default();
+    let mut side_a: Option<(String, i64)> = None;
+    let mut round: Option<(i64, Totals)> = None;
+    let round_line =
+        |(n, totals): (i64, Totals)| format!("round {}: {}", n, format_totals(&totals));
+    let mut summaries = Summaries::default();
+    let rolled = roll_arguments(dice_args, options, rng)?;
+    for (expression, res) in rolled.expressions.iter().zip(rolled.results) {
+        if let Some(n) = res.round {
+            if round.as_ref().is_some_and(|(current, _)| *current != n) {
+                lines.extend(round.take().map(round_line));
+            }
+            round
+                .get_or_insert_with(|| (n, Totals::default()))
+                .1
+                .add(&res);
+        }
+        let mut name = match &res.label {
+            Some(label) => label.clone(),
+            None => expression.to_string(),
+        };
+        match (res.repetition, res.attempt) {
+            (Some(n), Some(attempt)) => name = format!("{} {}.{}", name, n, attempt),
+            (Some(n), None) | (None, Some(n)) => name = format!("{} {}", name, n),
+            (None, None) => {}
+        }
+        let subtotal = res.total();
+        let raw = res.raw_total();
+        let mut value = if res.is_pool() {
+            format_successes(subtotal)
+        } else if !res.has_summable() {
+            // Lookups like d66 and text dice have no sum, so show the faces.
+            let faces: Vec<String> = res
+                .rolls
+                .iter()
+                .flat_map(|roll| {
+                    roll.dice
+                        .iter()
+                        .map(|die| format_die_value(die, &roll.request))
+                })
+                .collect();
+            faces.join(", ")
+        } else if raw != subtotal {
+            format!("{} → {}", raw, subtotal)
+        } else {
+            subtotal.to_string()
+        };
+        if res.discarded {
+            value = format!("({})", value);
+        }
+        if let Some(check) = &res.check {
+            value = format!("{} {}", value, format_check(check));
+        }
+        let dropped: Vec<String> = res
+            .rolls
+            .iter()
+            .flat_map(|roll| &roll.dice)
+            .flat_map(|die| {
+                let unkept = (!die.kept).then_some(die.value);
+                die.alternates.iter().copied().chain(unkept)
+            })
+            .map(|value| value.to_string())
+            .collect();
+        if !dropped.is_empty() {
+            value = format!("{} (dropped {})", value, dropped.join(", "));
+        }
+        if options.stats
+            && let Some(summary) = summaries.get(&res)
+        {
+            let cells = format_summary(Some(summary));
+            value = format!(
+                "{} (min {}, mean {}, max {})",
+                value, cells[0], cells[1], cells[2]
+            );
+        }
+        lines.push(format!("{}: {}", name, value));
+        if !res.discarded {
+            match res.side {
+                Some(Side::A) => side_a = Some((name, subtotal)),
+                Some(Side::B) => {
+                    if let Some((a_name, a_total)) = side_a.take() {
+                        let outcome = format_outcome((&a_name, a_total), (&name, subtotal));
+                        lines.push(format!("outcome: {}", outcome));
+                    }
+                }
+                None => {}
+            }
+        }
+        totals.add(&res);
+    }
+    lines.extend(round.take().map(round_line));
+    if !options.no_total {
+        if let Some(total) = totals.total {
+            lines.push(format!("total: {}", total));
+        }
+        if let Some(successes) = totals.successes {
+            lines.push(format!("successes: {}", successes));
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Just the grand total, or with `separate` each expression's total in
+/// order, for `DMG=$(roll -s 8d6)`. Under --repeat, `separate` gives each
+/// round's total instead. A pool's total is its success count.
+fn execute_sum_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+    separate: bool,
+) -> Result<String> {
+    let mut lines = Vec::new();
+    let mut totals = Totals::default();
+    let mut round_totals: Vec<Totals> = Vec::new();
+    roll_each(dice_args, options, rng, |input, _, res| {
+        if res.discarded {
+            return Ok(());
+        }
+        if !res.is_pool() && !res.has_summable() {
+            bail!("Error: '{}' has nothing to sum.", input);
+        }
+        if let Some(n) = res.round {
+            if round_totals.len() < n as usize {
+                round_totals.push(Totals::default());
+            }
+            round_totals.last_mut().expect("just pushed").add(&res);
+        } else if separate {
+            lines.push(if res.is_pool() {
+                res.total()
+            } else {
+                res.summable_total()
+            });
+        }
+        totals.add(&res);
+        Ok(())
+    })?;
+    if separate {
+        let lines = if round_totals.is_empty() {
+            lines.iter().map(i64::to_string).collect()
+        } else {
+            round_totals
+                .into_iter()
+                .map(|totals| grand_total(totals).map(|total| total.to_string()))
+                .collect::<Result<Vec<_>>>()?
+        };
+        return Ok(lines.join("\n"));
+    }
+    grand_total(totals).map(|total| total.to_string())
+}
+
+/// The one number --sum prints for `totals`, and that --dc checks.
+fn grand_total(totals: Totals) -> Result<i64> {
+    match totals {
+        Totals {
+            total: Some(_),
+            successes: Some(_),
+        } => bail!("Error: Successes can't be added to a sum; use --separate for one total each."),
+        Totals {
+            total: Some(total), ..
+        }
+        | Totals {
+            successes: Some(total),
+            ..
+        } => Ok(total),
+        // Only opposed rolls, which are never added together.
+        Totals { .. } => bail!("Error: Opposed rolls have no grand total; use --separate."),
+    }
+}
+
+/// The rolls as a JSON document for scripts. Field names are part of the
+/// interface, so only ever add to them. Under --repeat, the expressions are
+/// grouped into `rounds`, each with its own totals. `seed` is echoed back so
+/// a result can be rolled again, and is null for an unseeded run.
+fn format_json(rolled: &Rolled, options: &Options) -> String {
+    let results = &rolled.results;
+    let mut summaries = Summaries::default();
+    let expressions: Vec<serde_json::Value> = results
+        .iter()
+        .zip(&rolled.inputs)
+        .zip(&rolled.expressions)
+        .map(|((res, input), expression)| {
+            let terms: Vec<serde_json::Value> = res.rolls.iter().map(term_json).collect();
+            let mut value = json!({
+                "input": input,
+                "expression": expression,
+                "label": res.label,
+                "repetition": res.repetition,
+                "attempt": res.attempt,
+                "discarded": res.discarded,
+                "side": res.side.map(|side| format!("{:?}", side)),
+                "terms": terms,
+                "pool": res.is_pool(),
+                "subtotal": res.total(),
+                "check": res.check.as_ref().map(|check| json!({
+                    "target": check.target.to_string(),
+                    "passed": check.passed,
+                    "margin": check.margin,
+                })),
+            });
+            if options.stats {
+                value["stats"] = summaries.get(res).map_or(json!(null), |summary| {
+                    json!({
+                        "min": summary.min,
+                        // Rounded as the other formats show it.
+                        "mean": format_mean(summary.mean).parse::<f64>().ok(),
+                        "max": summary.max,
+                        "exact": summary.exact,
+                    })
+                });
+            }
+            value
+        })
+        .collect();
+    // The same totals as the table's, left null when there is nothing to add.
+    let mut totals = Totals::default();
+    let mut rounds: Vec<(Totals, Vec<serde_json::Value>)> = Vec::new();
+    for (res, expression) in results.iter().zip(expressions.iter().cloned()) {
+        totals.add(res);
+        if let Some(n) = res.round {
+            if rounds.len() < n as usize {
+                rounds.push((Totals::default(), Vec::new()));
+            }
+            let (round_totals, round_expressions) = rounds.last_mut().expect("just pushed");
+            round_totals.add(res);
+            round_expressions.push(expression);
+        }
+    }
+    let mut document = if rounds.is_empty() {
+        json!({
+            "expressions": expressions,
+            "total": totals.total,
+            "successes": totals.successes,
+        })
+    } else {
+        let rounds: Vec<serde_json::Value> = rounds
+            .into_iter()
+            .enumerate()
+            .map(|(i, (round_totals, round_expressions))| {
+                json!({
+                    "round": i + 1,
+                    "expressions": round_expressions,
+                    "total": round_totals.total,
+                    "successes": round_totals.successes,
+                })
+            })
+            .collect();
+        json!({
+            "rounds": rounds,
+            "total": totals.total,
+            "successes": totals.successes,
+        })
+    };
+    if options.no_total
+        && let Some(document) = document.as_object_mut()
+    {
+        document.remove("total");
+        document.remove("successes");
+    }
+    if let Some(document) = document.as_object_mut() {
+        document.insert("seed".to_string(), json!(options.seed));
+    }
+    serde_json::to_string_pretty(&document).expect("a JSON value always serializes")
+}
+
+fn mode_name(mode: RollMode) -> &'static str {
+    match mode {
+        RollMode::Normal => "normal",
+        RollMode::Advantage(_) => "advantage",
+        RollMode::Disadvantage(_) => "disadvantage",
+    }
+}
+
+/// Writes one JSON object per die, flushed as each expression is rolled, and
+/// then a line with the totals. A term's dice are rolled together, since keep
+/// rules and sorting need all of them, so the dice limit holds here too.
+fn execute_jsonl_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut totals = Totals::default();
+    let mut write_die = |input: &str,
+                         expression: &str,
+                         res: &ExpressionResult,
+                         (term, index): (usize, usize),
+                         request: &DiceRequest,
+                         die: &DieRoll|
+     -> Result<()> {
+        let mut dropped = die.alternates.clone();
+        if !die.kept {
+            dropped.push(die.value);
+        }
+        let line = json!({
+            "input": input,
+            "expression": expression,
+            "label": res.label,
+            "repetition": res.repetition,
+            "attempt": res.attempt,
+            "discarded": res.discarded,
+            "round": res.round,
+            "term": term,
+            "index": index,
+            "sides": request.sides,
+            "mode": mode_name(request.mode),
+            "kept": die.kept.then_some(die.value),
+            "dropped": dropped,
+        });
+        writeln!(out, "{}", line)?;
+        out.flush()?;
+        Ok(())
+    };
+    stream_each(dice_args, options, rng, &mut write_die, |_, _, res| {
+        totals.add(&res);
+        Ok(())
+    })?;
+    if !options.no_total {
+        let summary = json!({ "total": totals.total, "successes": totals.successes });
+        writeln!(out, "{}", summary)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// One row per die. Unless --no-total, the totals follow as comment rows,
+/// which spreadsheets can be told to skip.
+fn execute_csv_with(
+    dice_args: &(impl Arguments + ?Sized),
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<String> {
+    let mut header = "expression,die,mode,kept,dropped,subtotal,label".to_string();
+    if options.stats {
+        header.push_str(",min,mean,max,exact");
+    }
+    let mut lines = vec![header];
+    let mut totals = Totals::default();
+    let mut summaries = Summaries::default();
+    roll_each(dice_args, options, rng, |_, expression, res| {
+        // Unbounded ends and dice with no numeric value leave the cells empty.
+        let summary = match summaries.get(&res).filter(|_| options.stats) {
+            Some(summary) => vec![
+                summary.min.map(|min| min.to_string()).unwrap_or_default(),
+                format_mean(summary.mean),
+                summary.max.map(|max| max.to_string()).unwrap_or_default(),
+                summary.exact.to_string(),
+            ],
+            None if options.stats => vec![String::new(); 4],
+            None => Vec::new(),
+        };
+        // The label has a column of its own.
+        let unlabelled = match &res.label {
+            Some(label) => expression
+                .strip_suffix(&format_label(label))
+                .unwrap_or(expression),
+            None => expression,
+        };
+        // Lookup dice like d66 have no subtotal to speak of.
+        let subtotal = if res.is_pool() || res.has_summable() {
+            res.total().to_string()
+        } else {
+            String::new()
+        };
+        for roll in &res.rolls {
+            for die in &roll.dice {
+                let shown = |value: i64| match &die.text {
+                    Some(text) => text.clone(),
+                    None => value.to_string(),
+                };
+                let mut dropped: Vec<String> = die.alternates.iter().map(i64::to_string).collect();
+                if !die.kept {
+                    dropped.push(shown(die.value));
+                }
+                let kept = if die.kept {
+                    shown(die.value)
+                } else {
+                    String::new()
+                };
+                let fields = [
+                    unlabelled.to_string(),
+                    die_label(&roll.request),
+                    mode_name(roll.request.mode).to_string(),
+                    kept,
+                    dropped.join(" "),
+                    subtotal.clone(),
+                    res.label.clone().unwrap_or_default(),
+                ];
+                let fields: Vec<String> = fields
+                    .iter()
+                    .chain(&summary)
+                    .map(|field| csv_field(field))
+                    .collect();
+                lines.push(fields.join(","));
+            }
+        }
+        totals.add(&res);
+        Ok(())
+    })?;
+    if !options.no_total {
+        if let Some(total) = totals.total {
+            lines.push(format!("# total,{}", total));
+        }
+        if let Some(successes) = totals.successes {
+            lines.push(format!("# successes,{}", successes));
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Quotes a CSV field if it holds a comma, quote or line break, doubling any
+/// quotes inside.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn term_json(res: &RollResult) -> serde_json::Value {
+    let mode = mode_name(res.request.mode);
+    let dice: Vec<serde_json::Value> = res
+        .dice
+        .iter()
+        .map(|die| {
+            let mut value = json!({
+                "value": die.value,
+                "rolls": die.rolls,
+                "rerolled": die.rerolled,
+                "alternates": die.alternates,
+                "text": die.text,
+                "kept": die.kept,
+                "bonus": die.bonus,
+                "throws": die.throws.iter().map(|&(face, throw)| json!({
+                    "face": face,
+                    "result": throw.name(),
+                })).collect::<Vec<_>>(),
+            });
+            // A pool's dice also carry what each one scored.
+            if res.request.success.is_some() {
+                value["successes"] = json!(die.net_successes(&res.request));
+            }
+            value
+        })
+        .collect();
+    let mut term = json!({
+        "die": die_label(&res.request),
+        "count": res.dice.iter().filter(|die| !die.bonus).count(),
+        "sides": res.request.sides,
+        "mode": mode,
+        "dice": dice,
+    });
+    if let Some(target) = res.request.success {
+        term["target"] = json!(target.to_string());
+    }
+    term
+}
+
+/// Lists every face thrown for a die, in order: `6!` set off another roll,
+/// `1r` was rerolled, and a face in parentheses didn't count.
+fn format_throws(die: &DieRoll) -> String {
+    let throws: Vec<String> = die
+        .throws
+        .iter()
+        .map(|&(face, throw)| match throw {
+            Throw::Rerolled => format!("{}r", face),
+            Throw::Dropped => format!("({})", face),
+            Throw::Exploded if die.kept => format!("{}!", face),
+            Throw::Exploded => format!("({}!)", face),
+            Throw::Counted if die.kept => face.to_string(),
+            Throw::Counted => format!("({})", face),
+        })
+        .collect();
+    throws.join(" ")
+}
+
+/// Shows an exploded die as its chain of rolls: `6+6+3 = 15` for plain
+/// exploding, or `15 [6→6→3]` when the rolls compound into one die. A single
+/// Fate die is shown as its symbol, and a digit die as its component digits.
+fn format_die_value(die: &DieRoll, req: &DiceRequest) -> String {
+    match die.unclamped {
+        Some(raw) => format!("{} → {}", format_raw_value(die, req, raw), die.value),
+        None => format_raw_value(die, req, die.value),
+    }
+}
+
+/// The pips of every face a d6 counted, e.g. `⚅⚂` for an exploded 6 and 3.
+/// These glyphs are one column wide in Unicode, not of ambiguous width, so
+/// the table lines up even where CJK terminals draw those double.
+fn format_pips(die: &DieRoll) -> String {
+    die.throws
+        .iter()
+        .filter(|(_, throw)| matches!(throw, Throw::Counted | Throw::Exploded))
+        .filter_map(|&(face, _)| pip(face))
+        .collect()
+}
+
+/// The d6 glyph for `face`, from ⚀ to ⚅.
+fn pip(face: i64) -> Option<char> {
+    let offset = u32::try_from(face - 1).ok().filter(|&offset| offset < 6)?;
+    char::from_u32(0x2680 + offset)
+}
+
+/// Shows `value`, the die's result before any clamping.
+fn format_raw_value(die: &DieRoll, req: &DiceRequest, value: i64) -> String {
+    if let Some(text) = &die.text {
+        return text.clone();
+    }
+    if die.rolls.len() <= 1 {
+        return match (&req.faces, value) {
+            (Faces::Fate, 1) => "+".to_string(),
+            (Faces::Fate, -1) => "-".to_string(),
+            (Faces::Digits { .. }, value) => {
+                let digits: Vec<String> = value.to_string().chars().map(String::from).collect();
+                format!("{} → {}", digits.join(", "), value)
+            }
+            // The tens and units dice, shown as they would lie on the table.
+            (Faces::Percentile, value) => {
+                format!("{:02} + {} = {}", value % 100 / 10 * 10, value % 10, value)
+            }
+            _ => value.to_string(),
+        };
+    }
+    let chain: Vec<String> = die.rolls.iter().map(i64::to_string).collect();
+    match req.explode {
+        Some(Explode::Compound) => format!("{} [{}]", value, chain.join("→")),
+        _ => format!("{} = {}", chain.join("+"), value),
+    }
+}
+
+/// Flags a kept die whose natural roll is a critical success or fumble. Dice discarded by
+/// advantage or a keep rule are never flagged.
+fn crit_tag(die: &DieRoll, req: &DiceRequest) -> Option<&'static str> {
+    let natural = die.unclamped.unwrap_or(die.value);
+    let hits =
+        |condition: Option<Comparison>| die.kept && condition.is_some_and(|c| c.matches(natural));
+    if hits(req.crit_success) {
+        Some("CRIT")
+    } else if hits(req.crit_failure) {
+        Some("FUMBLE")
+    } else {
+        None
+    }
+}
+
+/// Describes discarded faces, e.g. `rerolled 1` or `rerolled 3×: 1, 1, 2`.
+fn format_rerolls(rerolled: &[i64]) -> String {
+    let faces: Vec<String> = rerolled.iter().map(i64::to_string).collect();
+    if rerolled.len() == 1 {
+        format!("rerolled {}", faces[0])
+    } else {
+        format!("rerolled {}×: {}", rerolled.len(), faces.join(", "))
+    }
+}
+
+/// Describes matching sets, e.g. `three 4s, two 1s`.
+fn format_sets(sets: &[(usize, i64)]) -> String {
+    const NUMBERS: [&str; 9] = [
+        "two", "three", "four", "five", "six", "seven", "eight", "nine", "ten",
+    ];
+    if sets.is_empty() {
+        return "no matches".to_string();
+    }
+    let sets: Vec<String> = sets
+        .iter()
+        .map(|&(n, face)| match NUMBERS.get(n.wrapping_sub(2)) {
+            Some(word) => format!("{} {}s", word, face),
+            None => format!("{} {}s", n, face),
+        })
+        .collect();
+    sets.join(", ")
+}
+
+fn format_duplicates(duplicates: &[i64]) -> String {
+    let faces: Vec<String> = duplicates.iter().map(i64::to_string).collect();
+    format!("duplicate {}", faces.join(", "))
+}
+
+/// Rerolls and failures usually target one face, so `r1` reads better than
+/// `r=1`.
+fn format_reroll_condition(condition: Comparison) -> String {
+    match condition.op {
+        CompareOp::Eq => condition.value.to_string(),
+        _ => condition.to_string(),
+    }
+}
+
+fn die_label(req: &DiceRequest) -> String {
+    let mode_str = match req.mode {
+        RollMode::Normal => String::new(),
+        RollMode::Advantage(2) => "a".to_string(),
+        RollMode::Advantage(n) => format!("a{}", n),
+        // The short forms are only read as disadvantage on a single die, and a
+        // bare `d` can't be followed by `lucky`.
+        RollMode::Disadvantage(2) if req.lucky || !is_single_die(req) => "dis".to_string(),
+        RollMode::Disadvantage(2) => "d".to_string(),
+        RollMode::Disadvantage(n) if !is_single_die(req) => format!("dis{}", n),
+        RollMode::Disadvantage(n) => format!("d{}", n),
+    };
+    let keep_str = match req.keep {
+        Some(Keep::Highest(n)) => format!("kh{}", n),
+        Some(Keep::Lowest(n)) => format!("kl{}", n),
+        Some(Keep::Middle(n)) => format!("km{}", n),
+        Some(Keep::DropLowest(n)) => format!("dl{}", n),
+        Some(Keep::DropHighest(n)) => format!("dh{}", n),
+        None => String::new(),
+    };
+    let explode_str = match req.explode {
+        Some(Explode::Standard) => "!",
+        Some(Explode::Compound) => "!!",
+        Some(Explode::Penetrating) => "!p",
+        None => "",
+    };
+    let explode_str = match req.explode_cap {
+        Some(cap) => format!("{}{}", explode_str, cap),
+        None => explode_str.to_string(),
+    };
+    let explode_str = match req.explode_on {
+        Some(condition) => format!("{}{}", explode_str, condition),
+        None => explode_str,
+    };
+    let reroll_str = match req.reroll {
+        Some(Reroll::Once(c)) => format!("ro{}", format_reroll_condition(c)),
+        Some(Reroll::Recursive(c)) => format!("r{}", format_reroll_condition(c)),
+        Some(Reroll::Add(c)) => format!("ra{}", format_reroll_condition(c)),
+        None => String::new(),
+    };
+    let faces_str = match &req.faces {
+        Faces::Standard | Faces::Digits { .. } => req.sides.to_string(),
+        Faces::ZeroBased => format!("{}z", req.sides),
+        Faces::Percentile => "100c".to_string(),
+        Faces::Fate => "F".to_string(),
+        Faces::Custom(faces) => {
+            let faces: Vec<String> = faces.iter().map(i64::to_string).collect();
+            format!("{{{}}}", faces.join(","))
+        }
+        Faces::Text(faces) => {
+            let faces: Vec<String> = faces
+                .iter()
+                .map(|face| {
+                    if face.contains([',', '}']) {
+                        format!("\"{}\"", face)
+                    } else {
+                        face.clone()
+                    }
+                })
+                .collect();
+            format!("{{{}}}", faces.join(","))
+        }
+    };
+    let success_str = match req.success {
+        // A bare comparison on a single die would read back as a check.
+        Some(target) if is_single_die(req) => format_pool_target(target),
+        Some(target) => target.to_string(),
+        None => String::new(),
+    };
+    let failure_str = match req.failure {
+        Some(c) => format!("f{}", format_reroll_condition(c)),
+        None => String::new(),
+    };
+    let minimum_str = match req.minimum {
+        Some(n) => format!("min{}", n),
+        None => String::new(),
+    };
+    let maximum_str = match req.maximum {
+        Some(n) => format!("max{}", n),
+        None => String::new(),
+    };
+    let sort_str = match req.sort {
+        Some(Sort::Ascending) => "s",
+        Some(Sort::Descending) => "sd",
+        None => "",
+    };
+    let each_str = match req.each {
+        Some(n) if n < 0 => format!("e{}", n),
+        Some(n) => format!("e+{}", n),
+        None => String::new(),
+    };
+    let wild_str = match req.wild {
+        Some(6) => "w".to_string(),
+        Some(n) => format!("w{}", n),
+        None => String::new(),
+    };
+    let matching_str = match req.matching {
+        Some(Some(n)) => format!("m{}", n),
+        Some(None) => "m".to_string(),
+        None => String::new(),
+    };
+    let lucky_str = if req.lucky { "lucky" } else { "" };
+    let brutal_str = if req.brutal { "b" } else { "" };
+    let unique_str = if req.unique { "u" } else { "" };
+    let mut crit_str = String::new();
+    if let Some(c) = req.crit_success {
+        crit_str += &format!("cs{}", format_reroll_condition(c));
+    }
+    if let Some(c) = req.crit_failure {
+        crit_str += &format!("cf{}", format_reroll_condition(c));
+    }
+    format!(
+        "d{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}",
+        faces_str,
+        explode_str,
+        mode_str,
+        lucky_str,
+        wild_str,
+        keep_str,
+        reroll_str,
+        minimum_str,
+        maximum_str,
+        each_str,
+        success_str,
+        failure_str,
+        crit_str,
+        matching_str,
+        brutal_str,
+        unique_str,
+        sort_str
+    )
+}
+
+/// A success target spelled with `t`, which never reads as a check: `t5`
+/// for `>=5`, or `t<=2`.
+fn format_pool_target(target: Comparison) -> String {
+    match target.op {
+        CompareOp::Ge => format!("t{}", target.value),
+        _ => format!("t{}", target),
+    }
+}
+
+fn is_single_die(req: &DiceRequest) -> bool {
+    req.count == 1 && req.count_roll.is_none()
+}
+
+/// The canonical spelling: an explicit count and the suffixes in the order
+/// they are parsed, e.g. `4d6!kh3`.
+impl fmt::Display for DiceRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.count_roll {
+            Some(count) => write!(f, "({}){}", count, die_label(self)),
+            None => write!(f, "{}{}", self.count, die_label(self)),
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Dice(request) => write!(f, "{}", request),
+            Node::Constant(n) => write!(f, "{}", n),
+            // `BinaryOp`'s own Display is for the Formula row, where `×` reads
+            // better than the `*` the parser expects.
+            Node::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    BinaryOp::Mul => "*".to_string(),
+                    op => op.to_string(),
+                };
+                write!(f, "{}{}{}", lhs, op, rhs)
+            }
+            Node::Group(inner) => write!(f, "({})", inner),
+        }
+    }
+}
+
+/// Everything but the side of an opposed roll, which belongs to the
+/// argument, and comments, which are dropped.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.repeat > 1 {
+            write!(f, "{}x", self.repeat)?;
+        }
+        // A target ending a longer expression would read back as its check,
+        // so it is spelled with `t` there.
+        let mut root = self.root.to_string();
+        if !matches!(self.root, Node::Dice(_))
+            && let Some(target) = self.root.dice().last().and_then(|req| req.success)
+            && let Some(rest) = root.strip_suffix(&target.to_string())
+        {
+            root = format!("{}{}", rest, format_pool_target(target));
+        }
+        match self.pick {
+            Some(pick) => write!(f, "{}({})", pick, root)?,
+            None => write!(f, "{}", root)?,
+        }
+        if let Some(floor) = self.floor {
+            write!(f, "~{}", floor)?;
+        }
+        if let Some(check) = self.check {
+            write!(f, "{}", check)?;
+        }
+        if let Some(label) = &self.label {
+            write!(f, "{}", format_label(label))?;
+        }
+        Ok(())
+    }
+}
+
+/// A label as it is written after its expression: `:attack`, or quoted when
+/// it holds anything but letters, digits, `-` and `_`.
+fn format_label(label: &str) -> String {
+    if label.chars().all(is_label_char) {
+        format!(":{}", label)
+    } else {
+        format!(":\"{}\"", label.replace('"', "\"\""))
+    }
+}
+
+/// Reads the --chance or --target threshold: `15` means `>=15`, and any
+/// comparison such as `<=5` or `=7` is taken as written.
+fn parse_threshold(s: &str) -> Result<Comparison, String> {
+    let target = map(
+        pair(opt(parse_compare_op), parse_signed_i64),
+        |(op, value)| Comparison {
+            op: op.unwrap_or(CompareOp::Ge),
+            value,
+        },
+    )(s.trim());
+    match target {
+        Ok(("", target)) => Ok(target),
+        _ => Err(format!(
+            "expected a number or a comparison like '>=15', got '{}'",
+            s
+        )),
+    }
+}
+
+/// Lists each argument next to its canonical form, without rolling.
+fn normalize(dice: &[String], options: &Options) -> Result<String> {
+    let mut table = options.style.table();
+    table.set_header(vec!["Input", "Canonical"]);
+    for (index, arg) in dice.iter().enumerate() {
+        let mut canonical = String::new();
+        for (i, expression) in parse_pasted(index, arg)?.iter().enumerate() {
+            if expression.side == Some(Side::B) {
+                canonical += " vs ";
+            } else if i > 0 {
+                canonical += ",";
+            }
+            canonical += &expression.to_string();
+        }
+        table.add_row(vec![arg.clone(), canonical]);
+    }
+    Ok(table.to_string())
+}
+
+/// Lists each expression's exact average without rolling: to two places,
+/// with the fraction when that rounds it, and rounded down, as a stat block
+/// gives average damage (`2d6+3` is 10). Repeats and checks don't change
+/// the average of one roll, so they are left to the expression's text.
+fn expected(dice: &[String], options: &Options) -> Result<String> {
+    let mut table = options.style.table();
+    table.set_header(vec!["Expression", "Expected", "Average"]);
+    for (index, arg) in dice.iter().enumerate() {
+        for expression in parse_pasted(index, arg)? {
+            let Some(mean) = stats::expected(&expression) else {
+                bail!(
+                    "Error: The average of '{}' can't be worked out without rolling.",
+                    expression
+                );
+            };
+            let mut exact = format_mean(mean);
+            if let Some((numerator, denominator)) = stats::as_fraction(mean)
+                && exact.parse::<f64>() != Ok(numerator as f64 / denominator as f64)
+            {
+                exact = format!("{} ({}/{})", exact, numerator, denominator);
+            }
+            // Nudged for the same reason as `format_mean`.
+            let average = (mean + 1e-9).floor() as i64;
+            table.add_row(vec![expression.to_string(), exact, average.to_string()]);
+        }
+    }
+    Ok(table.to_string())
+}
+
+/// Most bars in a histogram; a wider range of totals is grouped.
+const MAX_BARS: i64 = 50;
+
+/// Rolls each expression `samples` times and charts how often each total
+/// came up, fitting the chart to `width` columns.
+fn histogram(dice: &[String], samples: u64, width: usize, rng: &mut impl DieRng) -> Result<String> {
+    let mut charts = Vec::new();
+    for (index, arg) in dice.iter().enumerate() {
+        for expression in parse_pasted(index, arg)? {
+            check_held(&expression)?;
+            let counts = simulate::counts(&expression, samples, rng);
+            charts.push(format_histogram(
+                &expression.to_string(),
+                &counts,
+                samples,
+                width,
+            ));
+        }
+    }
+    Ok(charts.join("\n\n"))
+}
+
+/// One bar per total from the lowest rolled to the highest, or per range of
+/// totals such as `10..19` past `MAX_BARS`, each with its count and share.
+/// The most common bar fills whatever `width` leaves after the numbers.
+fn format_histogram(name: &str, counts: &BTreeMap<i64, u64>, samples: u64, width: usize) -> String {
+    let low = counts.keys().next().copied().unwrap_or(0);
+    let high = counts.keys().next_back().copied().unwrap_or(0);
+    let size = (high - low + MAX_BARS) / MAX_BARS;
+    let bars: Vec<(String, u64)> = iter::successors(Some(low), |start| Some(start + size))
+        .take_while(|&start| start <= high)
+        .map(|start| {
+            let end = (start + size - 1).min(high);
+            let count = counts.range(start..=end).map(|(_, count)| count).sum();
+            let label = if size == 1 {
+                start.to_string()
+            } else {
+                format!("{}..{}", start, end)
+            };
+            (label, count)
+        })
+        .collect();
+    let label_width = bars.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let most = bars
+        .iter()
+        .map(|&(_, count)| count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let count_width = most.to_string().len();
+    // The label, " | ", the bar, a space, the count, and " 100.00%".
+    let bar_width = width
+        .saturating_sub(label_width + 3 + 1 + count_width + 8)
+        .max(10);
+    let mut lines = vec![format!("{}: {} rolls", name, samples)];
+    for (label, count) in bars {
+        let length = (count as f64 / most as f64 * bar_width as f64).round() as usize;
+        lines.push(format!(
+            "{:>label_width$} | {:<bar_width$} {:>count_width$} {:>6.2}%",
+            label,
+            "#".repeat(length),
+            count,
+            count as f64 * 100.0 / samples as f64,
+        ));
+    }
+    lines.join("\n")
+}
+
+/// The percentiles `--simulate` lists besides the median.
+const PERCENTILES: [f64; 4] = [5.0, 25.0, 75.0, 95.0];
+
+/// Rolls each expression `samples` times and tabulates the spread of its
+/// totals, then how long that took.
+fn simulation(
+    dice: &[String],
+    samples: u64,
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<String> {
+    let start = Instant::now();
+    let mut table = options.style.table();
+    let mut header = vec![
+        "Expression".to_string(),
+        "Min".to_string(),
+        "Max".to_string(),
+        "Mean".to_string(),
+        "Std dev".to_string(),
+        "Median".to_string(),
+    ];
+    header.extend(PERCENTILES.iter().map(|p| format!("{}th", p)));
+    table.set_header(header);
+    for (index, arg) in dice.iter().enumerate() {
+        for expression in parse_pasted(index, arg)? {
+            check_held(&expression)?;
+            let totals = simulate::accumulate(&expression, samples, rng);
+            let mut row = vec![
+                expression.to_string(),
+                totals.min.to_string(),
+                totals.max.to_string(),
+                format_mean(totals.mean()),
+                format_mean(totals.std_dev()),
+                totals.percentile(50.0).to_string(),
+            ];
+            row.extend(
+                PERCENTILES
+                    .iter()
+                    .map(|&p| totals.percentile(p).to_string()),
+            );
+            table.add_row(row);
+        }
+    }
+    Ok(format!(
+        "{}\n{} rolls of each expression in {:.2?}",
+        table,
+        samples,
+        start.elapsed()
+    ))
+}
+
+/// Most dice --chance throws for one estimate, so a huge expression is
+/// rolled fewer than `samples` times rather than for minutes.
+const MAX_ESTIMATE_DICE: u64 = 2_000_000;
+
+/// How many of `samples` rolls of `expression` fit in `MAX_ESTIMATE_DICE`.
+fn estimate_samples(expression: &Expression, samples: u64) -> u64 {
+    let dice_per_roll: u64 = expression
+        .root
+        .dice()
+        .iter()
+        .map(|req| match &req.count_roll {
+            Some(count) => count.bound().unwrap_or(MAX_DICE),
+            None => req.count,
+        })
+        .map(|count| count.max(1) as u64)
+        .sum::<u64>()
+        * expression.pick.map_or(1, |pick| pick.attempts() as u64);
+    samples.min((MAX_ESTIMATE_DICE / dice_per_roll).max(1))
+}
+
+/// The chance of each expression's total meeting `target`, worked out from
+/// its distribution where possible. Otherwise it is estimated from `samples`
+/// rolls, or fewer past `MAX_ESTIMATE_DICE`, with the margin of error at 95%
+/// confidence.
+fn chance(
+    dice: &[String],
+    target: Comparison,
+    samples: u64,
+    rng: &mut impl DieRng,
+) -> Result<String> {
+    let mut lines = Vec::new();
+    for (index, arg) in dice.iter().enumerate() {
+        for expression in parse_pasted(index, arg)? {
+            check_held(&expression)?;
+            // Named for one roll, so a repeat, label or check of its own is left off.
+            let single = Expression {
+                repeat: 1,
+                label: None,
+                check: None,
+                ..expression.clone()
+            };
+            let name = format!("{}{}", single, target);
+            let line = match stats::chance(&expression, target) {
+                Some(p) => format!("{}: {} ({})", name, format_percent(p), format_odds(p)),
+                None => {
+                    let samples = estimate_samples(&expression, samples);
+                    let hits: u64 = simulate::counts(&expression, samples, rng)
+                        .into_iter()
+                        .filter(|&(total, _)| target.matches(total))
+                        .map(|(_, count)| count)
+                        .sum();
+                    let p = hits as f64 / samples as f64;
+                    let margin = 1.96 * (p * (1.0 - p) / samples as f64).sqrt();
+                    format!(
+                        "{}: ~{} ({}), estimated from {} rolls, ±{} at 95% confidence",
+                        name,
+                        format_percent(p),
+                        format_odds(p),
+                        samples,
+                        format_percent(margin)
+                    )
+                }
+            };
+            lines.push(line);
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+/// A chance as a percentage to at most two places, never rounding a
+/// possible outcome to 0% or 100%.
+fn format_percent(p: f64) -> String {
+    if p > 0.0 && p < 0.0001 {
+        "<0.01%".to_string()
+    } else if p < 1.0 && p > 0.9999 {
+        ">99.99%".to_string()
+    } else {
+        format!("{}%", format_mean(p * 100.0))
+    }
+}
+
+/// A chance as odds to say at the table: `7 in 10`, or `1 in 400` for a long
+/// shot. A chance no fraction up to twentieths matches is `about` the
+/// nearest one.
+fn format_odds(p: f64) -> String {
+    let exact = |n: f64, d: f64| (n / d - p).abs() < 1e-9;
+    let (n, d) = if p <= 0.0 {
+        return "never".to_string();
+    } else if p >= 1.0 {
+        return "always".to_string();
+    } else if p < 0.05 {
+        (1.0, (1.0 / p).round())
+    } else if p > 0.95 {
+        let d = (1.0 / (1.0 - p)).round();
+        (d - 1.0, d)
+    } else {
+        (1..=20)
+            .map(|d| ((p * d as f64).round(), d as f64))
+            .min_by(|a, b| (a.0 / a.1 - p).abs().total_cmp(&(b.0 / b.1 - p).abs()))
+            .unwrap_or((0.0, 1.0))
+    };
+    let about = if exact(n, d) { "" } else { "about " };
+    format!("{}{} in {}", about, n, d)
+}
+
+/// The terminal's width in columns, or 80 when stdout isn't a terminal.
+fn terminal_width() -> usize {
+    Table::new().width().map_or(80, usize::from)
+}
+
+/// Net successes for a pool; a negative count is a botch.
+fn format_successes(count: i64) -> String {
+    match count {
+        1 => "1 success".to_string(),
+        n if n < 0 => format!("{} successes (botch)", n),
+        n => format!("{} successes", n),
+    }
+}
+
+/// Describes a check, e.g. `PASS (+3)` or `FAIL (-2), NAT 20`.
+fn format_check(check: &Check) -> String {
+    let outcome = if check.passed { "PASS" } else { "FAIL" };
+    let outcome = format!("{} ({})", outcome, format_modifier(check.margin));
+    match check.natural {
+        Some(face) => format!("{}, NAT {}", outcome, face),
+        None => outcome,
+    }
+}
+
+fn format_modifier(modifier: i64) -> String {
+    if modifier > 0 {
+        format!("+{}", modifier)
+    } else {
+        modifier.to_string()
+    }
+}
+
+/// The exit status when a roll falls short of --dc. 1 is left for errors
+/// and 2 for clap's usage errors.
+const DC_FAILED: i32 = 10;
+
+/// Passes every roll through to `rng`, keeping the faces so that they can
+/// be rolled again with `Replay`.
+struct Recorder<'a, R> {
+    rng: &'a mut R,
+    faces: Vec<i64>,
+}
+
+impl<R: DieRng> DieRng for Recorder<'_, R> {
+    fn roll(&mut self, low: i64, high: i64) -> i64 {
+        let face = self.rng.roll(low, high);
+        self.faces.push(face);
+        face
+    }
+}
+
+/// Gives back the faces a `Recorder` kept, in order.
+struct Replay(std::vec::IntoIter<i64>);
+
+impl DieRng for Replay {
+    fn roll(&mut self, low: i64, _high: i64) -> i64 {
+        self.0.next().unwrap_or(low)
+    }
+}
+
+/// Whether `rolled` meets `dc`: its grand total, or with `all` the total of
+/// every expression. Opposed rolls are left out, as from the grand total.
+fn meets_dc(rolled: &Rolled, dc: i64, all: bool) -> Result<bool> {
+    let mut totals = Totals::default();
+    for (input, res) in rolled.inputs.iter().zip(&rolled.results) {
+        if res.discarded || res.side.is_some() {
+            continue;
+        }
+        if !res.is_pool() && !res.has_summable() {
+            bail!("Error: '{}' has no total to check against --dc.", input);
+        }
+        let total = if res.is_pool() {
+            res.total()
+        } else {
+            res.summable_total()
+        };
+        if all && total < dc {
+            return Ok(false);
+        }
+        totals.add(res);
+    }
+    if all {
+        return Ok(true);
+    }
+    Ok(grand_total(totals)? >= dc)
+}
+
+/// Runs `run`, then with --dc checks the same rolls against it, giving the
+/// output to print and the exit status.
+fn run_checked(
+    args: &Args,
+    options: &Options,
+    dice: &[String],
+    rng: &mut impl DieRng,
+) -> Result<(Option<String>, i32)> {
+    let Some(dc) = args.dc else {
+        return run(args, options, dice, rng).map(|output| (output, 0));
+    };
+    let mut recorder = Recorder {
+        rng,
+        faces: Vec::new(),
+    };
+    let output = if args.quiet {
+        // Rolling is all that's needed, whatever the format.
+        roll_arguments(dice, options, &mut recorder)?;
+        None
+    } else {
+        run(args, options, dice, &mut recorder)?
+    };
+    let rolled = roll_arguments(dice, options, &mut Replay(recorder.faces.into_iter()))?;
+    let code = if meets_dc(&rolled, dc, args.all)? {
+        0
+    } else {
+        DC_FAILED
+    };
+    Ok((output, code))
+}
+
+/// Rolls `dice` in the format asked for. JSON lines are written as they are
+/// rolled rather than returned at the end.
+fn run(
+    args: &Args,
+    options: &Options,
+    dice: &(impl Arguments + ?Sized),
+    rng: &mut impl DieRng,
+) -> Result<Option<String>> {
+    if args.normalize {
+        normalize(dice.inputs(), options).map(Some)
+    } else if args.expected {
+        expected(dice.inputs(), options).map(Some)
+    } else if let Some(samples) = args.simulate {
+        simulation(dice.inputs(), samples, options, rng).map(Some)
+    } else if let Some(target) = args.chance {
+        chance(dice.inputs(), target, args.samples, rng).map(Some)
+    } else if args.histogram {
+        histogram(dice.inputs(), args.samples, terminal_width(), rng).map(Some)
+    } else if args.jsonl {
+        execute_jsonl_with(dice, options, rng, &mut io::stdout().lock()).map(|()| None)
+    } else if args.json {
+        execute_json_with(dice, options, rng).map(Some)
+    } else if args.csv {
+        execute_csv_with(dice, options, rng).map(Some)
+    } else if args.markdown {
+        execute_markdown_with(dice, options, rng).map(Some)
+    } else if args.sum {
+        execute_sum_with(dice, options, rng, args.separate).map(Some)
+    } else if args.plain || (!args.table && !io::stdout().is_terminal()) {
+        execute_plain_with(dice, options, rng).map(Some)
+    } else {
+        execute_roll_with(dice, options, rng).map(Some)
+    }
+}
+
+fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let config = if args.no_config {
+        Config::default()
+    } else {
+        match Config::load(args.config.as_deref()) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    };
+    config.defaults.apply(&mut args, &matches);
+    CONFIG_STEP_CHAIN.set(config.step_chain.clone());
+    if args.list_macros {
+        println!("{}", config.list_macros(args.style.table()));
+        return;
+    }
+    let variables = args.variables();
+    let dice = gather_expressions(&args, &variables, &config);
+    let no_color = std::env::var("NO_COLOR").ok();
+    let force = std::env::var("CLICOLOR_FORCE").ok();
+    let color = args.color.enabled(
+        no_color.as_deref(),
+        force.as_deref(),
+        io::stdout().is_terminal(),
+    );
+    let options = Options {
+        crit: config.defaults.crit,
+        ..args.options(color)
+    };
+    if args.interactive {
+        let session = match args.seed {
+            Some(seed) => repl::interact(&args, &config, &options, &mut SeededRng(seed)),
+            None => repl::interact(&args, &config, &options, &mut rand::thread_rng()),
+        };
+        if let Err(e) = session {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let output = dice.and_then(|dice| match args.seed {
+        Some(seed) => run_checked(&args, &options, &dice, &mut SeededRng(seed)),
+        None => run_checked(&args, &options, &dice, &mut rand::thread_rng()),
+    });
+    let code = report(output, args.json || args.jsonl, &mut io::stdout().lock());
+    if code != 0 {
+        process::exit(code);
+    }
+}
+
+/// Writes what `run_checked` gave back to `out`, or its error to stderr, and
+/// returns the exit code.
+fn report(output: Result<(Option<String>, i32)>, json: bool, out: &mut impl Write) -> i32 {
+    let code = output.and_then(|(output, code)| {
+        if let Some(output) = output {
+            writeln!(out, "{}", output)?;
+        }
+        Ok(code)
+    });
+    match code {
+        Ok(code) => code,
+        // The reader went away, as with `roll --jsonl 100d6 | head`.
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) =>
+        {
+            0
+        }
+        Err(e) if json => {
+            eprintln!("{}", json!({ "error": e.to_string() }));
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Returns a fixed sequence of faces, panicking if a roll is out of range
+    /// or the script runs out.
+    pub(crate) struct ScriptedRng(VecDeque<i64>);
+
+    impl ScriptedRng {
+        pub(crate) fn new(faces: &[i64]) -> Self {
+            ScriptedRng(faces.iter().copied().collect())
+        }
+    }
+
+    impl DieRng for ScriptedRng {
+        fn roll(&mut self, low: i64, high: i64) -> i64 {
+            let face = self.0.pop_front().expect("scripted rolls exhausted");
+            assert!(
+                (low..=high).contains(&face),
+                "{} not in {}..={}",
+                face,
+                low,
+                high
+            );
+            face
+        }
+    }
+
+    /// Rolls with real randomness, for tests that only look at the shape.
+    fn execute_roll(dice_args: &[String]) -> Result<String> {
+        execute_roll_with(dice_args, &Options::default(), &mut rand::thread_rng())
+    }
+
+    // --- Parser Unit Tests ---
+
+    #[test]
+    fn test_parse_dice_simple() {
+        let (_, res) = parse_dice_expression("1d20").unwrap();
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 1,
+                count_roll: None,
+                sides: 20,
+                faces: Faces::Standard,
+                mode: RollMode::Normal,
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: None,
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dice_advantage() {
+        let (_, res) = parse_dice_expression("1d20a").unwrap();
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 1,
+                count_roll: None,
+                sides: 20,
+                faces: Faces::Standard,
+                mode: RollMode::Advantage(2),
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: None,
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dice_disadvantage() {
+        let (_, res) = parse_dice_expression("1d20d").unwrap();
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 1,
+                count_roll: None,
+                sides: 20,
+                faces: Faces::Standard,
+                mode: RollMode::Disadvantage(2),
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: None,
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dice_implicit_count() {
+        let (_, res) = parse_dice_expression("d6").unwrap();
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 1,
+                count_roll: None,
+                sides: 6,
+                faces: Faces::Standard,
+                mode: RollMode::Normal,
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: None,
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dice_multiple() {
+        let (_, res) = parse_dice_expression("10d100").unwrap();
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 10,
+                count_roll: None,
+                sides: 100,
+                faces: Faces::Standard,
+                mode: RollMode::Normal,
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: None,
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dice_with_modifier() {
+        let (_, res) = parse_expression("1d20+5").unwrap();
+        assert_eq!(
+            res,
+            Expression {
+                repeat: 1,
+                label: None,
+                check: None,
+                side: None,
+                floor: None,
+                pick: None,
+                root: Node::Binary(
+                    BinaryOp::Add,
+                    Box::new(Node::Dice(Box::new(DiceRequest {
+                        count: 1,
+                        count_roll: None,
+                        sides: 20,
+                        faces: Faces::Standard,
+                        mode: RollMode::Normal,
+                        explode: None,
+                        explode_on: None,
+                        explode_cap: None,
+                        keep: None,
+                        reroll: None,
+                        success: None,
+                        failure: None,
+                        minimum: None,
+                        maximum: None,
+                        each: None,
+                        crit_success: None,
+                        crit_failure: None,
+                        wild: None,
+                        matching: None,
+                        lucky: false,
+                        brutal: false,
+                        unique: false,
+                        sort: None,
+                    }))),
+                    Box::new(Node::Constant(5)),
+                ),
+            }
+        );
+        assert_eq!(res.root.modifier(), Some(5));
+    }
+
+    #[test]
+    fn test_parse_dice_with_negative_modifier() {
+        let (_, res) = parse_expression("1d20-2").unwrap();
+        assert_eq!(
+            res,
+            Expression {
+                repeat: 1,
+                label: None,
+                check: None,
+                side: None,
+                floor: None,
+                pick: None,
+                root: Node::Binary(
+                    BinaryOp::Sub,
+                    Box::new(Node::Dice(Box::new(DiceRequest {
+                        count: 1,
+                        count_roll: None,
+                        sides: 20,
+                        faces: Faces::Standard,
+                        mode: RollMode::Normal,
+                        explode: None,
+                        explode_on: None,
+                        explode_cap: None,
+                        keep: None,
+                        reroll: None,
+                        success: None,
+                        failure: None,
+                        minimum: None,
+                        maximum: None,
+                        each: None,
+                        crit_success: None,
+                        crit_failure: None,
+                        wild: None,
+                        matching: None,
+                        lucky: false,
+                        brutal: false,
+                        unique: false,
+                        sort: None,
+                    }))),
+                    Box::new(Node::Constant(2)),
+                ),
+            }
+        );
+        assert_eq!(res.root.modifier(), Some(-2));
+    }
+
+    #[test]
+    fn test_parse_dice_advantage_modifier() {
+        let (_, res) = parse_expression("1d20a+5").unwrap();
+        assert_eq!(
+            res,
+            Expression {
+                repeat: 1,
+                label: None,
+                check: None,
+                side: None,
+                floor: None,
+                pick: None,
+                root: Node::Binary(
+                    BinaryOp::Add,
+                    Box::new(Node::Dice(Box::new(DiceRequest {
+                        count: 1,
+                        count_roll: None,
+                        sides: 20,
+                        faces: Faces::Standard,
+                        mode: RollMode::Advantage(2),
+                        explode: None,
+                        explode_on: None,
+                        explode_cap: None,
+                        keep: None,
+                        reroll: None,
+                        success: None,
+                        failure: None,
+                        minimum: None,
+                        maximum: None,
+                        each: None,
+                        crit_success: None,
+                        crit_failure: None,
+                        wild: None,
+                        matching: None,
+                        lucky: false,
+                        brutal: false,
+                        unique: false,
+                        sort: None,
+                    }))),
+                    Box::new(Node::Constant(5)),
+                ),
+            }
+        );
+        assert_eq!(res.root.modifier(), Some(5));
+    }
+
+    #[test]
+    fn test_parse_dice_disadvantage_negative_modifier() {
+        let (rem, res) = parse_expression("2d8dis-3").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res,
+            Expression {
+                repeat: 1,
+                label: None,
+                check: None,
+                side: None,
+                floor: None,
+                pick: None,
+                root: Node::Binary(
+                    BinaryOp::Sub,
+                    Box::new(Node::Dice(Box::new(DiceRequest {
+                        count: 2,
+                        count_roll: None,
+                        sides: 8,
+                        faces: Faces::Standard,
+                        mode: RollMode::Disadvantage(2),
+                        explode: None,
+                        explode_on: None,
+                        explode_cap: None,
+                        keep: None,
+                        reroll: None,
+                        success: None,
+                        failure: None,
+                        minimum: None,
+                        maximum: None,
+                        each: None,
+                        crit_success: None,
+                        crit_failure: None,
+                        wild: None,
+                        matching: None,
+                        lucky: false,
+                        brutal: false,
+                        unique: false,
+                        sort: None,
+                    }))),
+                    Box::new(Node::Constant(3)),
+                ),
+            }
+        );
+        assert_eq!(res.root.modifier(), Some(-3));
+    }
+
+    #[test]
+    fn test_parse_multi_term() {
+        let (rem, res) = parse_expression("1d8+2d6+4").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.root,
+            Node::Binary(
+                BinaryOp::Add,
+                Box::new(Node::Binary(
+                    BinaryOp::Add,
+                    Box::new(Node::Dice(Box::new(DiceRequest {
+                        count: 1,
+                        count_roll: None,
+                        sides: 8,
+                        faces: Faces::Standard,
+                        mode: RollMode::Normal,
+                        explode: None,
+                        explode_on: None,
+                        explode_cap: None,
+                        keep: None,
+                        reroll: None,
+                        success: None,
+                        failure: None,
+                        minimum: None,
+                        maximum: None,
+                        each: None,
+                        crit_success: None,
+                        crit_failure: None,
+                        wild: None,
+                        matching: None,
+                        lucky: false,
+                        brutal: false,
+                        unique: false,
+                        sort: None,
+                    }))),
+                    Box::new(Node::Dice(Box::new(DiceRequest {
+                        count: 2,
+                        count_roll: None,
+                        sides: 6,
+                        faces: Faces::Standard,
+                        mode: RollMode::Normal,
+                        explode: None,
+                        explode_on: None,
+                        explode_cap: None,
+                        keep: None,
+                        reroll: None,
+                        success: None,
+                        failure: None,
+                        minimum: None,
+                        maximum: None,
+                        each: None,
+                        crit_success: None,
+                        crit_failure: None,
+                        wild: None,
+                        matching: None,
+                        lucky: false,
+                        brutal: false,
+                        unique: false,
+                        sort: None,
+                    }))),
+                )),
+                Box::new(Node::Constant(4)),
+            )
+        );
+        assert_eq!(res.root.modifier(), Some(4));
+    }
+
+    #[test]
+    fn test_parse_multi_term_mixed_modes_and_constants() {
+        let (rem, res) = parse_expression("1d20a+1d4+3-1").unwrap();
+        assert_eq!(rem, "");
+        let dice = res.root.dice();
+        assert_eq!(dice.len(), 2);
+        assert_eq!(dice[0].mode, RollMode::Advantage(2));
+        assert_eq!(dice[1].mode, RollMode::Normal);
+        assert_eq!(res.root.modifier(), Some(2));
+    }
+
+    #[test]
+    fn test_parse_advantage_count() {
+        let (rem, res) = parse_dice_expression("1d20a3").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Advantage(3));
+        assert_eq!(die_label(&res), "d20a3");
+
+        let (_, res) = parse_dice_expression("1d20a").unwrap();
+        assert_eq!(die_label(&res), "d20a");
+    }
+
+    #[test]
+    fn test_parse_disadvantage_count() {
+        let (rem, res) = parse_dice_expression("1d20d3").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Disadvantage(3));
+        assert_eq!(res.keep, None);
+        assert_eq!(die_label(&res), "d20d3");
+
+        let (rem, res) = parse_dice_expression("4d6dis3dl1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Disadvantage(3));
+        assert_eq!(res.keep, Some(Keep::DropLowest(1)));
+    }
+
+    #[test]
+    fn test_parse_long_mode_keywords() {
+        let (rem, res) = parse_dice_expression("1d20adv").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Advantage(2));
+        assert_eq!(die_label(&res), "d20a");
+
+        let (rem, res) = parse_dice_expression("2d20DIS").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Disadvantage(2));
+        assert_eq!(res.keep, None);
+
+        let (_, res) = parse_dice_expression("1d20adv3").unwrap();
+        assert_eq!(res.mode, RollMode::Advantage(3));
+
+        let (rem, res) = parse_dice_expression("1d20advx").unwrap();
+        assert_eq!(rem, "x");
+        assert_eq!(res.mode, RollMode::Advantage(2));
+        let err = parse_and_validate("1d20advx").unwrap_err();
+        assert!(err.to_string().contains("Unparsed content: 'x'"));
+    }
+
+    #[test]
+    fn test_mode_order() {
+        let canonical = validate_expression("1d20a+3").unwrap();
+        for input in [
+            "1d20+3a",
+            "1d20 + 3 adv",
+            "1d20+3 a2",
+            "1d20a+3",
+            "3+1d20a",
+            "3+1d20 a",
+        ] {
+            let res = validate_expression(input).unwrap();
+            assert_eq!(res.root.dice()[0].mode, RollMode::Advantage(2), "{}", input);
+            assert_eq!(res.root.modifier(), Some(3), "{}", input);
+        }
+        assert_eq!(canonical.to_string(), "1d20a+3");
+        let res = validate_expression("1d20kh1cs dis").unwrap();
+        assert_eq!(res.to_string(), "1d20dkh1cs20");
+        assert_eq!(
+            validate_expression("1d20+3 d").unwrap().root.dice()[0].mode,
+            RollMode::Disadvantage(2)
+        );
+
+        for input in ["1d20ad", "1d20a d", "1d20a+3a", "1d20dis+3adv"] {
+            let err = parse_and_validate(input).unwrap_err().to_string();
+            assert!(err.contains("Conflicting modes"), "{}", err);
+        }
+        // A trailing mode needs a single dice term to belong to, and a bare
+        // `d` against a number is an unfinished die.
+        assert!(parse_and_validate("1d20+1d6+3a").is_err());
+        assert!(parse_and_validate("2d6+1d").is_err());
+
+        // Suffixes go in any order, each only once.
+        for (a, b) in [
+            ("4d6r1kl1", "4d6kl1r1"),
+            ("4d6ro1dl1", "4d6dl1ro1"),
+            ("3d6ra1kh2", "3d6kh2ra1"),
+            ("4d6gwf dh", "4d6dh1gwf"),
+            ("3d6kh2!", "3d6!kh2"),
+            ("4d6min2kh3", "4d6kh3min2"),
+            ("8d10>=7kh5!>=10", "8d10!>=10kh5>=7"),
+            ("4d6u s kh3", "4d6kh3us"),
+        ] {
+            let a = parse_and_validate(a).unwrap();
+            assert_eq!(a, parse_and_validate(b).unwrap(), "{}", b);
+            assert!(a.root.dice()[0].keep.is_some(), "{}", b);
+        }
+        for (input, name) in [
+            ("4d6kh3kh2", "keep or drop"),
+            ("4d6kh3r1kl1", "keep or drop"),
+            ("4d6r1min2r2", "reroll"),
+            ("3d6!!!", "explosion"),
+        ] {
+            let err = parse_and_validate(input).unwrap_err().to_string();
+            let expected = format!(
+                "Conflicting suffixes in '{}': a die can only have one {}.",
+                input, name
+            );
+            assert!(err.contains(&expected), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_parse_d_suffixes() {
+        use Keep::*;
+        use RollMode::*;
+        // `None` marks an input rejected as ambiguous.
+        type Expected = Option<(RollMode, Option<Keep>)>;
+        let cases: &[(&str, Expected)] = &[
+            ("1d20d", Some((Disadvantage(2), None))),
+            ("1d20D", Some((Disadvantage(2), None))),
+            ("1d20d3", Some((Disadvantage(3), None))),
+            ("1d20dis", Some((Disadvantage(2), None))),
+            ("1d20DIS3", Some((Disadvantage(3), None))),
+            ("4d6dis3", Some((Disadvantage(3), None))),
+            ("4d6dl", Some((Normal, Some(DropLowest(1))))),
+            ("4d6dl2", Some((Normal, Some(DropLowest(2))))),
+            ("4d6dh", Some((Normal, Some(DropHighest(1))))),
+            ("4d6DH2", Some((Normal, Some(DropHighest(2))))),
+            ("4d6disdl1", Some((Disadvantage(2), Some(DropLowest(1))))),
+            ("4d6disdh1", Some((Disadvantage(2), Some(DropHighest(1))))),
+            // A bare `d` or `dN` on a pool could be a drop, and `d1` is
+            // never disadvantage.
+            ("4d6d", None),
+            ("4d6ddl1", None),
+            ("2d6d1", None),
+            ("4d6d3", None),
+            ("(1d4)d6d2", None),
+            ("1d20d1", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = parse_dice_expression(input);
+            match expected {
+                Some((mode, keep)) => {
+                    let (rem, res) = parsed.unwrap();
+                    assert_eq!(rem, "", "{}", input);
+                    assert_eq!((res.mode, res.keep), (*mode, *keep), "{}", input);
+                }
+                None => assert!(
+                    matches!(parsed, Err(nom::Err::Failure(_))),
+                    "{} should be ambiguous",
+                    input
+                ),
+            }
+        }
+
+        let err = parse_and_validate("2d6d1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: 'd1' in '2d6d1' is ambiguous: write dl1 to drop dice.\n  2d6d1\n     ^"
+        );
+        let err = parse_and_validate("4d6d").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: 'd' in '4d6d' is ambiguous: write dl to drop dice or dis for disadvantage.\n  4d6d\n     ^"
+        );
+        let err = parse_and_validate("4d6d3").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("write dl3 to drop dice or dis3 for disadvantage"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_parse_keep_highest() {
+        let (rem, res) = parse_dice_expression("4d6kh3").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 4,
+                count_roll: None,
+                sides: 6,
+                faces: Faces::Standard,
+                mode: RollMode::Normal,
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: Some(Keep::Highest(3)),
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_keep_lowest() {
+        let (rem, res) = parse_dice_expression("5d10kl2").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 5,
+                count_roll: None,
+                sides: 10,
+                faces: Faces::Standard,
+                mode: RollMode::Normal,
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: Some(Keep::Lowest(2)),
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_disadvantage_vs_drop_lowest() {
+        let (rem, res) = parse_dice_expression("4d6dis").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Disadvantage(2));
+        assert_eq!(res.keep, None);
+        // Shown the long way, since a bare `d` on a pool is ambiguous.
+        assert_eq!(die_label(&res), "d6dis");
+
+        let (rem, res) = parse_dice_expression("4d6dl1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Normal);
+        assert_eq!(res.keep, Some(Keep::DropLowest(1)));
+
+        let (rem, res) = parse_dice_expression("4d6dl").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Normal);
+        assert_eq!(res.keep, Some(Keep::DropLowest(1)));
+
+        let (rem, res) = parse_dice_expression("3d6dh").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Normal);
+        assert_eq!(res.keep, Some(Keep::DropHighest(1)));
+
+        let (rem, res) = parse_dice_expression("4d6disdl2").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Disadvantage(2));
+        assert_eq!(res.keep, Some(Keep::DropLowest(2)));
+    }
+
+    #[test]
+    fn test_parse_exploding() {
+        let (rem, res) = parse_dice_expression("3d6!").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 3,
+                count_roll: None,
+                sides: 6,
+                faces: Faces::Standard,
+                mode: RollMode::Normal,
+                explode: Some(Explode::Standard),
+                explode_on: None,
+                explode_cap: None,
+                keep: None,
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_compound_exploding() {
+        let (rem, res) = parse_dice_expression("5d6!!").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.explode, Some(Explode::Compound));
+    }
+
+    #[test]
+    fn test_parse_penetrating_exploding() {
+        let (rem, res) = parse_dice_expression("d6!pkh1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.explode, Some(Explode::Penetrating));
+        assert_eq!(res.keep, Some(Keep::Highest(1)));
+    }
+
+    #[test]
+    fn test_parse_reroll_once() {
+        let (rem, res) = parse_dice_expression("2d6ro1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.reroll,
+            Some(Reroll::Once(Comparison {
+                op: CompareOp::Eq,
+                value: 1,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_reroll_recursive() {
+        let (rem, res) = parse_dice_expression("d10r1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.reroll,
+            Some(Reroll::Recursive(Comparison {
+                op: CompareOp::Eq,
+                value: 1,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_comparison_ops() {
+        let cases = [
+            ("<3", CompareOp::Lt, 3),
+            ("<=3", CompareOp::Le, 3),
+            (">5", CompareOp::Gt, 5),
+            (">=5", CompareOp::Ge, 5),
+            ("=2", CompareOp::Eq, 2),
+            ("2", CompareOp::Eq, 2),
+        ];
+        for (input, op, value) in cases {
+            let (rem, res) = parse_comparison(input).unwrap();
+            assert_eq!(rem, "", "{}", input);
+            assert_eq!(res, Comparison { op, value }, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_comparison_boundaries() {
+        let lt = Comparison {
+            op: CompareOp::Lt,
+            value: 3,
+        };
+        assert!(lt.matches(2));
+        assert!(!lt.matches(3));
+        let ge = Comparison {
+            op: CompareOp::Ge,
+            value: 5,
+        };
+        assert!(!ge.matches(4));
+        assert!(ge.matches(5));
+        assert!(lt.matches_all(1, 2));
+        assert!(!lt.matches_all(1, 3));
+    }
+
+    #[test]
+    fn test_parse_fate() {
+        let (rem, res) = parse_dice_expression("4dF").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.count, 4);
+        assert_eq!(res.faces, Faces::Fate);
+        assert_eq!((res.min_face(), res.max_face()), (-1, 1));
+    }
+
+    #[test]
+    fn test_parse_percentile() {
+        let (rem, res) = parse_dice_expression("2d%").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res,
+            DiceRequest {
+                count: 2,
+                count_roll: None,
+                sides: 100,
+                faces: Faces::Standard,
+                mode: RollMode::Normal,
+                explode: None,
+                explode_on: None,
+                explode_cap: None,
+                keep: None,
+                reroll: None,
+                success: None,
+                failure: None,
+                minimum: None,
+                maximum: None,
+                each: None,
+                crit_success: None,
+                crit_failure: None,
+                wild: None,
+                matching: None,
+                lucky: false,
+                brutal: false,
+                unique: false,
+                sort: None,
+            }
+        );
+
+        let (rem, res) = parse_dice_expression("d%a").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.sides, 100);
+        assert_eq!(res.mode, RollMode::Advantage(2));
+        assert_eq!(die_label(&res), "d100a");
+    }
+
+    #[test]
+    fn test_parse_digit_dice() {
+        let (_, res) = parse_dice_expression("d66").unwrap();
+        assert_eq!(res.faces, Faces::Digits { base: 6, digits: 2 });
+        assert_eq!((res.min_face(), res.max_face()), (11, 66));
+
+        let (_, res) = parse_dice_expression("d666").unwrap();
+        assert_eq!((res.min_face(), res.max_face()), (111, 666));
+
+        let (_, res) = parse_dice_expression("d88").unwrap();
+        assert_eq!(res.faces, Faces::Digits { base: 8, digits: 2 });
+
+        // Other repeated digits are ordinary dice: d22 rolls 1 to 22.
+        let (_, res) = parse_dice_expression("d22").unwrap();
+        assert_eq!(res.faces, Faces::Standard);
+        assert_eq!((res.min_face(), res.max_face()), (1, 22));
+
+        for sides in [6, 11, 20, 33, 44, 67, 99, 100, 555] {
+            assert_eq!(Faces::from_sides(sides), Faces::Standard, "d{}", sides);
+        }
+    }
+
+    #[test]
+    fn test_parse_custom_faces() {
+        let (rem, res) = parse_dice_expression("3d{0,0,1,1,2,4}").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.count, 3);
+        assert_eq!(res.sides, 6);
+        assert_eq!(res.faces, Faces::Custom(vec![0, 0, 1, 1, 2, 4]));
+        assert_eq!((res.min_face(), res.max_face()), (0, 4));
+        assert_eq!(die_label(&res), "d{0,0,1,1,2,4}");
+
+        let (_, res) = parse_dice_expression("d{-1,5}").unwrap();
+        assert_eq!(res.faces, Faces::Custom(vec![-1, 5]));
+    }
+
+    #[test]
+    fn test_parse_text_faces() {
+        let (rem, res) = parse_dice_expression("d{yes,no,\"maybe, later\"}").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.sides, 3);
+        assert_eq!(
+            res.faces,
+            Faces::Text(vec![
+                "yes".to_string(),
+                "no".to_string(),
+                "maybe, later".to_string()
+            ])
+        );
+        assert_eq!(die_label(&res), "d{yes,no,\"maybe, later\"}");
+
+        let (_, res) = parse_dice_expression("d{1,two}").unwrap();
+        assert_eq!(
+            res.faces,
+            Faces::Text(vec!["1".to_string(), "two".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_success_target() {
+        let (rem, res) = parse_dice_expression("8d10>=7").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.success,
+            Some(Comparison {
+                op: CompareOp::Ge,
+                value: 7,
+            })
+        );
+        // The operator is required, so a trailing number is left unparsed.
+        let (rem, res) = parse_dice_expression("8d10 7").unwrap();
+        assert_eq!(rem, " 7");
+        assert_eq!(res.success, None);
+    }
+
+    #[test]
+    fn test_pool_with_summed_dice() {
+        let err = parse_and_validate("2d6+6d6t5").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: '2d6+6d6t5' adds the sum of 2d6 to a count of successes; \
+             roll them as separate expressions."
+        );
+        // Pools add up with each other and with constants, as bonus successes.
+        assert!(parse_and_validate("6d6t5+2d6t6+1").is_ok());
+        let rng = &mut ScriptedRng::new(&[5, 1, 6, 2, 6]);
+        let output =
+            execute_roll_with(&["2d6, 3d6t5".to_string()], &Options::default(), rng).unwrap();
+        assert!(
+            output.contains("| Successes      | 2 successes |"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("| Total          | 6           |"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_parse_failure_clause() {
+        let (rem, res) = parse_dice_expression("8d10>=7f1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.failure,
+            Some(Comparison {
+                op: CompareOp::Eq,
+                value: 1,
+            })
+        );
+        assert_eq!(die_label(&res), "d10>=7f1");
+    }
+
+    #[test]
+    fn test_parse_target_shorthand() {
+        let (rem, res) = parse_dice_expression("6d6t5").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.success,
+            Some(Comparison {
+                op: CompareOp::Ge,
+                value: 5,
+            })
+        );
+    }
+
+    /// Parses `input` and evaluates it with the given dice totals.
+    fn evaluate_with(input: &str, values: &[i64]) -> i64 {
+        let (rem, res) = parse_expression(input).unwrap();
+        assert_eq!(rem, "", "{}", input);
+        res.root.evaluate(&mut values.iter().copied())
+    }
+
+    #[test]
+    fn test_parse_minimum() {
+        let (rem, res) = parse_dice_expression("8d6min2").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.minimum, Some(2));
+        assert_eq!(die_label(&res), "d6min2");
+
+        let (rem, res) = parse_dice_expression("4d6r1min3>=5").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.minimum, Some(3));
+        assert!(res.reroll.is_some() && res.success.is_some());
+    }
+
+    #[test]
+    fn test_parse_maximum() {
+        let (rem, res) = parse_dice_expression("3d10max8").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.maximum, Some(8));
+        assert_eq!(die_label(&res), "d10max8");
+
+        let (rem, res) = parse_dice_expression("3d10min2max8").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!((res.minimum, res.maximum), (Some(2), Some(8)));
+    }
+
+    #[test]
+    fn test_parse_crit_success() {
+        let (rem, res) = parse_dice_expression("1d20cs>=19").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.crit_success,
+            Some(Comparison {
+                op: CompareOp::Ge,
+                value: 19,
+            })
+        );
+        assert_eq!(die_label(&res), "d20cs>=19");
+
+        let (rem, res) = parse_dice_expression("1d20acs").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.crit_success,
+            Some(Comparison {
+                op: CompareOp::Eq,
+                value: 20,
+            })
+        );
+        assert_eq!(die_label(&res), "d20acs20");
+    }
+
+    #[test]
+    fn test_parse_crit_failure() {
+        let (rem, res) = parse_dice_expression("1d20cf<=2").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.crit_failure,
+            Some(Comparison {
+                op: CompareOp::Le,
+                value: 2,
+            })
+        );
+
+        let (rem, res) = parse_dice_expression("1d20cscf").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.crit_failure,
+            Some(Comparison {
+                op: CompareOp::Eq,
+                value: 1,
+            })
+        );
+        assert_eq!(die_label(&res), "d20cs20cf1");
+    }
+
+    #[test]
+    fn test_parse_nested_count() {
+        let (rem, res) = parse_dice_expression("(1d4)d6").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.sides, 6);
+        let count_roll = res.count_roll.unwrap();
+        assert_eq!((count_roll.count, count_roll.sides), (1, 4));
+
+        let (rem, res) = parse_expression("(2d4kh1)d6+1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.root.modifier(), Some(1));
+        assert!(res.root.dice()[0].count_roll.is_some());
+
+        // A parenthesised sum is still a group.
+        let (_, res) = parse_expression("(1d4+1)*3").unwrap();
+        assert!(matches!(res.root, Node::Binary(BinaryOp::Mul, _, _)));
+    }
+
+    #[test]
+    fn test_parse_multiplication() {
+        assert_eq!(evaluate_with("2d6*10+5", &[7]), 75);
+        assert_eq!(evaluate_with("10*2d6", &[7]), 70);
+        assert_eq!(evaluate_with("1d8+3*2d6+2*3", &[1, 7]), 28);
+    }
+
+    #[test]
+    fn test_parse_division() {
+        assert_eq!(evaluate_with("2d6/2+1", &[7]), 4);
+        assert_eq!(evaluate_with("2d6*3/^2", &[7]), 11);
+        assert_eq!(evaluate_with("2d6/2*3", &[7]), 9);
+    }
+
+    #[test]
+    fn test_division_rounding() {
+        assert_eq!(BinaryOp::Div.apply(7, 2), 3);
+        assert_eq!(BinaryOp::DivUp.apply(7, 2), 4);
+        assert_eq!(BinaryOp::DivUp.apply(8, 2), 4);
+        assert_eq!(BinaryOp::Div.apply(-3, 2), -2);
+        assert_eq!(BinaryOp::DivUp.apply(-3, 2), -1);
+        assert_eq!(BinaryOp::Div.apply(7, -2), -4);
+    }
+
+    #[test]
+    fn test_parse_grouping() {
+        assert_eq!(evaluate_with("(1d4+1)*3", &[3]), 12);
+        assert_eq!(evaluate_with("1d4+1*3", &[3]), 6);
+        assert_eq!(evaluate_with("2*((1d6-1))", &[4]), 6);
+        assert_eq!(evaluate_with("10-2-1d4", &[3]), 5);
+
+        let (_, res) = parse_expression("(1d4+1)*3").unwrap();
+        assert_eq!(res.root.modifier(), None);
+        assert_eq!(res.root.formula(&mut [3].into_iter()), "(3 + 1) × 3");
+    }
+
+    #[test]
+    fn test_unclosed_parenthesis() {
+        let err = parse_and_validate("(1d4").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Unclosed parenthesis in '(1d4'.\n  (1d4\n  ^"
+        );
+        let err = parse_and_validate("2*(1d4+(1d6)").unwrap_err();
+        assert!(err.to_string().ends_with("\n  2*(1d4+(1d6)\n    ^"));
+
+        let err = parse_and_validate("1d4)").unwrap_err();
+        assert!(err.to_string().contains("Unparsed content: ')'"));
+    }
+
+    #[test]
+    fn test_parse_whitespace() {
+        let (rem, res) = parse_expression(" 2 d 6 ").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.root, parse_expression("2d6").unwrap().1.root);
+
+        let (rem, res) = parse_expression("1d20 +5").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.root.modifier(), Some(5));
+
+        let (rem, res) = parse_expression("1d20 a").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.root.dice()[0].mode, RollMode::Advantage(2));
+
+        let (rem, res) = parse_expression("\t4d6 dl1 + ( 1d4 * 2 )").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.root.dice()[0].keep, Some(Keep::DropLowest(1)));
+        assert_eq!(res.root.dice().len(), 2);
+    }
+
+    #[test]
+    fn test_whitespace_errors() {
+        let err = parse_and_validate("2d6 + x").unwrap_err();
+        assert!(err.to_string().contains("Unparsed content: '+ x'"));
+        let err = parse_and_validate("2 * (1d4").unwrap_err();
+        assert!(err.to_string().ends_with("\n  2 * (1d4\n      ^"));
+    }
+
+    #[test]
+    fn test_parse_case_insensitive() {
+        let (rem, res) = parse_dice_expression("2D6a").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res, parse_dice_expression("2d6a").unwrap().1);
+
+        let (rem, res) = parse_dice_expression("4D6KH3").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.keep, Some(Keep::Highest(3)));
+        assert_eq!(die_label(&res), "d6kh3");
+
+        let (rem, res) = parse_dice_expression("1D20D").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.mode, RollMode::Disadvantage(2));
+        assert_eq!(die_label(&res), "d20d");
+
+        let (_, res) = parse_dice_expression("4D6DL1").unwrap();
+        assert_eq!(res.keep, Some(Keep::DropLowest(1)));
+        let (_, res) = parse_dice_expression("2D6!P").unwrap();
+        assert_eq!(res.explode, Some(Explode::Penetrating));
+        let (_, res) = parse_dice_expression("4df").unwrap();
+        assert_eq!(res.faces, Faces::Fate);
+        let (_, res) = parse_expression("3X2D6").unwrap();
+        assert_eq!(res.repeat, 3);
+    }
+
+    #[test]
+    fn test_parse_repeat() {
+        let (rem, res) = parse_expression("6x(4d6dl1)").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.repeat, 6);
+        assert!(matches!(res.root, Node::Group(_)));
+
+        let (rem, res) = parse_expression("3x2d6+1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.repeat, 3);
+        assert_eq!(res.root.modifier(), Some(1));
+
+        let (_, res) = parse_expression("2d6").unwrap();
+        assert_eq!(res.repeat, 1);
+    }
+
+    #[test]
+    fn test_parse_label() {
+        let (rem, res) = parse_expression("1d20+5:attack").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.label.as_deref(), Some("attack"));
+        assert_eq!(res.root.modifier(), Some(5));
+
+        let (rem, res) = parse_expression("2d6 : off-hand_2").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.label.as_deref(), Some("off-hand_2"));
+
+        let (rem, _) = parse_expression("1d20:bad label").unwrap();
+        assert_eq!(rem, "label");
+
+        // Quoted, a label may hold anything, with `""` for a quote.
+        let (rem, res) = parse_expression("1d20 :\"Bob's \"\"big\"\" axe, swung # ok\"").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.label.as_deref(), Some("Bob's \"big\" axe, swung # ok"));
+        assert_eq!(
+            res.to_string(),
+            "1d20:\"Bob's \"\"big\"\" axe, swung # ok\""
+        );
+        let (rem, _) = parse_expression("1d20:\"\"").unwrap();
+        assert_eq!(rem, ":\"\"");
+        let expressions = parse_argument("1d20:\"a, b vs c\",1d6").unwrap();
+        assert_eq!(expressions.len(), 2);
+        assert_eq!(expressions[0].label.as_deref(), Some("a, b vs c"));
+        let (rem, res) = parse_expression("1d20:").unwrap();
+        assert_eq!(rem, ":");
+        assert_eq!(res.label, None);
+    }
+
+    #[test]
+    fn test_parse_comment() {
+        let (rem, res) = parse_expression("2d6+3 # goblin shortbow").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.root.modifier(), Some(3));
+        assert_eq!(res.label, None);
+
+        let (rem, res) = parse_expression("1d20:attack#note").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.label.as_deref(), Some("attack"));
+
+        let (rem, res) = parse_expression("d{a#1,b} # pick one").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.root.dice()[0].faces,
+            Faces::Text(vec!["a#1".to_string(), "b".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_dice_invalid() {
+        assert!(parse_dice_expression("invalid").is_err());
+        let (rem, _) = parse_dice_expression("1d20extra").unwrap();
+        assert_eq!(rem, "extra");
+    }
+
+    // --- Integration Tests (using function calls) ---
+
+    #[test]
+    fn test_single_die() {
+        let output = execute_roll(&["1d20".to_string()]).unwrap();
+        assert!(output.contains("d20"));
+        assert!(output.contains("Die")); // Header
+        assert!(output.contains("Roll")); // Header
+        assert!(!output.contains("Total")); // Should not show Total for single roll
+    }
+
+    #[test]
+    fn test_multiple_dice() {
+        let output = execute_roll(&["2d6".to_string(), "1d10".to_string()]).unwrap();
+        assert!(output.contains("d6"));
+        assert!(output.contains("d10"));
+        assert!(output.contains("Total"));
+    }
+
+    #[test]
+    fn test_advantage_roll_execution() {
+        let output = execute_roll(&["1d20a".to_string()]).unwrap();
+        assert!(output.contains("d20a"));
+        assert!(!output.contains("Total")); // Should not show Total for single roll
+        // We can't easily assert the values without parsing the table back, but we check if it runs.
+        // We can possibly check for parentheses if we roll enough times or mock,
+        // but for a formatted string check, existence of "d20a" is good.
+    }
+
+    #[test]
+    fn test_advantage_keeps_best_of_three() {
+        let expression = parse_and_validate("1d20a3").unwrap();
+        let mut rng = ScriptedRng::new(&[7, 18, 4]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.value, 18);
+        assert_eq!(die.alternates, vec![7, 4]);
+        assert_eq!(result.total(), 18);
+
+        let output = execute_roll(&["1d1a3".to_string()]).unwrap();
+        assert!(output.contains("1 (1, 1)"));
+    }
+
+    #[test]
+    fn test_disadvantage_keeps_worst_of_three() {
+        let expression = parse_and_validate("1d20d3").unwrap();
+        let mut rng = ScriptedRng::new(&[7, 18, 4]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.value, 4);
+        assert_eq!(die.alternates, vec![7, 18]);
+        assert_eq!(result.total(), 4);
+    }
+
+    #[test]
+    fn test_disadvantage_count_vs_separate_die() {
+        let expressions: Vec<Expression> = ["1d20", "d3"]
+            .iter()
+            .map(|s| parse_and_validate(s).unwrap())
+            .collect();
+        assert_eq!(expressions[0].root.dice()[0].mode, RollMode::Normal);
+        assert_eq!(expressions[1].root.dice()[0].sides, 3);
+
+        let output = execute_roll(&["1d20".to_string(), "d3".to_string()]).unwrap();
+        assert!(output.contains("| d20 "));
+        assert!(output.contains("| d3 "));
+        assert!(output.contains("Total"));
+
+        let err = execute_roll(&["1d20d1".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("'d1' in '1d20d1' is ambiguous"));
+        let err = execute_roll(&["1d20dis1".to_string()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Disadvantage needs at least 2 dice")
+        );
+    }
+
+    #[test]
+    fn test_advantage_count_too_small() {
+        for input in ["1d20a0", "1d20a1"] {
+            let err = execute_roll(&[input.to_string()]).unwrap_err();
+            assert!(err.to_string().contains("at least 2 dice"), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_modifier_applies_once_per_expression() {
+        let expression = parse_and_validate("3d1+3").unwrap();
+        let result = roll_expression(&expression, &mut rand::thread_rng());
+        assert_eq!(result.rolls[0].dice.len(), 3);
+        assert_eq!(result.total(), 6);
+    }
+
+    #[test]
+    fn test_modifier_row() {
+        let output = execute_roll(&["1d20a+5".to_string()]).unwrap();
+        assert!(output.contains("d20a"));
+        assert!(output.contains("Modifier"));
+        assert!(output.contains("+5"));
+        assert!(output.contains("Total"));
+    }
+
+    #[test]
+    fn test_negative_modifier_total() {
+        let expression = parse_and_validate("1d1-10").unwrap();
+        assert_eq!(
+            roll_expression(&expression, &mut rand::thread_rng()).total(),
+            -9
+        );
+
+        let output = execute_roll(&["1d1-10".to_string()]).unwrap();
+        assert!(output.contains("-10"));
+        assert!(output.contains("-9"));
+    }
+
+    #[test]
+    fn test_multi_term_subtotals() {
+        let expression = parse_and_validate("1d1+2d1+4").unwrap();
+        let result = roll_expression(&expression, &mut rand::thread_rng());
+        assert_eq!(result.rolls.len(), 2);
+        assert_eq!(result.total(), 7);
+
+        let output = execute_roll(&["1d1+2d1+4".to_string(), "1d1+1".to_string()]).unwrap();
+        assert!(output.contains("Subtotal"));
+        assert!(output.contains("| 7 "));
+        assert!(output.contains("| 9 "));
+    }
+
+    #[test]
+    fn test_repeat_blocks() {
+        let output = execute_roll(&["3x(4d1dl1)".to_string()]).unwrap();
+        assert_eq!(output.matches("(1)").count(), 3);
+        for n in 1..=3 {
+            assert!(output.contains(&format!("| Subtotal {} | 3 ", n)));
+        }
+        assert!(output.contains("| Total      | 9 "));
+
+        let output = execute_roll(&["2x1d1".to_string(), "1d1".to_string()]).unwrap();
+        assert!(output.contains("Subtotal 2"));
+        assert!(output.contains("| 3 "));
+    }
+
+    #[test]
+    fn test_label_column() {
+        let output =
+            execute_roll(&["1d1+5:attack".to_string(), "1d1+3:damage".to_string()]).unwrap();
+        assert!(output.contains("| Label  | Die "));
+        assert!(output.contains("| attack | d1 "));
+        assert!(output.contains("| damage | Modifier         | +3 "));
+        assert!(output.contains("| damage | Subtotal (1d1+3) | 4 "));
+        assert!(output.contains("|        | Total            | 10 "));
+
+        let output = execute_roll(&["2x1d1:hp".to_string()]).unwrap();
+        assert!(output.contains("| hp 2  | Subtotal 2 "));
+
+        let output = execute_roll(&["1d1+5".to_string()]).unwrap();
+        assert!(!output.contains("Label"));
+    }
+
+    #[test]
+    fn test_repeat_invalid_counts() {
+        let err = execute_roll(&["0x1d6".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("at least once"));
+        let err = execute_roll(&["1000x1d6".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("more than 100 times"));
+    }
+
+    #[test]
+    fn test_apply_keep_highest() {
+        let mut dice: Vec<DieRoll> = [3, 6, 1, 6]
+            .into_iter()
+            .map(|value| DieRoll {
+                value,
+                rolls: vec![value],
+                text: None,
+                rerolled: vec![],
+                unclamped: None,
+                alternates: vec![],
+                duplicates: vec![],
+                snake_eyes: false,
+                luck: false,
+                bonus: false,
+                capped: false,
+                kept: true,
+                throws: vec![(value, Throw::Counted)],
+            })
+            .collect();
+        apply_keep(&mut dice, Keep::Highest(2));
+        let kept: Vec<bool> = dice.iter().map(|die| die.kept).collect();
+        assert_eq!(kept, vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn test_apply_keep_lowest() {
+        let mut dice: Vec<DieRoll> = [3, 6, 1, 1]
+            .into_iter()
+            .map(|value| DieRoll {
+                value,
+                rolls: vec![value],
+                text: None,
+                rerolled: vec![],
+                unclamped: None,
+                alternates: vec![],
+                duplicates: vec![],
+                snake_eyes: false,
+                luck: false,
+                bonus: false,
+                capped: false,
+                kept: true,
+                throws: vec![(value, Throw::Counted)],
+            })
+            .collect();
+        apply_keep(&mut dice, Keep::Lowest(1));
+        let kept: Vec<bool> = dice.iter().map(|die| die.kept).collect();
+        assert_eq!(kept, vec![false, false, true, false]);
+    }
+
+    #[test]
+    fn test_apply_drop_lowest() {
+        let mut dice: Vec<DieRoll> = [3, 6, 1, 5]
+            .into_iter()
+            .map(|value| DieRoll {
+                value,
+                rolls: vec![value],
+                text: None,
+                rerolled: vec![],
+                unclamped: None,
+                alternates: vec![],
+                duplicates: vec![],
+                snake_eyes: false,
+                luck: false,
+                bonus: false,
+                capped: false,
+                kept: true,
+                throws: vec![(value, Throw::Counted)],
+            })
+            .collect();
+        apply_keep(&mut dice, Keep::DropLowest(1));
+        let kept: Vec<bool> = dice.iter().map(|die| die.kept).collect();
+        assert_eq!(kept, vec![true, true, false, true]);
+    }
+
+    #[test]
+    fn test_apply_drop_highest() {
+        let mut dice: Vec<DieRoll> = [3, 6, 1, 6]
+            .into_iter()
+            .map(|value| DieRoll {
+                value,
+                rolls: vec![value],
+                text: None,
+                rerolled: vec![],
+                unclamped: None,
+                alternates: vec![],
+                duplicates: vec![],
+                snake_eyes: false,
+                luck: false,
+                bonus: false,
+                capped: false,
+                kept: true,
+                throws: vec![(value, Throw::Counted)],
+            })
+            .collect();
+        apply_keep(&mut dice, Keep::DropHighest(2));
+        let kept: Vec<bool> = dice.iter().map(|die| die.kept).collect();
+        assert_eq!(kept, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_keep_middle() {
+        let res = parse_and_validate("5d6km3").unwrap();
+        assert_eq!(res.root.dice()[0].keep, Some(Keep::Middle(3)));
+        let output = execute_roll_with(
+            &["3d20km1".to_string()],
+            &Options::default(),
+            &mut ScriptedRng::new(&[15, 4, 9]),
+        )
+        .unwrap();
+        assert!(output.contains("| d20km1 | (15) |"));
+        assert!(output.contains("| d20km1 | (4)  |"));
+        assert!(output.contains("| Total  | 9    |"));
+
+        let err = parse_and_validate("4d20km1").unwrap_err();
+        assert!(err.to_string().contains("4 dice have no middle 1"));
+        let err = parse_and_validate("3d20km4").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Cannot keep 4 dice when only 3 are rolled")
+        );
+    }
+
+    #[test]
+    fn test_drop_highest_matches_disadvantage() {
+        let drop_highest = parse_and_validate("2d20dh1").unwrap();
+        let disadvantage = parse_and_validate("1d20d").unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let result = roll_expression(&drop_highest, &mut rng);
+            let values: Vec<i64> = result.rolls[0].dice.iter().map(|die| die.value).collect();
+            assert_eq!(result.total(), values[0].min(values[1]));
+
+            let result = roll_expression(&disadvantage, &mut rng);
+            let die = &result.rolls[0].dice[0];
+            assert_eq!(result.total(), die.value.min(die.alternates[0]));
+        }
+    }
+
+    #[test]
+    fn test_drop_lowest_invalid_counts() {
+        let err = execute_roll(&["4d6dl0".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Must drop at least one die"));
+        let err = execute_roll(&["4d6dl4".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Cannot drop 4 dice"));
+    }
+
+    #[test]
+    fn test_keep_highest_total() {
+        let expression = parse_and_validate("4d1kh3+1").unwrap();
+        let result = roll_expression(&expression, &mut rand::thread_rng());
+        assert_eq!(result.rolls[0].dice.len(), 4);
+        assert_eq!(result.total(), 4);
+
+        let output = execute_roll(&["4d1kh3".to_string()]).unwrap();
+        assert!(output.contains("d1kh3"));
+        assert!(output.contains("(1)"));
+    }
+
+    #[test]
+    fn test_exploding_chain() {
+        let expression = parse_and_validate("d6!").unwrap();
+        let mut rng = ScriptedRng::new(&[6, 6, 6, 3]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.rolls, vec![6, 6, 6, 3]);
+        assert_eq!(result.total(), 21);
+        assert_eq!(
+            format_die_value(die, &result.rolls[0].request),
+            "6+6+6+3 = 21"
+        );
+    }
+
+    #[test]
+    fn test_exploding_only_on_max() {
+        let expression = parse_and_validate("2d6!").unwrap();
+        let mut rng = ScriptedRng::new(&[5, 6, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.rolls[0].dice[0].rolls, vec![5]);
+        assert_eq!(result.rolls[0].dice[1].rolls, vec![6, 1]);
+        assert_eq!(result.total(), 12);
+    }
+
+    #[test]
+    fn test_exploding_is_capped() {
+        let expression = parse_and_validate("d1!").unwrap();
+        let result = roll_expression(&expression, &mut rand::thread_rng());
+        assert_eq!(result.rolls[0].dice[0].rolls.len(), MAX_EXPLOSIONS + 1);
+        assert_eq!(result.total(), MAX_EXPLOSIONS as i64 + 1);
+    }
+
+    #[test]
+    fn test_explosion_cap() {
+        let args = ["2d6!2".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[6, 6, 6, 6, 3]),
+        )
+        .unwrap();
+        assert!(output.contains("| d6!2  | 6+6+6 = 18 (capped at 2) |"));
+        assert!(output.contains("| d6!2  | 6+3 = 9                  |"));
+        assert!(output.contains("| Total | 27                       |"));
+
+        // A cap makes exploding on every face safe.
+        let res = parse_and_validate("1d6!3>=1").unwrap();
+        assert_eq!(res.root.dice()[0].explode_cap, Some(3));
+        let result = roll_expression(&res, &mut rand::thread_rng());
+        assert_eq!(result.rolls[0].dice[0].rolls.len(), 4);
+        assert!(result.rolls[0].dice[0].capped);
+
+        let err = parse_and_validate("1d6!0").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("An explosion cap must be at least 1")
+        );
+    }
+
+    #[test]
+    fn test_roll_and_keep() {
+        let res = parse_and_validate("5k3").unwrap();
+        assert_eq!(
+            *res.root.dice()[0],
+            DiceRequest {
+                explode: Some(Explode::Standard),
+                keep: Some(Keep::Highest(3)),
+                ..DiceRequest::plain(5, 10)
+            }
+        );
+        let args = ["5k3".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[10, 4, 2, 7, 10, 1, 3]),
+        )
+        .unwrap();
+        assert!(output.contains("| d10!kh3 | 10+4 = 14 |"));
+        assert!(output.contains("| d10!kh3 | (2)       |"));
+        assert!(output.contains("| d10!kh3 | 7         |"));
+        assert!(output.contains("| d10!kh3 | 10+1 = 11 |"));
+        assert!(output.contains("| d10!kh3 | (3)       |"));
+        assert!(output.contains("| Total   | 32        |"));
+
+        let err = parse_and_validate("3k4").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Cannot keep 4 dice when only 3 are rolled")
+        );
+    }
+
+    #[test]
+    fn test_compound_exploding() {
+        let expression = parse_and_validate("2d6!!").unwrap();
+        let mut rng = ScriptedRng::new(&[6, 6, 2, 4]);
+        let result = roll_expression(&expression, &mut rng);
+        let dice = &result.rolls[0].dice;
+        assert_eq!(dice.len(), 2);
+        assert_eq!(dice[0].value, 14);
+        assert_eq!(dice[1].value, 4);
+        assert_eq!(result.total(), 18);
+        assert_eq!(
+            format_die_value(&dice[0], &result.rolls[0].request),
+            "14 [6→6→2]"
+        );
+        assert_eq!(format_die_value(&dice[1], &result.rolls[0].request), "4");
+    }
+
+    #[test]
+    fn test_compound_exploding_is_capped() {
+        let expression = parse_and_validate("d1!!").unwrap();
+        let result = roll_expression(&expression, &mut rand::thread_rng());
+        assert_eq!(result.rolls[0].dice.len(), 1);
+        assert_eq!(result.total(), MAX_EXPLOSIONS as i64 + 1);
+    }
+
+    #[test]
+    fn test_penetrating_exploding() {
+        let expression = parse_and_validate("d6!p").unwrap();
+        let mut rng = ScriptedRng::new(&[6, 6, 3]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.rolls, vec![6, 5, 2]);
+        assert_eq!(result.total(), 13);
+        assert_eq!(
+            format_die_value(die, &result.rolls[0].request),
+            "6+5+2 = 13"
+        );
+    }
+
+    #[test]
+    fn test_penetrating_never_below_first_roll() {
+        let expression = parse_and_validate("d6!p").unwrap();
+        let mut rng = ScriptedRng::new(&[6, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.rolls[0].dice[0].rolls, vec![6, 0]);
+        assert_eq!(result.total(), 6);
+    }
+
+    #[test]
+    fn test_reroll_once() {
+        let expression = parse_and_validate("2d6ro1").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 4, 3]);
+        let result = roll_expression(&expression, &mut rng);
+        let dice = &result.rolls[0].dice;
+        assert_eq!(dice[0].value, 4);
+        assert_eq!(dice[0].rerolled, vec![1]);
+        assert_eq!(dice[1].value, 3);
+        assert!(dice[1].rerolled.is_empty());
+        assert_eq!(result.total(), 7);
+    }
+
+    #[test]
+    fn test_reroll_once_keeps_second_one() {
+        let expression = parse_and_validate("1d6ro1").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.value, 1);
+        assert_eq!(die.rerolled, vec![1]);
+    }
+
+    #[test]
+    fn test_reroll_add() {
+        let res = parse_and_validate("3d6kh2ra1").unwrap();
+        assert_eq!(
+            res.root.dice()[0].reroll,
+            Some(Reroll::Add(Comparison {
+                op: CompareOp::Eq,
+                value: 1
+            }))
+        );
+        // The added roll is part of the die before keep picks the highest.
+        let args = ["3d6kh2ra1".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[1, 6, 2, 3]),
+        )
+        .unwrap();
+        assert!(output.contains("| d6kh2ra1 | 1+6 = 7 |"));
+        assert!(output.contains("| d6kh2ra1 | (2)     |"));
+        assert!(output.contains("| Total    | 10      |"));
+
+        // Replacing shows the old face as rerolled instead.
+        let args = ["1d6ro1".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[1, 6])).unwrap();
+        assert!(output.contains("| d6ro1 | 6 (rerolled 1) |"));
+
+        let err = parse_and_validate("d66ra1").unwrap_err();
+        assert!(err.to_string().contains("d66ra1 dice cannot add a reroll"));
+    }
+
+    #[test]
+    fn test_reroll_recursive() {
+        let expression = parse_and_validate("d10r1").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 1, 1, 7]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.value, 7);
+        assert_eq!(die.rerolled, vec![1, 1, 1]);
+        assert_eq!(result.total(), 7);
+        assert_eq!(format_rerolls(&die.rerolled), "rerolled 3×: 1, 1, 1");
+    }
+
+    #[test]
+    fn test_reroll_recursive_is_capped() {
+        let (_, expression) = parse_expression("d1r1").unwrap();
+        let result = roll_expression(&expression, &mut rand::thread_rng());
+        assert_eq!(result.rolls[0].dice[0].rerolled.len(), MAX_REROLLS);
+        assert_eq!(result.total(), 1);
+    }
+
+    #[test]
+    fn test_reroll_below_threshold() {
+        let expression = parse_and_validate("4d6r<3").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 2, 5, 3, 6, 2, 4]);
+        let result = roll_expression(&expression, &mut rng);
+        let values: Vec<i64> = result.rolls[0].dice.iter().map(|die| die.value).collect();
+        assert_eq!(values, vec![5, 3, 6, 4]);
+        assert_eq!(result.rolls[0].dice[0].rerolled, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_reroll_impossible_condition() {
+        let err = execute_roll(&["d6r<7".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("matches every face"));
+        let err = execute_roll(&["d1r1".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("matches every face"));
+        assert!(execute_roll(&["d6r<6".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn test_minimum_clamps_each_die() {
+        let expression = parse_and_validate("4d6min2").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 2, 5, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        let dice = &result.rolls[0].dice;
+        let values: Vec<i64> = dice.iter().map(|die| die.value).collect();
+        assert_eq!(values, vec![2, 2, 5, 2]);
+        assert_eq!(dice[0].unclamped, Some(1));
+        assert_eq!(dice[1].unclamped, None);
+        assert_eq!(result.total(), 11);
+        assert_eq!(
+            format_die_value(&dice[0], &result.rolls[0].request),
+            "1 → 2"
+        );
+        assert_eq!(format_die_value(&dice[2], &result.rolls[0].request), "5");
+    }
+
+    #[test]
+    fn test_minimum_above_sides() {
+        let err = execute_roll(&["8d6min7".to_string()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Minimum 7 is higher than every face of a d6")
+        );
+        assert!(execute_roll(&["8d6min6".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn test_maximum_clamps_last() {
+        let expression = parse_and_validate("3d10max8").unwrap();
+        let mut rng = ScriptedRng::new(&[10, 3, 9]);
+        let result = roll_expression(&expression, &mut rng);
+        let values: Vec<i64> = result.rolls[0].dice.iter().map(|die| die.value).collect();
+        assert_eq!(values, vec![8, 3, 8]);
+        assert_eq!(result.total(), 19);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(format_die_value(die, &result.rolls[0].request), "10 → 8");
+
+        // The cap applies to the whole exploded chain, after rerolls.
+        let expression = parse_and_validate("1d6!r1max8").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 6, 4]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.rerolled, vec![1]);
+        assert_eq!(die.rolls, vec![6, 4]);
+        assert_eq!(die.value, 8);
+        assert_eq!(
+            format_die_value(die, &result.rolls[0].request),
+            "6+4 = 10 → 8"
+        );
+    }
+
+    #[test]
+    fn test_maximum_out_of_range() {
+        let err = execute_roll(&["3d10max0".to_string()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Maximum 0 is lower than every face")
+        );
+        let err = execute_roll(&["3d10max11".to_string()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Maximum 11 is higher than every face")
+        );
+        let err = execute_roll(&["3d10min5max3".to_string()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Minimum 5 is higher than maximum 3")
+        );
+    }
+
+    #[test]
+    fn test_crit_success_flags_kept_die() {
+        let expression = parse_and_validate("1d20acs>=19").unwrap();
+        let mut rng = ScriptedRng::new(&[19, 4]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(crit_tag(die, &result.rolls[0].request), Some("CRIT"));
+        assert_eq!(result.total(), 19);
+
+        // Only the kept die counts, so a discarded 20 is no crit.
+        let mut rng = ScriptedRng::new(&[20, 4]);
+        let expression = parse_and_validate("1d20dcs").unwrap();
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.value, 4);
+        assert_eq!(crit_tag(die, &result.rolls[0].request), None);
+
+        let output = execute_roll(&["1d1cs".to_string()]).unwrap();
+        assert!(output.contains("1 CRIT"));
+    }
+
+    #[test]
+    fn test_crit_failure_flags_kept_die() {
+        let expression = parse_and_validate("1d20cf").unwrap();
+        let mut rng = ScriptedRng::new(&[1]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(crit_tag(die, &result.rolls[0].request), Some("FUMBLE"));
+
+        // A 1 dropped by advantage is not a fumble.
+        let expression = parse_and_validate("1d20acf<=1").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 12]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!(die.value, 12);
+        assert_eq!(die.alternates, vec![1]);
+        assert_eq!(crit_tag(die, &result.rolls[0].request), None);
+
+        let output = execute_roll(&["1d1cf".to_string()]).unwrap();
+        assert!(output.contains("1 FUMBLE"));
+    }
+
+    #[test]
+    fn test_split_items() {
+        assert_eq!(split_items("1d20"), vec!["1d20"]);
+        assert_eq!(
+            split_items("1d20,2d6+3,4d6dl1"),
+            vec!["1d20", "2d6+3", "4d6dl1"]
+        );
+        assert_eq!(
+            split_items("d{1,2,3},d{a,\"b,c\"}"),
+            vec!["d{1,2,3}", "d{a,\"b,c\"}"]
+        );
+    }
+
+    #[test]
+    fn test_comma_separated_arguments() {
+        let joined = execute_roll(&["1d1,2d1+3:x,4d1dl1".to_string()]).unwrap();
+        let separate = execute_roll(&[
+            "1d1".to_string(),
+            "2d1+3:x".to_string(),
+            "4d1dl1".to_string(),
+        ])
+        .unwrap();
+        assert_eq!(joined, separate);
+
+        let err = execute_roll(&["1d20, 2d0".to_string()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: item 2: Dice cannot have 0 or fewer sides."
+        );
+        let err = execute_roll(&["1d20,".to_string()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Error: item 2: Failed to parse")
+        );
+    }
+
+    #[test]
+    fn test_zero_count() {
+        let output = execute_roll(&["0d6".to_string()]).unwrap();
+        assert!(output.contains("| d6  | 0d6 → 0 |"));
+        assert!(!output.contains("Total"));
+
+        let args = ["1d4+0d6+2".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[3])).unwrap();
+        assert!(output.contains("| d6       | 0d6 → 0 |"));
+        assert!(output.contains("| Total    | 5 "));
+
+        // A rolled count of zero shows the same row.
+        let args = ["(1d{0})d6".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[0])).unwrap();
+        assert!(output.contains("| count | 1d{0} → 0 |"));
+        assert!(output.contains("| d6    | 0d6 → 0   |"));
+
+        let err = parse_and_validate("0d6kh1").unwrap_err();
+        assert!(err.to_string().contains("Cannot keep"));
+    }
+
+    #[test]
+    fn test_matching_sets() {
+        let (rem, res) = parse_dice_expression("6d6m").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.matching, Some(None));
+        assert_eq!(die_label(&res), "d6m");
+        let (_, res) = parse_dice_expression("6d6min2").unwrap();
+        assert_eq!((res.minimum, res.matching), (Some(2), None));
+        let (rem, res) = parse_dice_expression("6d6max5m2").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!((res.maximum, res.matching), (Some(5), Some(Some(2))));
+
+        let args = ["6d6m".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[4, 2, 4, 1, 4, 2]),
+        )
+        .unwrap();
+        assert!(output.contains("| Matches | three 4s "));
+
+        let args = ["6d6m2".to_string(), "3d6m".to_string()];
+        let mut rng = ScriptedRng::new(&[4, 2, 4, 1, 4, 2, 1, 2, 3]);
+        let output = execute_roll_with(&args, &Options::default(), &mut rng).unwrap();
+        assert!(output.contains("| Matches          | three 4s, two 2s "));
+        assert!(output.contains("| Matches          | no matches "));
+
+        assert!(parse_and_validate("6d6m1").is_err());
+    }
+
+    #[test]
+    fn test_step_dice() {
+        let stepped = |input: &str| {
+            let (rem, res) = parse_expression(input).unwrap();
+            assert_eq!(rem, "");
+            let sides: Vec<i64> = res.root.dice().iter().map(|req| req.sides).collect();
+            (sides, res.root.modifier())
+        };
+        assert_eq!(stepped("1d8step"), (vec![10], Some(0)));
+        assert_eq!(stepped("1d8step2"), (vec![12], Some(0)));
+        assert_eq!(stepped("1d6step-1"), (vec![4], Some(0)));
+        assert_eq!(stepped("1d10step3"), (vec![12], Some(2)));
+        assert_eq!(stepped("1d4step-2+1"), (vec![4], Some(-1)));
+        assert_eq!(stepped("2d12step1"), (vec![12], Some(2)));
+
+        // The bonus stays with the die it came from.
+        let (_, res) = parse_expression("2*1d12step").unwrap();
+        assert_eq!(res.root.evaluate(&mut [5].into_iter()), 12);
+    }
+
+    #[test]
+    fn test_step_off_chain() {
+        let err = parse_and_validate("1+d20step").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Only a fixed number of d4, d6, d8, d10, d12 can be stepped in '1+d20step'.\n  1+d20step\n    ^"
+        );
+        assert!(parse_and_validate("4dFstep").is_err());
+        assert!(parse_and_validate("(1d4)d6step").is_err());
+    }
+
+    #[test]
+    fn test_config_step_chain() {
+        CONFIG_STEP_CHAIN.set(Some(vec![4, 6, 8, 10, 12, 20]));
+        let (_, res) = parse_expression("1d12step2").unwrap();
+        assert_eq!(res.root.dice()[0].sides, 20);
+        assert_eq!(res.root.modifier(), Some(1));
+        let err = parse_and_validate("1d3step").unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "Error: Only a fixed number of d4, d6, d8, d10, d12, d20 can be stepped"
+            )
+        );
+        CONFIG_STEP_CHAIN.set(None);
+    }
+
+    #[test]
+    fn test_subtracted_dice() {
+        let (_, res) = parse_expression("1d20+5-1d4").unwrap();
+        assert_eq!(res.root.modifier(), Some(5));
+        assert_eq!(res.root.negated_dice(), vec![false, true]);
+        let (_, res) = parse_expression("1d20-(1d4-1d6+2)").unwrap();
+        assert_eq!(res.root.modifier(), Some(-2));
+        assert_eq!(res.root.negated_dice(), vec![false, true, false]);
+
+        let args = ["1d20+5-1d4".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[12, 3])).unwrap();
+        assert!(output.contains("| d20      | 12 "));
+        assert!(output.contains("| -d4      | 3  "));
+        assert!(output.contains("| Modifier | +5 "));
+        assert!(output.contains("| Total    | 14 "));
+
+        // Signed math carries into the grand total.
+        let args = ["1d4-2d6".to_string(), "1d4".to_string()];
+        let mut rng = ScriptedRng::new(&[1, 5, 6, 2]);
+        let output = execute_roll_with(&args, &Options::default(), &mut rng).unwrap();
+        assert!(output.contains("| Subtotal (1d4-2d6) | -10 "));
+        assert!(output.contains("| Total              | -8 "));
+    }
+
+    #[test]
+    fn test_each_modifier() {
+        let (rem, res) = parse_dice_expression("3d6e+1").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.each, Some(1));
+        assert_eq!(die_label(&res), "d6e+1");
+        let (_, res) = parse_dice_expression("3d6e-2").unwrap();
+        assert_eq!(die_label(&res), "d6e-2");
+
+        // Both kinds of modifier: each die gets +1, then the roll gets +2.
+        let args = ["3d6e+1+2".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[1, 4, 6]),
+        )
+        .unwrap();
+        assert!(output.contains("| d6e+1    | 1 → 2 "));
+        assert!(output.contains("| d6e+1    | 6 → 7 "));
+        assert!(output.contains("| Total    | 16 "));
+    }
+
+    #[test]
+    fn test_each_modifier_counts_adjusted_successes() {
+        let expression = parse_and_validate("3d6e+1>=6").unwrap();
+        let mut rng = ScriptedRng::new(&[5, 4, 6]);
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.total(), 2);
+
+        // Every roll of an exploding chain is adjusted before counting.
+        let expression = parse_and_validate("1d6!e+1t7").unwrap();
+        let mut rng = ScriptedRng::new(&[6, 6, 2]);
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.rolls[0].dice[0].value, 15);
+        assert_eq!(result.total(), 2);
+
+        assert!(parse_and_validate("1d{a,b}e+1").is_err());
+    }
+
+    #[test]
+    fn test_floor_suffix() {
+        let (rem, res) = parse_expression("1d4-3~0:damage").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.floor, Some(0));
+        let (_, res) = parse_expression("1d4-3 floor").unwrap();
+        assert_eq!(res.floor, Some(0));
+        let (_, res) = parse_expression("1d4-3~-1").unwrap();
+        assert_eq!(res.floor, Some(-1));
+
+        let args = ["1d4-3~0".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[1])).unwrap();
+        assert!(output.contains("| Subtotal | -2 → 0 |"));
+        assert!(output.contains("| Total    | 0      |"));
+
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[4])).unwrap();
+        assert!(!output.contains("→"));
+
+        // Each expression is floored on its own, not the grand total.
+        let args = ["1d4-3~0".to_string(), "1d4-3".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[1, 1])).unwrap();
+        assert!(output.contains("| Subtotal (1d4-3) | -2 → 0 |"));
+        assert!(output.contains("| Total            | -2     |"));
+    }
+
+    #[test]
+    fn test_hash_repeat_prefix() {
+        let (rem, res) = parse_expression("3#1d20+5:attack").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.repeat, 3);
+        assert_eq!(res.label.as_deref(), Some("attack"));
+
+        // With a space, `#` starts a comment instead.
+        let (rem, res) = parse_expression("1d20 # 3#").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.repeat, 1);
+
+        let args = ["2#1d20a+5:attack".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[3, 9, 14, 2]),
+        )
+        .unwrap();
+        assert!(output.contains("| attack 1 | Subtotal 1 | 14 "));
+        assert!(output.contains("| attack 2 | Subtotal 2 | 19 "));
+
+        let vars = variables(&[("str", 3)]);
+        assert_eq!(
+            substitute_variables("2#1d20+str", &vars).unwrap(),
+            "2#1d20+3"
+        );
+
+        let err = parse_and_validate("0#1d20").unwrap_err();
+        assert!(err.to_string().contains("Must repeat at least once"));
+    }
+
+    #[test]
+    fn test_best_and_worst_of() {
+        let res = parse_and_validate("bo2(2d6+3):damage").unwrap();
+        assert_eq!(res.pick, Some(Pick::Best(2)));
+        assert_eq!(res.label.as_deref(), Some("damage"));
+
+        let args = ["bo2(2d6+3)".to_string(), "1d4".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[2, 3, 6, 1, 4]),
+        )
+        .unwrap();
+        assert!(output.contains("| Attempt 1 | (8)  |"));
+        assert!(output.contains("| Attempt 2 | 10   |"));
+        assert!(output.contains("| Total     | 14   |"));
+
+        // Ties keep the first attempt.
+        let args = ["wo2(1d20)".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[7, 7])).unwrap();
+        assert!(output.contains("| Attempt 1 | 7    |"));
+        assert!(output.contains("| Attempt 2 | (7)  |"));
+
+        let err = parse_and_validate("bo1(1d20)").unwrap_err();
+        assert!(err.to_string().contains("'bo1' needs at least 2 attempts"));
+        let vars = variables(&[("str", 3)]);
+        assert_eq!(
+            substitute_variables("bo2(1d20+str)", &vars).unwrap(),
+            "bo2(1d20+3)"
+        );
+    }
+
+    #[test]
+    fn test_pasted_expressions() {
+        let pasted = parse_pasted(0, "1d20+5 2d6 + 3 1d4").unwrap();
+        let separate: Vec<Expression> = ["1d20+5", "2d6+3", "1d4"]
+            .iter()
+            .map(|s| parse_and_validate(s).unwrap())
+            .collect();
+        assert_eq!(pasted, separate);
+        // Anything that parses whole is left alone.
+        assert_eq!(parse_pasted(0, "1d20+7 vs 1d20+4").unwrap().len(), 2);
+        assert_eq!(parse_pasted(0, "1d20dis lucky").unwrap().len(), 1);
+
+        let err = parse_pasted(1, "1d20+5 2d6+3 1dx").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Error: argument 2, piece 3: Invalid dice format '1dx'")
+        );
+        let err = parse_pasted(0, "1dx").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Error: Invalid dice format '1dx'")
+        );
+    }
+
+    #[test]
+    fn test_split_versus() {
+        assert_eq!(
+            split_versus("1d20+7 vs 1d20+4"),
+            Some(("1d20+7 ", " 1d20+4"))
+        );
+        assert_eq!(split_versus("1d20 VS 1d20"), Some(("1d20 ", " 1d20")));
+        assert_eq!(split_versus("d{vs ,x} + 1"), None);
+        assert_eq!(split_versus("1d20 # me vs you"), None);
+        assert_eq!(split_versus("1d20+1:vs"), None);
+    }
+
+    #[test]
+    fn test_opposed_rolls() {
+        let args = ["1d20+7 vs 1d20+4".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[10, 12]))
+                .unwrap();
+        assert!(output.contains("| Side A | Subtotal (1d20+7) | 17 "));
+        assert!(output.contains("| Side B | Subtotal (1d20+4) | 16 "));
+        assert!(output.contains("|        | Outcome           | Side A wins by 1 |"));
+        assert!(!output.contains("Total"));
+
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[9, 12])).unwrap();
+        assert!(output.contains("| Tie at 16 |"));
+
+        let args = ["1d20+2:grapple vs 1d20+5:escape".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[10, 12]))
+                .unwrap();
+        assert!(output.contains("| escape wins by 5 |"));
+
+        let vars = variables(&[("str", 3)]);
+        assert_eq!(
+            substitute_variables("1d20+str vs str", &vars).unwrap(),
+            "1d20+3 vs 3"
+        );
+
+        let err = execute_roll(&["2x1d20 vs 1d20".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("must be rolled once"));
+    }
+
+    #[test]
+    fn test_parse_check() {
+        let (rem, res) = parse_expression("1d20+5>=15:save").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.check,
+            Some(Comparison {
+                op: CompareOp::Ge,
+                value: 15,
+            })
+        );
+        assert_eq!(res.label.as_deref(), Some("save"));
+
+        let vars = variables(&[("dc", 15)]);
+        assert_eq!(
+            substitute_variables("1d20+5>=dc", &vars).unwrap(),
+            "1d20+5>=15"
+        );
+
+        // A lone die is checked too; a pool takes the comparison as its
+        // own target unless something comes before it.
+        let (_, res) = parse_expression("1d20>=15").unwrap();
+        assert_eq!(res.check, Some(parse_threshold(">=15").unwrap()));
+        assert!(res.root.dice()[0].success.is_none());
+        let (_, res) = parse_expression("6d6>=5").unwrap();
+        assert_eq!(res.check, None);
+        assert!(res.root.dice()[0].success.is_some());
+
+        // Bless: the d4 is added before the check.
+        let (rem, res) = parse_expression("1d20+1d4>=15").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.check, Some(parse_threshold(">=15").unwrap()));
+        assert!(res.root.dice().iter().all(|req| req.success.is_none()));
+        let (_, res) = parse_expression("1d20+3d6>=4").unwrap();
+        assert_eq!(res.check, Some(parse_threshold(">=4").unwrap()));
+        let (_, res) = parse_expression("2d6t5+3d6t4").unwrap();
+        assert_eq!(res.check, None);
+        assert_eq!(res.to_string(), "2d6>=5+3d6t4");
+
+        // Targets that would read back as checks are spelled with `t`.
+        for input in ["1d6t4", "1d6t<=2", "6d6>=5", "1d6t4+6d6t5"] {
+            let res = validate_expression(input).unwrap();
+            assert_eq!(res.to_string(), input);
+            assert_eq!(validate_expression(&res.to_string()).unwrap(), res);
+        }
+
+        let rng = &mut ScriptedRng::new(&[12, 3]);
+        let output =
+            execute_roll_with(&["1d20+1d4>=15".to_string()], &Options::default(), rng).unwrap();
+        assert!(
+            output.contains("| Check >=15 | 15   | PASS (0) |"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn test_check_outcome() {
+        let args = ["1d20+5>=15".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[13])).unwrap();
+        assert!(output.contains("| Die        | Roll | Check     |"));
+        assert!(output.contains("| Check >=15 | 18   | PASS (+3) |"));
+
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[8])).unwrap();
+        assert!(output.contains("| 13   | FAIL (-2) |"));
+
+        // Below-target checks count the margin the other way.
+        let args = ["1d6+1<=4".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[2])).unwrap();
+        assert!(output.contains("| 3    | PASS (+1) |"));
+    }
+
+    #[test]
+    fn test_check_flags_natural_rolls() {
+        let args = ["1d20+1>=25".to_string(), "1d6".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[20, 4])).unwrap();
+        assert!(output.contains("| FAIL (-4), NAT 20 |"));
+
+        // The dropped 1 under advantage isn't natural 1 for the check.
+        let args = ["1d20a+30>=10".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[1, 9])).unwrap();
+        assert!(output.contains("| PASS (+29) |"));
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[1, 1])).unwrap();
+        assert!(output.contains("| PASS (+21), NAT 1 |"));
+    }
+
+    #[test]
+    fn test_parse_lucky() {
+        let (rem, res) = parse_dice_expression("1d20a lucky").unwrap();
+        assert_eq!(rem, "");
+        assert!(res.lucky);
+        assert_eq!(res.mode, RollMode::Advantage(2));
+        assert_eq!(die_label(&res), "d20alucky");
+
+        let (rem, res) = parse_dice_expression("1d20alucky").unwrap();
+        assert_eq!(rem, "");
+        assert!(res.lucky);
+        assert!(parse_and_validate("1dFlucky").is_err());
+    }
+
+    #[test]
+    fn test_lucky_rerolls_natural_one() {
+        let mut rng = ScriptedRng::new(&[1, 1]);
+        let output =
+            execute_roll_with(&["1d20lucky".to_string()], &Options::default(), &mut rng).unwrap();
+        assert!(output.contains("| 1 (luck: rerolled 1) "));
+
+        let mut rng = ScriptedRng::new(&[2]);
+        let output =
+            execute_roll_with(&["1d20lucky".to_string()], &Options::default(), &mut rng).unwrap();
+        assert!(!output.contains("luck:"));
+    }
+
+    #[test]
+    fn test_lucky_before_disadvantage() {
+        // The 1 is rerolled into a 15 before the lower die is picked, so the
+        // 8 is kept rather than the reroll being wasted on a die thrown away.
+        let expression = parse_and_validate("1d20dis lucky").unwrap();
+        let mut rng = ScriptedRng::new(&[1, 15, 8]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!((die.value, die.alternates.clone()), (8, vec![15]));
+        assert!(die.luck);
+    }
+
+    #[test]
+    fn test_brutal_bonus_dice() {
+        let (rem, res) = parse_dice_expression("1d12b").unwrap();
+        assert_eq!(rem, "");
+        assert!(res.brutal);
+        assert_eq!(die_label(&res), "d12b");
+
+        // Two 12s add two bonus dice; the bonus 12 doesn't add another.
+        let args = ["3d12b+2".to_string()];
+        let mut rng = ScriptedRng::new(&[12, 5, 12, 12, 3]);
+        let output = execute_roll_with(&args, &Options::default(), &mut rng).unwrap();
+        let bonus: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("| d12b* "))
+            .collect();
+        assert_eq!(bonus.len(), 2);
+        assert!(output.contains("| Total    | 46 "));
+    }
+
+    #[test]
+    fn test_brutal_after_keep() {
+        // The dropped 8 doesn't earn a bonus die, and the bonus die is kept.
+        let expression = parse_and_validate("2d8kl1b").unwrap();
+        let mut rng = ScriptedRng::new(&[8, 8, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        let dice: Vec<(i64, bool, bool)> = result.rolls[0]
+            .dice
+            .iter()
+            .map(|die| (die.value, die.kept, die.bonus))
+            .collect();
+        assert_eq!(
+            dice,
+            vec![(8, true, false), (8, false, false), (1, true, true)]
+        );
+        assert_eq!(result.total(), 9);
+
+        assert!(parse_and_validate("1d12!b").is_err());
+        assert!(parse_and_validate("1d66b").is_err());
+    }
+
+    #[test]
+    fn test_great_weapon_fighting() {
+        let (rem, res) = parse_dice_expression("2d6gwf").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.reroll,
+            Some(Reroll::Once(Comparison {
+                op: CompareOp::Le,
+                value: 2,
+            }))
+        );
+        assert_eq!(die_label(&res), "d6ro<=2");
+
+        // The first die rerolls a 1 and lands on another 1, which stands.
+        let args = ["2d6gwf+4".to_string()];
+        let mut rng = ScriptedRng::new(&[1, 1, 2, 6]);
+        let output = execute_roll_with(&args, &Options::default(), &mut rng).unwrap();
+        assert!(output.contains("| 1 (rerolled 1) "));
+        assert!(output.contains("| 6 (rerolled 2) "));
+        assert!(output.contains("| Total    | 11 "));
+    }
+
+    #[test]
+    fn test_wild_die() {
+        let (rem, res) = parse_dice_expression("1d8!w").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.wild, Some(6));
+        assert_eq!(die_label(&res), "d8!w");
+        let (_, res) = parse_dice_expression("1d4w8").unwrap();
+        assert_eq!(die_label(&res), "d4w8");
+
+        // The d8 shows 3; the wild d6 explodes 6 + 2 and wins.
+        let expression = parse_and_validate("1d8!w+1").unwrap();
+        let mut rng = ScriptedRng::new(&[3, 6, 2]);
+        let result = roll_expression(&expression, &mut rng);
+        let die = &result.rolls[0].dice[0];
+        assert_eq!((die.value, die.alternates.clone()), (8, vec![3]));
+        assert_eq!(result.total(), 9);
+
+        // Ties go to the trait die.
+        let mut rng = ScriptedRng::new(&[4, 4]);
+        let result = roll_expression(&parse_and_validate("1d8w").unwrap(), &mut rng);
+        assert_eq!(result.rolls[0].dice[0].rolls, vec![4]);
+    }
+
+    #[test]
+    fn test_wild_die_snake_eyes() {
+        let mut rng = ScriptedRng::new(&[1, 1]);
+        let output =
+            execute_roll_with(&["1d8!w".to_string()], &Options::default(), &mut rng).unwrap();
+        assert!(output.contains("| 1 (1) SNAKE EYES "));
+
+        let mut rng = ScriptedRng::new(&[1, 2]);
+        let output =
+            execute_roll_with(&["1d8!w".to_string()], &Options::default(), &mut rng).unwrap();
+        assert!(!output.contains("SNAKE EYES"));
+
+        let err = parse_and_validate("2d8w").unwrap_err();
+        assert!(err.to_string().contains("single trait die"));
+        let err = parse_and_validate("1d8aw").unwrap_err();
+        assert!(err.to_string().contains("plain trait die"));
+        assert!(parse_and_validate("1d8w0").is_err());
+    }
+
+    #[test]
+    fn test_unique_rerolls_duplicates() {
+        let expression = parse_and_validate("4d20u").unwrap();
+        assert_eq!(die_label(expression.root.dice()[0]), "d20u");
+        let mut rng = ScriptedRng::new(&[5, 5, 9, 5, 9, 12, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        let values: Vec<i64> = result.rolls[0].dice.iter().map(|die| die.value).collect();
+        assert_eq!(values, vec![5, 9, 12, 1]);
+        assert_eq!(result.rolls[0].dice[1].duplicates, vec![5]);
+        assert_eq!(result.rolls[0].dice[2].duplicates, vec![5, 9]);
+
+        let output = execute_roll_with(
+            &["2d20u".to_string()],
+            &Options::default(),
+            &mut ScriptedRng::new(&[7, 7, 3]),
+        )
+        .unwrap();
+        assert!(output.contains("| 3 (duplicate 7) "));
+    }
+
+    #[test]
+    fn test_unique_needs_enough_faces() {
+        let err = parse_and_validate("7d6u").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Cannot roll 7 unique values on a d6, which has only 6 faces."
+        );
+        assert!(parse_and_validate("6d6u").is_ok());
+        assert!(parse_and_validate("3d{1,1,2}u").is_err());
+        assert!(parse_and_validate("3dFu").is_ok());
+    }
+
+    #[test]
+    fn test_unique_reroll_is_capped() {
+        // Clamping can leave too few values, so the retry cap has to stop it.
+        let expression = parse_and_validate("2d6max1u").unwrap();
+        let mut rng = rand::thread_rng();
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.rolls[0].dice[1].duplicates.len(), MAX_REROLLS);
+    }
+
+    #[test]
+    fn test_parse_sort() {
+        let (rem, res) = parse_dice_expression("10d6sd").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.sort, Some(Sort::Descending));
+        assert_eq!(die_label(&res), "d6sd");
+
+        let (rem, res) = parse_dice_expression("8d10>=7S").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(res.sort, Some(Sort::Ascending));
+        assert_eq!(die_label(&res), "d10>=7s");
+    }
+
+    #[test]
+    fn test_sort_orders_printed_dice() {
+        let args = ["3d6sd".to_string(), "2d6".to_string(), "3d6s".to_string()];
+        let mut rng = ScriptedRng::new(&[2, 6, 4, 5, 1, 3, 1, 2]);
+        let output = execute_roll_with(&args, &Options::default(), &mut rng).unwrap();
+        let rolls: Vec<&str> = output
+            .lines()
+            .filter(|line| {
+                line.split('|')
+                    .nth(2)
+                    .is_some_and(|die| die.trim().starts_with("d6"))
+            })
+            .map(|line| line.split('|').nth(3).unwrap().trim())
+            .collect();
+        assert_eq!(rolls, vec!["6", "4", "2", "5", "1", "1", "2", "3"]);
+        assert!(
+            output
+                .lines()
+                .any(|line| line.contains("| Total") && line.contains(" 24 "))
+        );
+
+        // Dropped dice keep their marks once sorted.
+        let expression = parse_and_validate("4d6dl1s").unwrap();
+        let mut rng = ScriptedRng::new(&[5, 2, 6, 3]);
+        let result = roll_expression(&expression, &mut rng);
+        let dice: Vec<(i64, bool)> = result.rolls[0]
+            .dice
+            .iter()
+            .map(|die| (die.value, die.kept))
+            .collect();
+        assert_eq!(dice, vec![(2, false), (3, true), (5, true), (6, true)]);
+        assert_eq!(result.total(), 14);
+    }
+
+    #[test]
+    fn test_nested_count() {
+        let expression = parse_and_validate("(1d4)d6").unwrap();
+        let mut rng = ScriptedRng::new(&[3, 1, 2, 3]);
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.rolls[0].count_roll.as_ref().unwrap().total(), 3);
+        assert_eq!(result.rolls[0].dice.len(), 3);
+        assert_eq!(result.total(), 6);
+
+        let output = execute_roll(&["(3d1)d1".to_string()]).unwrap();
+        assert!(output.contains("| count | 3d1 → 3 "));
+        assert!(output.contains("| Total | 3 "));
+    }
+
+    #[test]
+    fn test_nested_count_limit() {
+        let err = execute_roll(&["(1000d1000)d6".to_string()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: '(1000d1000)d6' could roll up to 1000000 dice; the limit is 10000."
+        );
+        assert!(execute_roll(&["(10d1000)d6".to_string()]).is_ok());
+
+        let err = execute_roll(&["(1d0)d6".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("0 or fewer sides"));
+        let err = execute_roll(&["(1d{-1,2})d6".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("cannot be negative"));
+        let err = execute_roll(&["20000d6".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("more than 10000 dice"));
+    }
+
+    #[test]
+    fn test_fate_dice() {
+        let expression = parse_and_validate("4dF+2").unwrap();
+        let mut rng = ScriptedRng::new(&[-1, -1, 0, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.total(), 1);
+        let symbols: Vec<String> = result.rolls[0]
+            .dice
+            .iter()
+            .map(|die| format_die_value(die, &result.rolls[0].request))
+            .collect();
+        assert_eq!(symbols, vec!["-", "-", "0", "+"]);
+
+        let expression = parse_and_validate("4dF-1").unwrap();
+        let mut rng = ScriptedRng::new(&[-1, -1, -1, 0]);
+        assert_eq!(roll_expression(&expression, &mut rng).total(), -4);
+    }
+
+    #[test]
+    fn test_digit_dice() {
+        let expression = parse_and_validate("2d66").unwrap();
+        let mut rng = ScriptedRng::new(&[3, 5, 6, 1]);
+        let result = roll_expression(&expression, &mut rng);
+        let dice = &result.rolls[0].dice;
+        assert_eq!(dice[0].value, 35);
+        assert_eq!(dice[1].value, 61);
+        assert_eq!(
+            format_die_value(&dice[0], &result.rolls[0].request),
+            "3, 5 → 35"
+        );
+        assert_eq!(result.total(), 96);
+        assert_eq!(result.summable_total(), 0);
+        assert!(!result.has_summable());
+    }
+
+    #[test]
+    fn test_digit_dice_excluded_from_total() {
+        let output = execute_roll(&["d66".to_string(), "d66".to_string()]).unwrap();
+        assert!(!output.contains("Total"));
+
+        let output = execute_roll(&["d66".to_string(), "1d1+2".to_string()]).unwrap();
+        assert!(output.contains("Total"));
+        assert!(output.contains("| 3 "));
+
+        let err = execute_roll(&["d66!".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("cannot explode"));
+    }
+
+    #[test]
+    fn test_custom_faces() {
+        let expression = parse_and_validate("3d{0,0,1,1,2,4}").unwrap();
+        let mut rng = ScriptedRng::new(&[5, 0, 3]);
+        let result = roll_expression(&expression, &mut rng);
+        let values: Vec<i64> = result.rolls[0].dice.iter().map(|die| die.value).collect();
+        assert_eq!(values, vec![4, 0, 1]);
+        assert_eq!(result.total(), 5);
+    }
+
+    #[test]
+    fn test_text_faces() {
+        let expression = parse_and_validate("2d{yes,no,maybe}").unwrap();
+        let mut rng = ScriptedRng::new(&[2, 0]);
+        let result = roll_expression(&expression, &mut rng);
+        let words: Vec<String> = result.rolls[0]
+            .dice
+            .iter()
+            .map(|die| format_die_value(die, &result.rolls[0].request))
+            .collect();
+        assert_eq!(words, vec!["maybe", "yes"]);
+        assert!(!result.has_summable());
+    }
+
+    #[test]
+    fn test_text_faces_mixed_with_numbers() {
+        let output = execute_roll(&["d{yes,no}".to_string(), "2d1+1".to_string()]).unwrap();
+        assert!(output.contains("d{yes,no}"));
+        assert!(output.contains("Total"));
+        assert!(output.contains("| 3 "));
+
+        let err = execute_roll(&["2d{yes,no}kh1".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Text dice cannot"));
+    }
+
+    #[test]
+    fn test_custom_faces_empty() {
+        let err = execute_roll(&["d{}".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("at least one face"));
+    }
+
+    #[test]
+    fn test_success_counting() {
+        let expression = parse_and_validate("8d10>=7").unwrap();
+        let mut rng = ScriptedRng::new(&[7, 1, 10, 6, 3, 9, 2, 7]);
+        let result = roll_expression(&expression, &mut rng);
+        assert!(result.is_pool());
+        assert_eq!(result.total(), 4);
+        assert_eq!(format_successes(result.total()), "4 successes");
+        assert_eq!(format_successes(1), "1 success");
+    }
+
+    #[test]
+    fn test_success_counting_output() {
+        let output = execute_roll(&["4d1>=1".to_string()]).unwrap();
+        assert!(output.contains("1 ✓"));
+        assert!(output.contains("4 successes"));
+        assert!(!output.contains("Total"));
+
+        let output = execute_roll(&["4d1>=1".to_string(), "2d1>1".to_string()]).unwrap();
+        assert!(output.contains("0 successes"));
+        assert!(output.contains("Total"));
+    }
+
+    #[test]
+    fn test_failures_subtract_successes() {
+        let expression = parse_and_validate("6d10>=7f1").unwrap();
+        let mut rng = ScriptedRng::new(&[8, 1, 1, 5, 1, 3]);
+        let result = roll_expression(&expression, &mut rng);
+        assert_eq!(result.total(), -2);
+        assert_eq!(format_successes(result.total()), "-2 successes (botch)");
+
+        let output = execute_roll(&["2d1>=2f1".to_string()]).unwrap();
+        assert!(output.contains("1 ✗"));
+        assert!(output.contains("botch"));
+    }
+
+    #[test]
+    fn test_pool_totals() {
+        let output = execute_roll(&["3d1t1".to_string(), "2d1t1".to_string()]).unwrap();
+        assert!(output.contains("| Total "));
+        assert!(output.contains("5 successes"));
+
+        // Mixed pools and sums keep separate totals.
+        let output = execute_roll(&[
+            "3d1t1".to_string(),
+            "2d1t1".to_string(),
+            "2d1+5".to_string(),
+        ])
+        .unwrap();
+        assert!(output.contains("| Total            | 7 "));
+        assert!(output.contains("| Total successes  | 5 successes"));
+    }
+
+    #[test]
+    fn test_failure_without_target() {
+        let err = execute_roll(&["8d10f1".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("needs a success target"));
+    }
+
+    #[test]
+    fn test_multiplication_total() {
+        let expression = parse_and_validate("2d6*10+5").unwrap();
+        let mut rng = ScriptedRng::new(&[3, 4]);
+        assert_eq!(roll_expression(&expression, &mut rng).total(), 75);
+
+        let output = execute_roll(&["2d1*100".to_string()]).unwrap();
+        assert!(output.contains("| Formula | 2 × 100 = 200"));
+    }
+
+    #[test]
+    fn test_division_total() {
+        let expression = parse_and_validate("2d6/2+1").unwrap();
+        let mut rng = ScriptedRng::new(&[3, 4]);
+        assert_eq!(roll_expression(&expression, &mut rng).total(), 4);
+
+        let output = execute_roll(&["3d1/^2".to_string()]).unwrap();
+        assert!(output.contains("3 /^ 2 = 2"));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let err = execute_roll(&["2d6/0".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("divide by zero"));
+        let err = execute_roll(&["2d6/(2-2)".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("divide by zero"));
+        let err = execute_roll(&["2d6/1d4".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("divide by a constant"));
+    }
+
+    #[test]
+    fn test_expression_without_dice() {
+        let err = execute_roll(&["2+3".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("has no dice"));
+    }
+
+    #[test]
+    fn test_multiplication_overflow() {
+        let err = execute_roll(&["1000d1000000*99999999999".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn test_keep_more_than_rolled() {
+        let err = execute_roll(&["4d6kh5".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Cannot keep 5 dice"));
+    }
+
+    #[test]
+    fn test_keep_lowest_invalid_counts() {
+        let err = execute_roll(&["2d20kl0".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Must keep at least one die"));
+        let err = execute_roll(&["2d20kl3".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Cannot keep 3 dice"));
+    }
+
+    #[test]
+    fn test_cli_accepts_hyphen_in_expression() {
+        let args = Args::try_parse_from(["roll", "1d20-2", "1d4-10"]).unwrap();
+        assert_eq!(args.dice, vec!["1d20-2", "1d4-10"]);
+    }
+
+    fn variables(pairs: &[(&str, i64)]) -> HashMap<String, i64> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value))
+            .collect()
+    }
+
+    #[test]
+    fn test_substitute_variables() {
+        let vars = variables(&[("prof", 3), ("str", 4), ("penalty", -2)]);
+        assert_eq!(
+            substitute_variables("1d20+prof+str", &vars).unwrap(),
+            "1d20+3+4"
+        );
+        assert_eq!(
+            substitute_variables("1d20 + penalty", &vars).unwrap(),
+            "1d20 + -2"
+        );
+        assert_eq!(
+            substitute_variables("(d6+str)*prof:str # prof", &vars).unwrap(),
+            "(d6+4)*3:str # prof"
+        );
+        assert_eq!(
+            substitute_variables("d{prof,str}+2d6kh1", &vars).unwrap(),
+            "d{prof,str}+2d6kh1"
+        );
+
+        let expression =
+            parse_and_validate(&substitute_variables("1d1-penalty", &vars).unwrap()).unwrap();
+        assert_eq!(expression.root.modifier(), Some(2));
+    }
+
+    #[test]
+    fn test_unknown_variable() {
+        let err = substitute_variables("1d20+dex", &variables(&[("str", 1)])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Unknown variable 'dex' in '1d20+dex'. Define it with --set dex=N."
+        );
+    }
+
+    #[test]
+    fn test_variables_shadowing_and_unused() {
+        let args = Args::try_parse_from([
+            "roll", "--set", "prof=2", "--set", "unused=9", "--set", "prof=3", "1d1+prof",
+        ])
+        .unwrap();
+        let vars = args.variables();
+        assert_eq!(vars["prof"], 3);
+        let dice = substitute_variables(&args.dice[0], &vars).unwrap();
+        assert_eq!(dice, "1d1+3");
+        let output = execute_roll(&[dice]).unwrap();
+        assert!(output.contains("| 4 "));
+    }
+
+    #[test]
+    fn test_parse_variable() {
+        assert_eq!(
+            parse_variable("penalty=-2"),
+            Ok(("penalty".to_string(), -2))
+        );
+        assert!(parse_variable("prof").is_err());
+        assert!(parse_variable("2x=1").is_err());
+        assert!(parse_variable("d6=1").is_err());
+        assert!(parse_variable("prof=three").is_err());
+    }
+
+    #[test]
+    fn test_join_unquoted_pieces() {
+        let expressions = |argv: &[&str]| {
+            let args =
+                Args::try_parse_from(iter::once("roll").chain(argv.iter().copied())).unwrap();
+            args.expressions()
+        };
+        assert_eq!(expressions(&["2d6", "+", "3"]), vec!["2d6 + 3"]);
+        assert_eq!(expressions(&["1d20", "-2"]), vec!["1d20 -2"]);
+        assert_eq!(expressions(&["2d6+", "3", "1d8"]), vec!["2d6+ 3", "1d8"]);
+        assert_eq!(
+            expressions(&["1d20", "*", "2", "/^", "3"]),
+            vec!["1d20 * 2 /^ 3"]
+        );
+        // Standalone expressions are left alone.
+        assert_eq!(expressions(&["2d6", "3d8"]), vec!["2d6", "3d8"]);
+        assert_eq!(expressions(&["2d6", "3"]), vec!["2d6", "3"]);
+
+        let joined = expressions(&["--set", "p=2", "1d1", "+", "p", "-", "1d4"]);
+        assert_eq!(joined, vec!["1d1 + p - 1d4"]);
+    }
+
+    #[test]
+    fn test_read_file() {
+        let dir = std::env::temp_dir().join(format!("roll-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let encounter = dir.join("encounter.txt");
+        let bad = dir.join("bad.txt");
+        fs::write(
+            &encounter,
+            "# goblins\n1d20+4:attack # shortbow\n\n1d6+dex\n",
+        )
+        .unwrap();
+        fs::write(&bad, "1d4\n\n1d20+5 2d6 1dx\n").unwrap();
+        let parse = |argv: &[&str]| {
+            let argv = iter::once("roll").chain(argv.iter().copied());
+            Args::try_parse_from(argv).unwrap()
+        };
+        let path = |path: &Path| path.to_str().unwrap().to_string();
+
+        let args = parse(&[
+            "--set",
+            "dex=2",
+            "-f",
+            &path(&encounter),
+            "-f",
+            &path(&encounter),
+            "1d8",
+        ]);
+        let dice = gather_expressions(&args, &args.variables(), &Config::default()).unwrap();
+        assert_eq!(
+            dice,
+            vec![
+                "1d20+4:attack # shortbow",
+                "1d6+2",
+                "1d20+4:attack # shortbow",
+                "1d6+2",
+                "1d8"
+            ]
+        );
+
+        let args = parse(&["-f", &path(&bad)]);
+        let err = gather_expressions(&args, &args.variables(), &Config::default()).unwrap_err();
+        let place = format!(
+            "Error: {}, line 3, piece 3: Invalid dice format",
+            bad.display()
+        );
+        assert!(err.to_string().starts_with(&place), "{}", err);
+        let args = parse(&["-f", &path(&encounter)]);
+        let err = gather_expressions(&args, &args.variables(), &Config::default()).unwrap_err();
+        let place = format!(
+            "Error: {}, line 4: Unknown variable 'dex'",
+            encounter.display()
+        );
+        assert!(err.to_string().starts_with(&place), "{}", err);
+        let args = parse(&["-f", &path(&dir.join("missing.txt"))]);
+        assert!(gather_expressions(&args, &args.variables(), &Config::default()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_expressions() {
+        let args = Args::try_parse_from(["roll", "-"]).unwrap();
+        assert!(args.reads_stdin());
+        let input = "# the party\n1d20+5 2d6\n\n  # fighter\n1d8 +\n3 # longsword\n3#1d6\n";
+        assert_eq!(
+            read_expressions(input.as_bytes()).unwrap(),
+            vec!["1d20+5", "2d6", "1d8 + 3", "3#1d6"]
+        );
+        let err = read_expressions("\n# nothing here\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "Error: No dice expressions on stdin.");
+    }
+
+    #[test]
+    fn test_explode_threshold() {
+        let (rem, res) = parse_dice_expression("d6!>=5").unwrap();
+        assert_eq!(rem, "");
+        assert_eq!(
+            res.explode_on,
+            Some(Comparison {
+                op: CompareOp::Ge,
+                value: 5,
+            })
+        );
+        assert_eq!(die_label(&res), "d6!>=5");
+        let (_, res) = parse_dice_expression("d10!!=1").unwrap();
+        assert_eq!(res.explode, Some(Explode::Compound));
+        assert_eq!(die_label(&res), "d10!!=1");
+        let (_, res) = parse_dice_expression("8d10!>=10>=7").unwrap();
+        assert_eq!(res.explode_on.unwrap().value, 10);
+        assert_eq!(res.success.unwrap().value, 7);
+
+        let args = ["2d6!>=5".to_string()];
+        let mut rng = ScriptedRng::new(&[5, 6, 2, 4]);
+        let output = execute_roll_with(&args, &Options::default(), &mut rng).unwrap();
+        assert!(output.contains("| d6!>=5 | 5+6+2 = 13 |"));
+        assert!(output.contains("| Total  | 17         |"));
+    }
+
+    #[test]
+    fn test_explode_threshold_validation() {
+        let err = parse_and_validate("d6!>=1").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Exploding on '>=1' would explode every face of a d6."
+        );
+        assert!(parse_and_validate("d6!<7").is_err());
+        assert!(parse_and_validate("d10!=1").is_ok());
+    }
+
+    #[test]
+    fn test_suggest() {
+        assert_eq!(suggest("1s20").as_deref(), Some("1d20"));
+        assert_eq!(suggest("1d2o").as_deref(), Some("1d20"));
+        assert_eq!(suggest("1D2O+5").as_deref(), Some("1D20+5"));
+        assert_eq!(suggest("1s2o").as_deref(), Some("1d20"));
+        assert_eq!(suggest("1d20"), None);
+        assert_eq!(suggest("hello"), None);
+        // `s` is only swapped between digits, so a sort suffix is left alone.
+        assert_eq!(suggest("4d6sx"), None);
+    }
+
+    #[test]
+    fn test_suggestion_in_error() {
+        let err = parse_and_validate("1s20").unwrap_err();
+        assert!(err.to_string().ends_with("\nDid you mean '1d20'?"));
+        let err = parse_and_validate("1d20q").unwrap_err();
+        assert!(!err.to_string().contains("Did you mean"));
+    }
+
+    #[test]
+    fn test_error_caret() {
+        let caret = |input: &str| {
+            let err = parse_and_validate(input).unwrap_err().to_string();
+            err.lines().skip(1).collect::<Vec<_>>().join("\n")
+        };
+        assert_eq!(
+            caret("1dx"),
+            "  1dx\n    ^ expected number of sides after 'd'"
+        );
+        assert_eq!(
+            caret("2d6+1d"),
+            "  2d6+1d\n        ^ expected number of sides after 'd'"
+        );
+        assert_eq!(
+            caret("1d20 +"),
+            "  1d20 +\n        ^ expected a number or dice after '+'"
+        );
+        assert_eq!(caret("1d20)"), "  1d20)\n      ^ no '(' to close");
+        assert_eq!(caret("1d20advx"), "  1d20advx\n         ^ unexpected 'x'");
+        assert_eq!(caret("abc"), "  abc\n  ^ unexpected 'a'");
+    }
+
+    #[test]
+    fn test_number_too_large() {
+        // i64::MAX itself parses, and is only then rejected for what it is.
+        let err = parse_and_validate("9223372036854775807d6").unwrap_err();
+        assert!(err.to_string().contains("Cannot roll more than"));
+        assert!(parse_expression("1d9223372036854775807").is_ok());
+        for input in [
+            "9223372036854775808d6",
+            "1d9223372036854775808",
+            "1d6+9223372036854775808",
+        ] {
+            let err = parse_and_validate(input).unwrap_err().to_string();
+            assert!(
+                err.contains("Number too large") && err.contains("(max 9223372036854775807)"),
+                "{}",
+                err
+            );
+            assert!(err.contains("^ number too large"));
+        }
+    }
+
+    #[test]
+    fn test_invalid_arg() {
+        let err = execute_roll(&["invalid".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Failed to parse dice expression"));
+    }
+
+    #[test]
+    fn test_partial_valid_arg() {
+        let err = execute_roll(&["1d20extra".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("Invalid dice format"));
+    }
+
+    #[test]
+    fn test_zero_sides() {
+        let err = execute_roll(&["2d0".to_string()]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Dice cannot have 0 or fewer sides")
+        );
+    }
+
+    #[test]
+    fn test_zero_based_dice() {
+        let res = parse_and_validate("2D10Z").unwrap();
+        let request = res.root.dice()[0];
+        assert_eq!(request.faces, Faces::ZeroBased);
+        assert_eq!((request.min_face(), request.max_face()), (0, 9));
+
+        let args = ["2d10z+1".to_string()];
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[0, 0])).unwrap();
+        assert!(output.contains("| d10z     | 0    |"));
+        assert!(output.contains("| Total    | 1    |"));
+
+        let err = parse_and_validate("d0z").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Dice cannot have 0 or fewer sides")
+        );
+    }
+
+    #[test]
+    fn test_percentile_dice() {
+        let res = parse_and_validate("d100c").unwrap();
+        assert_eq!(res.root.dice()[0].faces, Faces::Percentile);
+        // `cs` after a plain d100 is still a crit.
+        let res = parse_and_validate("d100cs").unwrap();
+        assert_eq!(res.root.dice()[0].faces, Faces::Standard);
+
+        // Tens then units: 00+0 is 100, 00+1 is 1, 10+0 is 10 and 90+9 is 99.
+        let args = ["4d100c".to_string()];
+        let output = execute_roll_with(
+            &args,
+            &Options::default(),
+            &mut ScriptedRng::new(&[0, 0, 0, 1, 1, 0, 9, 9]),
+        )
+        .unwrap();
+        for row in [
+            "| d100c | 00 + 0 = 100 |",
+            "| d100c | 00 + 1 = 1   |",
+            "| d100c | 10 + 0 = 10  |",
+            "| d100c | 90 + 9 = 99  |",
+            "| Total | 210          |",
+        ] {
+            assert!(output.contains(row), "{}", output);
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for input in [
+            "1D20A+5",
+            "d20",
+            "4d6 dl1",
+            "4d6!kh3",
+            "5d6km3",
+            "(1d4)d6!",
+            "(1d4+1)*3-2",
+            "1d20-(1d4-1)",
+            "2d20dis3",
+            "1d20d3",
+            "1d20dis lucky",
+            "1d20a3lucky",
+            "1d8!w8",
+            "6x(4d6dl1)",
+            "2x bo2(1d20 + 5)~1",
+            "wo3(2d6-1d4)>=3:save",
+            "3#1d20+5:attack # hi",
+            "1d8step",
+            "1d12step+2",
+            "2d6gwf*2",
+            "1d4-3 floor",
+            "2d6-10~-1",
+            "d%",
+            "4dF",
+            "d66",
+            "3d10z!",
+            "2D100Ccs",
+            "1d20+5>=15:save",
+            "8d10!>=10>=7f1",
+            "6d6t5",
+            "2d6min2max5e-1",
+            "3d6r<2e+1",
+            "1d20cs>=19cf",
+            "6d6m2",
+            "1d12b+3",
+            "4d20u",
+            "10d6sd",
+            "5d6!!s",
+            "2d6!ra1",
+            "1d6!p3>=5",
+            "1d6!p",
+            "3d{0,0,1,2}",
+            "d{yes,no,\"maybe, later\"}",
+            "8d6/2+8d6/^2",
+            "1d20+-3",
+        ] {
+            let parsed = validate_expression(input).unwrap();
+            let canonical = parsed.to_string();
+            assert_eq!(
+                validate_expression(&canonical).unwrap(),
+                parsed,
+                "{} → {}",
+                input,
+                canonical
+            );
+            assert_eq!(
+                canonical,
+                validate_expression(&canonical).unwrap().to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_display_canonical_form() {
+        let canonical = |s: &str| validate_expression(s).unwrap().to_string();
+        assert_eq!(canonical("D20 + 5"), "1d20+5");
+        assert_eq!(canonical("4d6DL1 # stats"), "4d6dl1");
+        assert_eq!(canonical("2d6*3"), "2d6*3");
+        assert_eq!(canonical("3#1d20:attack"), "3x1d20:attack");
+        assert_eq!(canonical("1d20dis lucky"), "1d20dislucky");
+        assert_eq!(canonical("1d4 floor"), "1d4~0");
+    }
+
+    #[test]
+    fn test_normalize() {
+        let output = normalize(
+            &["d20+5,4d6 DL1".to_string(), "1d20 vs 1d20+2".to_string()],
+            &Options::default(),
+        )
+        .unwrap();
+        let expected = [
+            "+----------------+----------------+",
+            "| Input          | Canonical      |",
+            "+=================================+",
+            "| d20+5,4d6 DL1  | 1d20+5,4d6dl1  |",
+            "|----------------+----------------|",
+            "| 1d20 vs 1d20+2 | 1d20 vs 1d20+2 |",
+            "+----------------+----------------+",
+        ];
+        assert_eq!(output, expected.join("\n"));
+        assert!(normalize(&["1d".to_string()], &Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_json_output() {
+        let args = ["1d20a+5:attack".to_string(), "4d6dl1,3d6>=5".to_string()];
+        let rng = &mut ScriptedRng::new(&[7, 15, 3, 6, 1, 4, 5, 2, 6]);
+        let output = execute_json_with(&args, &Options::default(), rng).unwrap();
+        // Scripts read these field names, so a change here is a breaking one.
+        assert_eq!(
+            output,
+            include_str!("../testdata/json_output.json").trim_end()
+        );
+        // A seeded run names its seed, and nothing else changes.
+        let options = Options {
+            seed: Some(42),
+            ..Options::default()
+        };
+        let rng = &mut ScriptedRng::new(&[7, 15, 3, 6, 1, 4, 5, 2, 6]);
+        let output = execute_json_with(&args, &options, rng).unwrap();
+        assert_eq!(
+            output,
+            include_str!("../testdata/json_output.json")
+                .trim_end()
+                .replace("\"seed\": null", "\"seed\": 42")
+        );
+    }
+
+    #[test]
+    fn test_jsonl_output() {
+        let args = ["1d20a+5".to_string(), "3d6>=5".to_string()];
+        let mut out = Vec::new();
+        let rng = &mut ScriptedRng::new(&[7, 15, 5, 2, 6]);
+        execute_jsonl_with(&args, &Options::default(), rng, &mut out).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0]["expression"], "1d20a+5");
+        assert_eq!(lines[0]["mode"], "advantage");
+        assert_eq!(lines[0]["kept"], 15);
+        assert_eq!(lines[0]["dropped"], json!([7]));
+        assert_eq!(lines[2]["index"], 1);
+        assert_eq!(lines[2]["kept"], 2);
+        assert_eq!(lines[4], json!({ "total": 20, "successes": 2 }));
+    }
+
+    #[test]
+    fn test_jsonl_streaming() {
+        // Past `MAX_DICE`, a plain sum is written out one die at a time.
+        let args = [format!("{}d6+1", MAX_DICE + 1)];
+        let mut out = Vec::new();
+        execute_jsonl_with(&args, &Options::default(), &mut SeededRng(7), &mut out).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len() as i64, MAX_DICE + 2);
+        assert_eq!(lines[MAX_DICE as usize]["index"], MAX_DICE);
+        let (summary, dice) = lines.split_last().unwrap();
+        let sum: i64 = dice.iter().map(|line| line["kept"].as_i64().unwrap()).sum();
+        assert_eq!(summary, &json!({ "total": sum + 1, "successes": null }));
+
+        // Anything that keeps its dice is still held to `MAX_DICE`.
+        let err = execute_plain_with(&args, &Options::default(), &mut SeededRng(7)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Error: Cannot roll more than 10000 dice at once, except as a plain sum with --jsonl."
+        );
+        let kept = [format!("{}d6kh1", MAX_DICE + 1)];
+        let mut out = Vec::new();
+        let err = execute_jsonl_with(&kept, &Options::default(), &mut SeededRng(7), &mut out)
+            .unwrap_err();
+        assert!(err.to_string().contains("more than 10000 dice"), "{}", err);
+        let sorted = Options {
+            sort: Some(Sort::Ascending),
+            ..Options::default()
+        };
+        assert!(execute_jsonl_with(&args, &sorted, &mut SeededRng(7), &mut out).is_err());
+
+        // A reader that stops early stops the rolling, however many dice
+        // are left.
+        struct Head(usize);
+        impl Write for Head {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                let lines = buf.iter().filter(|&&byte| byte == b'\n').count();
+                self.0 = self.0.saturating_sub(lines);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let args = [format!("{}d6", MAX_STREAMED_DICE)];
+        let err = execute_jsonl_with(&args, &Options::default(), &mut SeededRng(7), &mut Head(3))
+            .unwrap_err();
+        assert!(
+            err.downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn test_report_to_closed_pipe() {
+        let (reader, mut writer) = io::pipe().unwrap();
+        drop(reader);
+        // A reader that stopped early is a clean exit, whatever was printed.
+        assert_eq!(
+            report(Ok((Some("17".to_string()), 0)), false, &mut writer),
+            0
+        );
+        assert_eq!(report(Ok((None, 2)), false, &mut writer), 2);
+        let mut out = Vec::new();
+        assert_eq!(report(Ok((Some("17".to_string()), 0)), false, &mut out), 0);
+        assert_eq!(out, b"17\n");
+        assert_eq!(report(Err(anyhow!("Error: no")), true, &mut out), 1);
+    }
 
-    let mode = match mode_char {
-        Some("a") => RollMode::Advantage,
-        Some("d") => RollMode::Disadvantage,
-        _ => RollMode::Normal,
-    };
+    #[test]
+    fn test_csv_output() {
+        let args = [
+            "1d20a+5:attack".to_string(),
+            "2d{\"maybe, later\",no}".to_string(),
+            "1d4:\"Bob's \"\"big\"\" axe, swung\"".to_string(),
+        ];
+        let rng = &mut ScriptedRng::new(&[7, 15, 0, 1, 3]);
+        let output = execute_csv_with(&args, &Options::default(), rng).unwrap();
+        let expected = [
+            "expression,die,mode,kept,dropped,subtotal,label",
+            "1d20a+5,d20a,advantage,15,7,20,attack",
+            "\"2d{\"\"maybe, later\"\",no}\",\"d{\"\"maybe, later\"\",no}\",normal,\"maybe, later\",,,",
+            "\"2d{\"\"maybe, later\"\",no}\",\"d{\"\"maybe, later\"\",no}\",normal,no,,,",
+            "1d4,d4,normal,3,,3,\"Bob's \"\"big\"\" axe, swung\"",
+            "# total,23",
+        ];
+        assert_eq!(output, expected.join("\n"));
 
-    let modifier = match mod_pair {
-        Some(("+", val)) => val,
-        Some(("-", val)) => -val,
-        _ => 0,
-    };
+        let rng = &mut ScriptedRng::new(&[3, 6]);
+        let no_total = Options {
+            no_total: true,
+            ..Options::default()
+        };
+        let output = execute_csv_with(&["2d6kh1".to_string()], &no_total, rng).unwrap();
+        assert_eq!(
+            output,
+            "expression,die,mode,kept,dropped,subtotal,label\n2d6kh1,d6kh1,normal,,3,6,\n2d6kh1,d6kh1,normal,6,,6,"
+        );
+        assert_eq!(csv_field(r#"say "hi""#), r#""say ""hi""""#);
+    }
 
-    Ok((
-        input,
-        DiceRequest {
-            count: count.unwrap_or(1),
-            sides,
-            mode,
-            modifier,
-        },
-    ))
-}
+    #[test]
+    fn test_markdown_output() {
+        let args = ["1d20cs+5".to_string(), "2d6kh1".to_string()];
+        let rng = &mut ScriptedRng::new(&[20, 3, 5]);
+        let output = execute_markdown_with(&args, &Options::default(), rng).unwrap();
+        let expected = [
+            "| Die | Roll |",
+            "| :--- | ---: |",
+            "| d20cs20 | 20 CRIT |",
+            "| Modifier | +5 |",
+            "| Subtotal (1d20cs20+5) | 25 |",
+            "| d6kh1 | (3) |",
+            "| d6kh1 | 5 |",
+            "| Subtotal (2d6kh1) | 5 |",
+            "| **Total** | **30** |",
+        ];
+        assert_eq!(output, expected.join("\n"));
 
-fn parse_and_validate(s: &str) -> Result<DiceRequest> {
-    let (remainder, request) = parse_dice_expression(s).map_err(|_| {
-        anyhow!(
-            "Error: Failed to parse dice expression '{}'. Expected format 'NdS' (e.g. 1d20, 4d8).",
-            s
+        let output = execute_markdown_with(
+            &["1d{a|b}".to_string()],
+            &Options::default(),
+            &mut ScriptedRng::new(&[0]),
         )
-    })?;
+        .unwrap();
+        assert!(output.contains("| d{a\\|b} | a\\|b |"), "{}", output);
+    }
 
-    if !remainder.is_empty() {
-        bail!(
-            "Error: Invalid dice format '{}'. Unparsed content: '{}'",
-            s,
-            remainder
+    #[test]
+    fn test_plain_output() {
+        let args = ["1d20a:attack".to_string(), "4d6dl1".to_string()];
+        let rng = &mut ScriptedRng::new(&[4, 17, 3, 1, 5, 6]);
+        let output = execute_plain_with(&args, &Options::default(), rng).unwrap();
+        assert_eq!(
+            output,
+            "attack: 17 (dropped 4)\n4d6dl1: 14 (dropped 1)\ntotal: 31"
         );
+
+        let args = ["1d20+5>=15 vs 1d20".to_string(), "3d6>=5".to_string()];
+        let rng = &mut ScriptedRng::new(&[10, 12, 5, 6, 2]);
+        let output = execute_plain_with(&args, &Options::default(), rng).unwrap();
+        let expected = [
+            "Side A: 15 PASS (0)",
+            "Side B: 12",
+            "outcome: Side A wins by 3",
+            "3d6>=5: 2 successes",
+            "successes: 2",
+        ];
+        assert_eq!(output, expected.join("\n"));
     }
 
-    if request.sides <= 0 {
-        bail!("Error: Dice cannot have 0 or fewer sides.");
+    #[test]
+    fn test_sum_output() {
+        let args = ["2d6+3".to_string(), "1d8".to_string()];
+        let rng = &mut ScriptedRng::new(&[2, 5, 7]);
+        assert_eq!(
+            execute_sum_with(&args, &Options::default(), rng, false).unwrap(),
+            "17"
+        );
+        let rng = &mut ScriptedRng::new(&[2, 5, 7]);
+        assert_eq!(
+            execute_sum_with(&args, &Options::default(), rng, true).unwrap(),
+            "10\n7"
+        );
+
+        let args = ["1d20 vs 1d20".to_string(), "3d6>=5".to_string()];
+        let rng = &mut ScriptedRng::new(&[12, 9, 5, 6, 2]);
+        assert_eq!(
+            execute_sum_with(&args, &Options::default(), rng, true).unwrap(),
+            "12\n9\n2"
+        );
+
+        let args = ["3d6>=5".to_string(), "1d6".to_string()];
+        let rng = &mut ScriptedRng::new(&[5, 6, 2, 4]);
+        let err = execute_sum_with(&args, &Options::default(), rng, false).unwrap_err();
+        assert!(err.to_string().contains("--separate"), "{}", err);
+        let err = execute_sum_with(
+            &["d66".to_string()],
+            &Options::default(),
+            &mut ScriptedRng::new(&[1, 2]),
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Error: 'd66' has nothing to sum.");
     }
 
-    if request.count <= 0 {
-        bail!("Error: Dice count must be greater than 0.");
+    #[test]
+    fn test_seeded_rng_is_pinned() {
+        // SplitMix64's published outputs for seed 0.
+        let mut rng = SeededRng(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+        assert_eq!(rng.next_u64(), 0x06C4_5D18_8009_454F);
+
+        // Recorded rolls: if these change, every seed anyone wrote down breaks.
+        let args = ["4d6dl1".to_string(), "1d20a+5:attack".to_string()];
+        let output = execute_plain_with(&args, &Options::default(), &mut SeededRng(42)).unwrap();
+        assert_eq!(
+            output,
+            "4d6dl1: 5 (dropped 1)\nattack: 16 (dropped 3)\ntotal: 21"
+        );
     }
 
-    Ok(request)
-}
+    #[test]
+    fn test_repeat_rounds() {
+        let args = ["1d6".to_string(), "1d4".to_string()];
+        let rounds = Options {
+            rounds: 2,
+            ..Options::default()
+        };
+        let rng = &mut ScriptedRng::new(&[6, 1, 2, 3]);
+        let output = execute_plain_with(&args, &rounds, rng).unwrap();
+        let expected = [
+            "1d6: 6",
+            "1d4: 1",
+            "round 1: 7",
+            "1d6: 2",
+            "1d4: 3",
+            "round 2: 5",
+            "total: 12",
+        ];
+        assert_eq!(output, expected.join("\n"));
 
-fn roll_dice(requests: &[DiceRequest]) -> Vec<RollResult> {
-    requests
-        .iter()
-        .flat_map(|req| {
-            (0..req.count).map(move |_| {
-                let mut rng = rand::thread_rng();
-                let r1 = rng.gen_range(1..=req.sides);
-                match req.mode {
-                    RollMode::Normal => RollResult {
-                        sides: req.sides,
-                        mode: req.mode,
-                        kept: r1 + req.modifier,
-                        dropped: None,
-                        modifier: req.modifier,
-                    },
-                    RollMode::Advantage => {
-                        let r2 = rng.gen_range(1..=req.sides);
-                        RollResult {
-                            sides: req.sides,
-                            mode: req.mode,
-                            kept: r1.max(r2) + req.modifier,
-                            dropped: Some(r1.min(r2) + req.modifier),
-                            modifier: req.modifier,
-                        }
-                    }
-                    RollMode::Disadvantage => {
-                        let r2 = rng.gen_range(1..=req.sides);
-                        RollResult {
-                            sides: req.sides,
-                            mode: req.mode,
-                            kept: r1.min(r2) + req.modifier,
-                            dropped: Some(r1.max(r2) + req.modifier),
-                            modifier: req.modifier,
-                        }
-                    }
-                }
-            })
-        })
-        .collect()
-}
+        let rng = &mut ScriptedRng::new(&[6, 1, 2, 3]);
+        assert_eq!(execute_sum_with(&args, &rounds, rng, true).unwrap(), "7\n5");
 
-fn execute_roll(dice_args: &[String]) -> Result<String> {
-    // 1. Parse and Validate Inputs
-    let requests: Vec<DiceRequest> = dice_args
-        .iter()
-        .map(|s| parse_and_validate(s))
-        .collect::<Result<_>>()?;
+        let rng = &mut ScriptedRng::new(&[6, 1, 2, 3]);
+        let output = execute_roll_with(&args, &rounds, rng).unwrap();
+        assert!(output.contains("| Round 1 | 7    |"), "{}", output);
+        assert!(output.contains("| Round 2 | 5    |"), "{}", output);
+        assert!(output.contains("| Total   | 12   |"), "{}", output);
 
-    // 2. Perform Calculations
-    let results = roll_dice(&requests);
+        let rng = &mut ScriptedRng::new(&[6, 1, 2, 3]);
+        let output: serde_json::Value =
+            serde_json::from_str(&execute_json_with(&args, &rounds, rng).unwrap()).unwrap();
+        assert_eq!(output["rounds"][1]["round"], 2);
+        assert_eq!(output["rounds"][1]["expressions"][1]["subtotal"], 3);
+        assert_eq!(output["rounds"][1]["total"], 5);
+        assert_eq!(output["total"], 12);
+    }
 
-    // 3. Format Output
-    let mut table = Table::new();
-    table.set_header(vec!["Die", "Roll"]);
-
-    let total_sum: i64 = results.iter().map(|res| res.kept).sum();
-    let result_count = results.len();
-
-    for res in results {
-        let roll_str = match res.dropped {
-            Some(d) => format!("{} ({})", res.kept, d),
-            None => res.kept.to_string(),
-        };
-        let mode_str = match res.mode {
-            RollMode::Normal => "",
-            RollMode::Advantage => "a",
-            RollMode::Disadvantage => "d",
-        };
-        let mod_str = if res.modifier > 0 {
-            format!("+{}", res.modifier)
-        } else if res.modifier < 0 {
-            res.modifier.to_string()
-        } else {
-            String::new()
+    #[test]
+    fn test_sort_rows() {
+        let args = ["4d6dl1".to_string(), "1d4".to_string(), "1d20".to_string()];
+        let options = Options {
+            sort: Some(Sort::Ascending),
+            ..Options::default()
         };
-        table.add_row(vec![
-            format!("d{}{}{}", res.sides, mode_str, mod_str),
-            roll_str,
-        ]);
+        let output = execute_plain_with(&args, &options, &mut SeededRng(42)).unwrap();
+        let unsorted = execute_plain_with(&args, &Options::default(), &mut SeededRng(42)).unwrap();
+        assert_eq!(output, unsorted);
+        let output = execute_roll_with(&args, &options, &mut SeededRng(42)).unwrap();
+        let rolls: Vec<&str> = output
+            .lines()
+            .filter(|line| line.contains("| d6dl1 "))
+            .map(|line| line.split('|').nth(3).unwrap().trim())
+            .collect();
+        assert_eq!(rolls, ["1", "2", "2", "(1)"]);
+
+        let options = Options {
+            sort_all: true,
+            ..options
+        };
+        let output = execute_plain_with(&args, &options, &mut SeededRng(42)).unwrap();
+        assert_eq!(output, "1d4: 3\n1d20: 3\n4d6dl1: 5 (dropped 1)\ntotal: 11");
     }
 
-    if result_count > 1 {
-        table.add_row(vec!["Total", &total_sum.to_string()]);
+    #[test]
+    fn test_verbose_throws() {
+        let options = Options {
+            verbose: true,
+            ..Options::default()
+        };
+        let args = ["1d20a".to_string(), "2d6!kh1r1".to_string()];
+        let rng = &mut ScriptedRng::new(&[4, 17, 1, 6, 2, 3]);
+        let output = execute_roll_with(&args, &options, rng).unwrap();
+        assert!(output.contains("| Rolls "), "{}", output);
+        assert!(output.contains("| (4) 17 "), "{}", output);
+        assert!(output.contains("| 1r 6! 2 "), "{}", output);
+        assert!(output.contains("| (3) "), "{}", output);
+
+        let rng = &mut ScriptedRng::new(&[4, 17]);
+        let output = execute_roll_with(&["1d20a".to_string()], &Options::default(), rng).unwrap();
+        assert!(!output.contains("Rolls"), "{}", output);
     }
 
-    Ok(table.to_string())
-}
+    #[test]
+    fn test_no_total() {
+        let options = Options {
+            no_total: true,
+            ..Options::default()
+        };
+        let args = [
+            "2d6+3".to_string(),
+            "1d20".to_string(),
+            "2d6>=5".to_string(),
+        ];
+        let output =
+            execute_roll_with(&args, &options, &mut ScriptedRng::new(&[2, 5, 11, 5, 6])).unwrap();
+        assert!(output.contains("| Subtotal (2d6+3) | 10 "), "{}", output);
+        assert!(!output.contains("Total"), "{}", output);
 
-fn main() {
-    let args = Args::parse();
-    match execute_roll(&args.dice) {
-        Ok(output) => println!("{}", output),
-        Err(e) => {
-            eprintln!("{}", e);
-            process::exit(1);
-        }
+        let output =
+            execute_json_with(&args, &options, &mut ScriptedRng::new(&[2, 5, 11, 5, 6])).unwrap();
+        let output: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(output.get("total"), None);
+        assert_eq!(output.get("successes"), None);
+
+        let output =
+            execute_plain_with(&args, &options, &mut ScriptedRng::new(&[2, 5, 11, 5, 6])).unwrap();
+        assert_eq!(output, "2d6+3: 10\n1d20: 11\n2d6>=5: 2 successes");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_expression_column() {
+        let args = [
+            "2d6+3".to_string(),
+            "1d8:dagger".to_string(),
+            "1d4".to_string(),
+        ];
+        let rng = &mut ScriptedRng::new(&[2, 5, 7, 3]);
+        let output = execute_roll_with(&args, &Options::default(), rng).unwrap();
+        let expected = [
+            "| Expression | Die              | Roll |",
+            "| 2d6+3      | d6               | 2    |",
+            "|            | d6               | 5    |",
+            "|            | Modifier         | +3   |",
+            "|            | Subtotal (2d6+3) | 10   |",
+            "| dagger     | d8               | 7    |",
+            "| 1d4        | d4               | 3    |",
+            "|            | Total            | 20   |",
+        ];
+        let lines: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("| "))
+            .collect();
+        assert_eq!(lines, expected);
 
-    // --- Parser Unit Tests ---
+        // Two expressions keep the plain layout.
+        let rng = &mut ScriptedRng::new(&[2, 5, 7]);
+        let output = execute_roll_with(&args[..2], &Options::default(), rng).unwrap();
+        assert!(output.contains("| Label  | Die "), "{}", output);
+        assert!(!output.contains("Expression"), "{}", output);
+    }
 
     #[test]
-    fn test_parse_dice_simple() {
-        let (_, res) = parse_dice_expression("1d20").unwrap();
+    fn test_highlights() {
+        let req = parse_and_validate("1d20").unwrap().root.dice()[0].clone();
+        let die = |value: i64, kept: bool| {
+            let mut die = roll_chain(&req, &mut ScriptedRng::new(&[value]));
+            die.kept = kept;
+            die
+        };
         assert_eq!(
-            res,
-            DiceRequest {
-                count: 1,
-                sides: 20,
-                mode: RollMode::Normal,
-                modifier: 0,
-            }
+            Highlight::of(&die(20, true), &req),
+            Some(Highlight::Maximum)
         );
+        assert_eq!(Highlight::of(&die(1, true), &req), Some(Highlight::Minimum));
+        assert_eq!(
+            Highlight::of(&die(20, false), &req),
+            Some(Highlight::Dropped)
+        );
+        assert_eq!(Highlight::of(&die(7, true), &req), None);
+
+        let args = ["2d20kh1".to_string()];
+        let color = Options {
+            color: true,
+            ..Options::default()
+        };
+        let output = execute_roll_with(&args, &color, &mut ScriptedRng::new(&[20, 1])).unwrap();
+        assert!(output.contains('\u{1b}'), "{}", output);
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[20, 1])).unwrap();
+        assert!(!output.contains('\u{1b}'), "{}", output);
+        let output = execute_markdown_with(&args, &color, &mut ScriptedRng::new(&[20, 1])).unwrap();
+        assert!(!output.contains('\u{1b}'), "{}", output);
+
+        // The dice advantage leaves over are dimmed in the kept die's cell,
+        // which is still green for its natural 20.
+        let args = ["1d20a".to_string()];
+        let output = execute_roll_with(&args, &color, &mut ScriptedRng::new(&[20, 7])).unwrap();
+        assert!(output.contains("20 \x1b[2m(7)\x1b[22m"), "{:?}", output);
+        assert!(output.contains("\x1b[38;5;10m"), "{:?}", output);
+        let output =
+            execute_roll_with(&args, &Options::default(), &mut ScriptedRng::new(&[20, 7])).unwrap();
+        assert!(output.contains("| 20 (7) "), "{}", output);
+        let output = execute_markdown_with(&args, &color, &mut ScriptedRng::new(&[20, 7])).unwrap();
+        assert!(output.contains("| 20 (7) |"), "{}", output);
     }
 
     #[test]
-    fn test_parse_dice_advantage() {
-        let (_, res) = parse_dice_expression("1d20a").unwrap();
-        assert_eq!(
-            res,
-            DiceRequest {
-                count: 1,
-                sides: 20,
-                mode: RollMode::Advantage,
-                modifier: 0,
-            }
-        );
+    fn test_color_choice() {
+        let auto = ColorChoice::Auto;
+        assert!(auto.enabled(None, None, true));
+        assert!(!auto.enabled(None, None, false));
+        assert!(!auto.enabled(Some("1"), None, true));
+        assert!(auto.enabled(Some(""), None, true));
+        assert!(auto.enabled(None, Some("1"), false));
+        assert!(!auto.enabled(None, Some("0"), false));
+        assert!(!auto.enabled(Some("1"), Some("1"), true));
+        assert!(ColorChoice::Always.enabled(Some("1"), None, false));
+        assert!(!ColorChoice::Never.enabled(None, Some("1"), true));
+
+        // The whole command line, as main runs it, with every reason to color.
+        for (flag, colored) in [("always", true), ("never", false)] {
+            let args =
+                Args::try_parse_from(["roll", "--table", "--color", flag, "2d20kh1"]).unwrap();
+            let options = args.options(args.color.enabled(None, Some("1"), true));
+            let rng = &mut ScriptedRng::new(&[20, 1]);
+            let output = run(&args, &options, &args.dice, rng).unwrap().unwrap();
+            assert_eq!(output.contains('\u{1b}'), colored, "{}", output);
+        }
     }
 
     #[test]
-    fn test_parse_dice_disadvantage() {
-        let (_, res) = parse_dice_expression("1d20d").unwrap();
+    fn test_stats_columns() {
+        let options = Options {
+            stats: true,
+            ..Options::default()
+        };
+        let args = ["3d6".to_string(), "1d20a+5".to_string()];
+        let rng = &mut ScriptedRng::new(&[1, 2, 6, 4, 17]);
+        let output = execute_roll_with(&args, &options, rng).unwrap();
+        assert!(output.contains("| Min | Mean  | Max |"), "{}", output);
+        assert!(
+            output.contains("| Subtotal (3d6)     | 9      | 3   | 10.5  | 18  |"),
+            "{}",
+            output
+        );
+        assert!(output.contains("| 6   | 18.83 | 25  |"), "{}", output);
         assert_eq!(
-            res,
-            DiceRequest {
-                count: 1,
-                sides: 20,
-                mode: RollMode::Disadvantage,
-                modifier: 0,
-            }
+            format_summary(stats::summarize(
+                &parse_and_validate("1d6!").unwrap().root,
+                None
+            )),
+            ["1", "4.2", "∞"]
         );
-    }
 
-    #[test]
-    fn test_parse_dice_implicit_count() {
-        let (_, res) = parse_dice_expression("d6").unwrap();
+        // The other formats carry the same range and average.
+        let args = ["3d6".to_string(), "1d6!".to_string()];
+        let faces = [1, 2, 6, 4];
+        let output = execute_plain_with(&args, &options, &mut ScriptedRng::new(&faces)).unwrap();
         assert_eq!(
-            res,
-            DiceRequest {
-                count: 1,
-                sides: 6,
-                mode: RollMode::Normal,
-                modifier: 0,
-            }
+            output,
+            "3d6: 9 (min 3, mean 10.5, max 18)\n1d6!: 4 (min 1, mean 4.2, max ∞)\ntotal: 13"
+        );
+        let output = execute_json_with(&args, &options, &mut ScriptedRng::new(&faces)).unwrap();
+        let document: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            document["expressions"][0]["stats"],
+            json!({"min": 3, "mean": 10.5, "max": 18, "exact": true})
+        );
+        assert_eq!(document["expressions"][1]["stats"]["max"], json!(null));
+        let output = execute_csv_with(&args, &options, &mut ScriptedRng::new(&faces)).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[0],
+            "expression,die,mode,kept,dropped,subtotal,label,min,mean,max,exact"
         );
+        assert_eq!(lines[1], "3d6,d6,normal,1,,9,,3,10.5,18,true");
+        assert_eq!(lines[4], "1d6!,d6!,normal,4,,4,,1,4.2,,true");
+
+        for format in ["--jsonl", "--sum"] {
+            assert!(Args::try_parse_from(["roll", "--stats", format, "3d6"]).is_err());
+        }
     }
 
     #[test]
-    fn test_parse_dice_multiple() {
-        let (_, res) = parse_dice_expression("10d100").unwrap();
-        assert_eq!(
-            res,
-            DiceRequest {
-                count: 10,
-                sides: 100,
-                mode: RollMode::Normal,
-                modifier: 0,
-            }
+    fn test_expected_output() {
+        let args = [
+            "2d6+3".to_string(),
+            "1d20a+5:attack".to_string(),
+            "1d8+2".to_string(),
+        ];
+        let output = expected(&args, &Options::default()).unwrap();
+        assert!(
+            output.contains("| 2d6+3          | 10             | 10      |"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("| 1d20a+5:attack | 18.83 (753/40) | 18      |"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("| 1d8+2          | 6.5            | 6       |"),
+            "{}",
+            output
         );
+        let err = expected(&["1d8!w".to_string()], &Options::default()).unwrap_err();
+        assert!(err.to_string().contains("without rolling"), "{}", err);
     }
 
     #[test]
-    fn test_parse_dice_with_modifier() {
-        let (_, res) = parse_dice_expression("1d20+5").unwrap();
+    fn test_histogram() {
+        let counts = BTreeMap::from([(2, 1), (4, 3)]);
         assert_eq!(
-            res,
-            DiceRequest {
-                count: 1,
-                sides: 20,
-                mode: RollMode::Normal,
-                modifier: 5,
-            }
+            format_histogram("1d4", &counts, 4, 40),
+            "1d4: 4 rolls\n\
+             2 | #########                  1  25.00%\n\
+             3 |                            0   0.00%\n\
+             4 | ########################## 3  75.00%"
         );
-    }
 
-    #[test]
-    fn test_parse_dice_with_negative_modifier() {
-        let (_, res) = parse_dice_expression("1d20-2").unwrap();
+        // Past 50 totals, the bars cover a range each.
+        let counts = BTreeMap::from([(1, 5), (100, 5)]);
+        let chart = format_histogram("1d100", &counts, 10, 80);
+        assert_eq!(chart.lines().count(), 51);
+        assert!(chart.contains("\n   1..2 | "), "{}", chart);
+        assert!(chart.contains("\n99..100 | "), "{}", chart);
+
+        // The same seed charts the same rolls.
+        let args = ["2d6+3".to_string()];
+        let chart = histogram(&args, 1000, 80, &mut SeededRng(7)).unwrap();
         assert_eq!(
-            res,
-            DiceRequest {
-                count: 1,
-                sides: 20,
-                mode: RollMode::Normal,
-                modifier: -2,
-            }
+            chart,
+            histogram(&args, 1000, 80, &mut SeededRng(7)).unwrap()
         );
+        assert!(chart.starts_with("2d6+3: 1000 rolls\n 5 | "), "{}", chart);
+        assert_eq!(chart.lines().count(), 12);
     }
 
     #[test]
-    fn test_parse_dice_advantage_modifier() {
-        let (_, res) = parse_dice_expression("1d20a+5").unwrap();
+    fn test_chance_output() {
+        let rng = &mut SeededRng(1);
+        let target = parse_threshold("15").unwrap();
+        let output = chance(
+            &["1d20+5".to_string(), "1d20a+5".to_string()],
+            target,
+            100,
+            rng,
+        );
         assert_eq!(
-            res,
-            DiceRequest {
-                count: 1,
-                sides: 20,
-                mode: RollMode::Advantage,
-                modifier: 5,
-            }
+            output.unwrap(),
+            "1d20+5>=15: 55% (11 in 20)\n1d20a+5>=15: 79.75% (about 4 in 5)"
         );
-    }
+        let target = parse_threshold("<=5").unwrap();
+        let output = chance(&["3d6".to_string()], target, 100, rng).unwrap();
+        assert_eq!(output, "3d6<=5: 4.63% (about 1 in 22)");
 
-    #[test]
-    fn test_parse_dice_invalid() {
-        assert!(parse_dice_expression("invalid").is_err());
-        let (rem, _) = parse_dice_expression("1d20extra").unwrap();
-        assert_eq!(rem, "extra");
-    }
+        // Wild dice are estimated, with the sample count and margin.
+        let target = parse_threshold(">=4").unwrap();
+        let output = chance(&["1d6!w".to_string()], target, 1000, &mut SeededRng(3)).unwrap();
+        assert!(output.starts_with("1d6!w>=4: ~"), "{}", output);
+        assert!(
+            output.contains("estimated from 1000 rolls, ±"),
+            "{}",
+            output
+        );
+        // A huge expression is rolled fewer times, however many are asked for.
+        let samples = |input: &str| estimate_samples(&parse_and_validate(input).unwrap(), 100_000);
+        assert_eq!(samples("2000d6+1d6"), 999);
+        assert_eq!(samples("bo2(1000d6)"), 1000);
+        assert_eq!(samples("1d6!w"), 100_000);
 
-    // --- Integration Tests (using function calls) ---
+        assert_eq!(format_odds(0.7), "7 in 10");
+        assert_eq!(format_odds(0.0025), "1 in 400");
+        assert_eq!(format_odds(0.9975), "399 in 400");
+        assert_eq!(format_odds(0.0), "never");
+        assert!(parse_threshold("at least 5").is_err());
+        assert_eq!(
+            parse_threshold("-2"),
+            Ok(Comparison {
+                op: CompareOp::Ge,
+                value: -2
+            })
+        );
+    }
 
     #[test]
-    fn test_single_die() {
-        let output = execute_roll(&["1d20".to_string()]).unwrap();
-        assert!(output.contains("d20"));
-        assert!(output.contains("Die")); // Header
-        assert!(output.contains("Roll")); // Header
-        assert!(!output.contains("Total")); // Should not show Total for single roll
+    fn test_simulation() {
+        let rng = &mut ScriptedRng::new(&[1, 2, 3, 4, 5, 6]);
+        let output = simulation(&["1d6+1".to_string()], 6, &Options::default(), rng).unwrap();
+        assert!(
+            output.contains(
+                "| Expression | Min | Max | Mean | Std dev | Median | 5th | 25th | 75th | 95th |"
+            ),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains(
+                "| 1d6+1      | 2   | 7   | 4.5  | 1.87    | 4      | 2   | 3    | 6    | 7    |"
+            ),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("\n6 rolls of each expression in "),
+            "{}",
+            output
+        );
     }
 
     #[test]
-    fn test_multiple_dice() {
-        let output = execute_roll(&["2d6".to_string(), "1d10".to_string()]).unwrap();
-        assert!(output.contains("d6"));
-        assert!(output.contains("d10"));
-        assert!(output.contains("Total"));
+    fn test_table_styles() {
+        let is_box_drawing = |c: char| ('\u{2500}'..='\u{257f}').contains(&c);
+        for (style, corners) in [
+            ("heavy", ["┏", "┓", "┗", "┛"]),
+            ("light", ["┌", "┐", "└", "┘"]),
+            ("rounded", ["╭", "╮", "╰", "╯"]),
+            ("ascii", ["+-", "-+", "+-", "-+"]),
+        ] {
+            let args = Args::try_parse_from(["roll", "--table", "--style", style, "2d6"]).unwrap();
+            let options = args.options(false);
+            let rng = &mut ScriptedRng::new(&[3, 4]);
+            let output = run(&args, &options, &args.dice, rng).unwrap().unwrap();
+            let (first, last) = (
+                output.lines().next().unwrap(),
+                output.lines().last().unwrap(),
+            );
+            assert!(
+                first.starts_with(corners[0]) && first.ends_with(corners[1]),
+                "{}",
+                output
+            );
+            assert!(
+                last.starts_with(corners[2]) && last.ends_with(corners[3]),
+                "{}",
+                output
+            );
+            assert_eq!(
+                style == "ascii",
+                !output.chars().any(is_box_drawing),
+                "{}",
+                output
+            );
+        }
+
+        let args =
+            Args::try_parse_from(["roll", "--table", "--style", "borderless", "2d6"]).unwrap();
+        let rng = &mut ScriptedRng::new(&[3, 4]);
+        let output = run(&args, &args.options(false), &args.dice, rng)
+            .unwrap()
+            .unwrap();
+        assert!(output.starts_with(" Die "), "{}", output);
+        let bordered = output.lines().any(|line| line.starts_with(['+', '|']));
+        assert!(
+            !bordered && !output.chars().any(is_box_drawing),
+            "{}",
+            output
+        );
+
+        // The default is the look the tables have always had.
+        let args = Args::try_parse_from(["roll", "2d6"]).unwrap();
+        assert_eq!(args.style, TableStyle::Ascii);
     }
 
     #[test]
-    fn test_advantage_roll_execution() {
-        let output = execute_roll(&["1d20a".to_string()]).unwrap();
-        assert!(output.contains("d20a"));
-        assert!(!output.contains("Total")); // Should not show Total for single roll
-        // We can't easily assert the values without parsing the table back, but we check if it runs.
-        // We can possibly check for parentheses if we roll enough times or mock,
-        // but for a formatted string check, existence of "d20a" is good.
+    fn test_fancy_pips() {
+        let options = Options {
+            fancy: true,
+            ..Options::default()
+        };
+        let args = ["2d6kh1".to_string(), "1d6!".to_string(), "1d8".to_string()];
+        let rng = &mut ScriptedRng::new(&[5, 2, 6, 3, 3]);
+        let output = execute_roll_with(&args, &options, rng).unwrap();
+        assert!(output.contains("| 5 ⚄ "), "{}", output);
+        assert!(output.contains("| (2 ⚁) "), "{}", output);
+        assert!(output.contains("| 6+3 = 9 ⚅⚂ "), "{}", output);
+        assert!(output.contains("| d8  "), "{}", output);
+        assert!(!output.contains("3 ⚂ "), "{}", output);
+        // Each pip takes one column, so every line is as wide as the first.
+        let width = output.lines().next().unwrap().chars().count();
+        assert!(
+            output.lines().all(|line| line.chars().count() == width),
+            "{}",
+            output
+        );
+
+        let rng = &mut ScriptedRng::new(&[5]);
+        let output = execute_roll_with(&["1d6".to_string()], &Options::default(), rng).unwrap();
+        assert!(!output.contains('⚄'), "{}", output);
+
+        // The die advantage leaves over shows its pips too, dimmed in color.
+        let rng = &mut ScriptedRng::new(&[6, 2, 3, 5]);
+        let output = execute_roll_with(&["2d6a".to_string()], &options, rng).unwrap();
+        assert!(output.contains("| 6 ⚅ (2 ⚁) "), "{}", output);
+        let colored = Options {
+            color: true,
+            ..options
+        };
+        let rng = &mut ScriptedRng::new(&[6, 2, 3, 5]);
+        let output = execute_roll_with(&["2d6a".to_string()], &colored, rng).unwrap();
+        assert!(output.contains("6 ⚅ \x1b[2m(2 ⚁)\x1b[22m"), "{:?}", output);
     }
 
     #[test]
-    fn test_invalid_arg() {
-        let err = execute_roll(&["invalid".to_string()]).unwrap_err();
-        assert!(err.to_string().contains("Failed to parse dice expression"));
+    fn test_global_target() {
+        let options = Options {
+            target: Some(parse_threshold("5").unwrap()),
+            ..Options::default()
+        };
+        let args = ["3d6".to_string(), "2d6".to_string()];
+        let rng = &mut ScriptedRng::new(&[5, 2, 6, 1, 5]);
+        let output = execute_roll_with(&args, &options, rng).unwrap();
+        assert!(output.contains("| Successes | 2 successes "), "{}", output);
+        assert!(output.contains("| Successes | 1 success "), "{}", output);
+        assert!(output.contains("| Total     | 3 successes "), "{}", output);
+
+        let rng = &mut ScriptedRng::new(&[5, 2]);
+        let output = execute_json_with(&["2d6".to_string()], &options, rng).unwrap();
+        let output: serde_json::Value = serde_json::from_str(&output).unwrap();
+        let term = &output["expressions"][0]["terms"][0];
+        assert_eq!(term["target"], ">=5");
+        assert_eq!(term["dice"][0]["value"], 5);
+        assert_eq!(term["dice"][0]["successes"], 1);
+        assert_eq!(term["dice"][1]["successes"], 0);
+
+        let err = execute_roll_with(&["8d6>=4".to_string()], &options, rng).unwrap_err();
+        assert!(err.to_string().contains("not both"), "{}", err);
     }
 
     #[test]
-    fn test_partial_valid_arg() {
-        let err = execute_roll(&["1d20extra".to_string()]).unwrap_err();
-        assert!(err.to_string().contains("Invalid dice format"));
+    fn test_default_crit() {
+        let options = Options {
+            crit: Some(parse_threshold("19").unwrap()),
+            ..Options::default()
+        };
+        let args = [
+            "1d20+5".to_string(),
+            "1d20cs20".to_string(),
+            "1d6".to_string(),
+        ];
+        let parsed = Parsed::new(&args, &options).unwrap();
+        let crits: Vec<Option<String>> = parsed
+            .expressions
+            .iter()
+            .flatten()
+            .flat_map(|expression| expression.root.dice())
+            .map(|request| request.crit_success.map(|crit| crit.to_string()))
+            .collect();
+        // A d20's own `cs` wins, and other dice are left alone.
+        assert_eq!(
+            crits,
+            [Some(">=19".to_string()), Some("=20".to_string()), None]
+        );
+        let output = execute_roll_with(&args[..1], &options, &mut ScriptedRng::new(&[19])).unwrap();
+        assert!(output.contains("19 CRIT"), "{}", output);
     }
 
     #[test]
-    fn test_zero_sides() {
-        let err = execute_roll(&["2d0".to_string()]).unwrap_err();
-        assert!(
-            err.to_string()
-                .contains("Dice cannot have 0 or fewer sides")
-        );
+    fn test_dc_exit_code() {
+        let check = |argv: &[&str], faces: &[i64]| {
+            let args = Args::try_parse_from(argv).unwrap();
+            let options = args.options(false);
+            run_checked(&args, &options, &args.dice, &mut ScriptedRng::new(faces)).unwrap()
+        };
+        let (output, code) = check(&["roll", "--plain", "--dc", "15", "1d20+5"], &[10]);
+        assert_eq!(output.as_deref(), Some("1d20+5: 15\ntotal: 15"));
+        assert_eq!(code, 0);
+        let (_, code) = check(&["roll", "--plain", "--dc", "15", "1d20+5"], &[9]);
+        assert_eq!(code, DC_FAILED);
+
+        // The grand total can pass where one expression doesn't.
+        let argv = ["roll", "--plain", "--dc", "10", "1d20", "1d6"];
+        assert_eq!(check(&argv, &[8, 3]).1, 0);
+        let argv = ["roll", "--plain", "--dc", "5", "--all", "1d20", "1d6"];
+        assert_eq!(check(&argv, &[8, 3]).1, DC_FAILED);
+        assert_eq!(check(&argv, &[8, 5]).1, 0);
+
+        // Quiet prints nothing, whatever the format.
+        let (output, code) = check(&["roll", "--json", "-q", "--dc", "3", "2d6"], &[1, 1]);
+        assert_eq!((output, code), (None, DC_FAILED));
+
+        let args = Args::try_parse_from(["roll", "--dc", "5", "1d6", "1d4t3"]).unwrap();
+        let options = args.options(false);
+        let rng = &mut ScriptedRng::new(&[4, 3]);
+        let err = run_checked(&args, &options, &args.dice, rng).unwrap_err();
+        assert!(err.to_string().contains("Successes"), "{}", err);
+
+        let error = Args::try_parse_from(["roll", "--quiet", "1d6"]).unwrap_err();
+        assert_eq!(error.exit_code(), 2);
     }
 }
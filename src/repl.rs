@@ -0,0 +1,359 @@
+//! The interactive mode, `roll -i`: one expression per line, rolled as soon
+//! as it is entered, until `quit` or the end of input.
+
+use crate::{
+    Args, Config, DieRng, Options, Parsed, Recorder, Replay, Totals, format_totals,
+    read_expressions, roll_arguments, run, substitute_variables,
+};
+use anyhow::{Result, anyhow, bail};
+use rustyline::{DefaultEditor, error::ReadlineError};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+const PROMPT: &str = "roll> ";
+
+/// How many rolls `history` lists when not told.
+const HISTORY_SHOWN: usize = 20;
+
+/// What `help` prints.
+const HELP: &str = "\
+Enter dice expressions to roll them, several to a line if you like:
+  1d20+5         a d20 plus 5
+  2d20kh1        keep the highest (also kl, dl, dh, km)
+  1d20a, 1d20d   advantage and disadvantage
+  4d6!, 4d6ro1   exploding dice, and rerolling 1s once
+  8d10>=7        count the dice of 7 or more as successes
+  1d20+5>=15     check the total against a DC
+  1d20+5:attack  a label for the row
+  3#1d6          roll the expression 3 times
+  # ...          a comment, to the end of the line
+The flags given to roll -i, like --seed or --json, apply to every roll.
+history [N]  lists the last N rolls (20 if not given)
+!!           rolls the last line again, and !3 rolls entry 3 of the history
+help prints this, and quit or exit (or Ctrl-D) leaves.";
+
+/// The answer to one line of input.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reply {
+    /// Nothing to print, as for a blank line
+    Silent,
+    Output(String),
+    Error(String),
+    Quit,
+}
+
+/// One line rolled during the session, kept parsed for `!!` and `!N`.
+struct Entry {
+    line: String,
+    parsed: Parsed,
+    /// The totals it came to, e.g. `17`, or nothing if it wasn't rolled,
+    /// as under --expected
+    result: Option<String>,
+}
+
+/// The rolls so far, numbered from 1 in `history`. With a history file,
+/// each roll is added to it as well.
+pub(crate) struct Session<'a> {
+    history: Vec<Entry>,
+    file: Option<&'a Path>,
+    config: &'a Config,
+}
+
+impl<'a> Session<'a> {
+    /// A session starting with the rolls in `file` from earlier ones, if
+    /// given. A line that no longer parses, say for want of a --set
+    /// variable, is left out.
+    pub(crate) fn new(
+        file: Option<&'a Path>,
+        config: &'a Config,
+        options: &Options,
+        args: &Args,
+    ) -> Result<Self> {
+        let mut session = Session {
+            history: Vec::new(),
+            file,
+            config,
+        };
+        let Some(path) = file else {
+            return Ok(session);
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(session),
+            Err(e) => bail!("Can't read '{}': {}", path.display(), e),
+        };
+        for record in contents.lines() {
+            let (line, result) = match record.split_once('\t') {
+                Some((line, result)) => (line, Some(result.to_string())),
+                None => (record, None),
+            };
+            if let Ok(parsed) = parse(line, config, args, options) {
+                session.history.push(Entry {
+                    line: line.to_string(),
+                    parsed,
+                    result,
+                });
+            }
+        }
+        Ok(session)
+    }
+
+    /// The lines rolled so far, oldest first.
+    fn lines(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(|entry| entry.line.as_str())
+    }
+
+    /// Answers one `line`: rolls its expressions with `rng`, or runs it as a
+    /// command. An error is reported rather than ending the session.
+    pub(crate) fn respond(
+        &mut self,
+        line: &str,
+        args: &Args,
+        options: &Options,
+        rng: &mut impl DieRng,
+    ) -> Reply {
+        let line = line.trim();
+        let reply = match line {
+            "" => return Reply::Silent,
+            "quit" | "exit" => return Reply::Quit,
+            "help" => return Reply::Output(HELP.to_string()),
+            "history" => return Reply::Output(self.list(HISTORY_SHOWN)),
+            _ if line.starts_with("history ") => match line["history ".len()..].trim().parse() {
+                Ok(n) => return Reply::Output(self.list(n)),
+                Err(_) => Err(anyhow!(
+                    "Error: history takes a number of rolls, such as history 5."
+                )),
+            },
+            // Only a comment.
+            _ if line.starts_with('#') => return Reply::Silent,
+            _ if line.starts_with('!') => self
+                .recall(line)
+                .and_then(|(line, parsed)| self.roll(line, parsed, args, options, rng)),
+            _ => parse(line, self.config, args, options)
+                .and_then(|parsed| self.roll(line.to_string(), parsed, args, options, rng)),
+        };
+        match reply {
+            Ok(Some(output)) => Reply::Output(output),
+            Ok(None) => Reply::Silent,
+            Err(e) => Reply::Error(e.to_string()),
+        }
+    }
+
+    /// The entry `!!` or `!N` asks for.
+    fn recall(&self, line: &str) -> Result<(String, Parsed)> {
+        let entry = if line == "!!" {
+            self.history
+                .last()
+                .ok_or_else(|| anyhow!("Error: Nothing has been rolled yet."))?
+        } else {
+            let n: usize = line[1..]
+                .parse()
+                .map_err(|_| anyhow!("Error: '{}' is not !! or ! and a history number.", line))?;
+            n.checked_sub(1)
+                .and_then(|i| self.history.get(i))
+                .ok_or_else(|| anyhow!("Error: There is no roll {} in the history.", n))?
+        };
+        Ok((entry.line.clone(), entry.parsed.clone()))
+    }
+
+    /// Rolls `parsed` and adds it to the history. The same rolls are gone
+    /// over again to find the totals it records.
+    fn roll(
+        &mut self,
+        line: String,
+        parsed: Parsed,
+        args: &Args,
+        options: &Options,
+        rng: &mut impl DieRng,
+    ) -> Result<Option<String>> {
+        let mut recorder = Recorder {
+            rng,
+            faces: Vec::new(),
+        };
+        let output = run(args, options, &parsed, &mut recorder)?;
+        let analyzed = args.normalize
+            || args.expected
+            || args.histogram
+            || args.chance.is_some()
+            || args.simulate.is_some();
+        let result = if analyzed {
+            None
+        } else {
+            let replay = &mut Replay(recorder.faces.into_iter());
+            let rolled = roll_arguments(&parsed, options, replay)?;
+            let mut totals = Totals::default();
+            rolled.results.iter().for_each(|res| totals.add(res));
+            Some(format_totals(&totals))
+        };
+        if let Some(path) = self.file {
+            let record = match &result {
+                Some(result) => format!("{}\t{}", line, result),
+                None => line.clone(),
+            };
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", record))
+                .map_err(|e| anyhow!("Error: Can't write '{}': {}", path.display(), e))?;
+        }
+        self.history.push(Entry {
+            line,
+            parsed,
+            result,
+        });
+        Ok(output)
+    }
+
+    /// The last `n` rolls, numbered for `!N`.
+    fn list(&self, n: usize) -> String {
+        if self.history.is_empty() {
+            return "Nothing has been rolled yet.".to_string();
+        }
+        let start = self.history.len().saturating_sub(n);
+        let width = self.history.len().to_string().len();
+        self.history[start..]
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let number = format!("{:>width$}", start + i + 1, width = width);
+                match &entry.result {
+                    Some(result) => format!("{}  {} = {}", number, entry.line, result),
+                    None => format!("{}  {}", number, entry.line),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Parses a line of expressions, with macros expanded and the --set
+/// variables filled in.
+fn parse(line: &str, config: &Config, args: &Args, options: &Options) -> Result<Parsed> {
+    let variables = args.variables();
+    let dice = read_expressions(line.as_bytes())?
+        .iter()
+        .map(|s| substitute_variables(&config.expand(s)?, &variables))
+        .collect::<Result<Vec<_>>>()?;
+    Parsed::new(&dice, options)
+}
+
+/// Reads and rolls lines until `quit` or the end of input, keeping one
+/// `rng` for the whole session. Lines can be edited, and earlier ones
+/// recalled with the arrow keys.
+pub(crate) fn interact(
+    args: &Args,
+    config: &Config,
+    options: &Options,
+    rng: &mut impl DieRng,
+) -> Result<()> {
+    let mut session = Session::new(args.history_file.as_deref(), config, options, args)?;
+    let mut editor = DefaultEditor::new()?;
+    for line in session.lines() {
+        editor.add_history_entry(line)?;
+    }
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            // Ctrl-C drops the line being typed, as in a shell.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+        match session.respond(&line, args, options, rng) {
+            Reply::Silent => {}
+            Reply::Output(output) => println!("{}", output),
+            Reply::Error(error) => eprintln!("{}", error),
+            Reply::Quit => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::ScriptedRng;
+    use clap::Parser;
+
+    #[test]
+    fn test_respond() {
+        let args = Args::try_parse_from(["roll", "-i", "--plain", "--set", "str=3"]).unwrap();
+        let options = args.options(false);
+        let config = Config::default();
+        let mut session = Session::new(None, &config, &options, &args).unwrap();
+        let rng = &mut ScriptedRng::new(&[12, 4, 2]);
+        let mut respond =
+            |line: &str, rng: &mut ScriptedRng| session.respond(line, &args, &options, rng);
+        assert_eq!(
+            respond("1d20+str", rng),
+            Reply::Output("1d20+3: 15\ntotal: 15".to_string())
+        );
+        // A mistake is reported without ending the session.
+        assert!(matches!(respond("1dx", rng), Reply::Error(e) if e.contains("Invalid dice")));
+        assert_eq!(
+            respond("1d6 1d4 # two at once", rng),
+            Reply::Output("1d6: 4\n1d4: 2\ntotal: 6".to_string())
+        );
+        assert_eq!(respond("  ", rng), Reply::Silent);
+        assert_eq!(respond("# just a note", rng), Reply::Silent);
+        assert!(matches!(respond("help", rng), Reply::Output(help) if help.contains("1d20a")));
+        assert_eq!(respond("quit", rng), Reply::Quit);
+        assert_eq!(respond("exit", rng), Reply::Quit);
+    }
+
+    #[test]
+    fn test_history() {
+        let dir = std::env::temp_dir().join(format!("roll-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history");
+        let args = Args::try_parse_from(["roll", "-i", "--plain"]).unwrap();
+        let options = args.options(false);
+        let config = Config::default();
+        let mut session = Session::new(Some(&path), &config, &options, &args).unwrap();
+        let rng = &mut ScriptedRng::new(&[12, 3, 5, 6, 1, 20]);
+        let mut respond =
+            |line: &str, rng: &mut ScriptedRng| session.respond(line, &args, &options, rng);
+        assert_eq!(
+            respond("history", rng),
+            Reply::Output("Nothing has been rolled yet.".to_string())
+        );
+        assert!(matches!(respond("!!", rng), Reply::Error(e) if e.contains("Nothing")));
+        respond("1d20+5", rng);
+        respond("2d6:damage", rng);
+        assert_eq!(
+            respond("!!", rng),
+            Reply::Output("damage: 7\ntotal: 7".to_string())
+        );
+        assert_eq!(
+            respond("!1", rng),
+            Reply::Output("1d20+5: 25\ntotal: 25".to_string())
+        );
+        assert!(matches!(respond("!9", rng), Reply::Error(e) if e.contains("no roll 9")));
+        assert_eq!(
+            respond("history", rng),
+            Reply::Output(
+                "1  1d20+5 = 17\n2  2d6:damage = 8\n3  2d6:damage = 7\n4  1d20+5 = 25".to_string()
+            )
+        );
+        assert_eq!(
+            respond("history 2", rng),
+            Reply::Output("3  2d6:damage = 7\n4  1d20+5 = 25".to_string())
+        );
+
+        // A later session picks up where this one left off.
+        let mut session = Session::new(Some(&path), &config, &options, &args).unwrap();
+        assert_eq!(session.lines().count(), 4);
+        let rng = &mut ScriptedRng::new(&[4, 4]);
+        assert_eq!(
+            session.respond("!2", &args, &options, rng),
+            Reply::Output("damage: 8\ntotal: 8".to_string())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
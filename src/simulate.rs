@@ -0,0 +1,168 @@
+//! Rolls an expression over and over, for `--histogram`, `--simulate` and
+//! anything estimated from rolls rather than worked out exactly.
+
+use crate::{DieRng, Expression, floor_div, roll_attempts, roll_expression};
+use std::collections::BTreeMap;
+
+/// The total of one roll of `expression`, after any floor, counting only
+/// the picked attempt under `bo2(...)` or `wo2(...)`.
+pub(crate) fn roll_total(expression: &Expression, rng: &mut impl DieRng) -> i64 {
+    match expression.pick {
+        Some(pick) => roll_attempts(expression, pick, rng)
+            .iter()
+            .find(|attempt| !attempt.discarded)
+            .map_or(0, |attempt| attempt.total()),
+        None => roll_expression(expression, rng).total(),
+    }
+}
+
+/// How many of `samples` rolls came to each total.
+pub(crate) fn counts(
+    expression: &Expression,
+    samples: u64,
+    rng: &mut impl DieRng,
+) -> BTreeMap<i64, u64> {
+    let mut counts = BTreeMap::new();
+    for _ in 0..samples {
+        *counts.entry(roll_total(expression, rng)).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Most buckets an `Accumulator` keeps before it widens them.
+const MAX_BUCKETS: usize = 4096;
+
+/// Figures over any number of rolls in bounded memory: a running mean and
+/// variance (Welford's method), and a count per bucket of totals for the
+/// percentiles. Buckets start one total wide and double in width whenever
+/// there are too many, so percentiles of a huge range are rounded down to
+/// the start of their bucket.
+pub(crate) struct Accumulator {
+    pub(crate) count: u64,
+    pub(crate) min: i64,
+    pub(crate) max: i64,
+    mean: f64,
+    /// The sum of squared differences from the running mean
+    m2: f64,
+    /// Rolls per bucket, keyed by the lowest total it holds
+    buckets: BTreeMap<i64, u64>,
+    width: i64,
+}
+
+impl Accumulator {
+    pub(crate) fn new() -> Accumulator {
+        Accumulator {
+            count: 0,
+            min: i64::MAX,
+            max: i64::MIN,
+            mean: 0.0,
+            m2: 0.0,
+            buckets: BTreeMap::new(),
+            width: 1,
+        }
+    }
+
+    pub(crate) fn add(&mut self, total: i64) {
+        self.count += 1;
+        self.min = self.min.min(total);
+        self.max = self.max.max(total);
+        let delta = total as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (total as f64 - self.mean);
+        *self.buckets.entry(self.bucket(total)).or_insert(0) += 1;
+        if self.buckets.len() > MAX_BUCKETS {
+            self.width *= 2;
+            let buckets = std::mem::take(&mut self.buckets);
+            for (start, count) in buckets {
+                *self.buckets.entry(self.bucket(start)).or_insert(0) += count;
+            }
+        }
+    }
+
+    fn bucket(&self, total: i64) -> i64 {
+        floor_div(total, self.width) * self.width
+    }
+
+    pub(crate) fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /// The sample standard deviation, 0 for a single roll.
+    pub(crate) fn std_dev(&self) -> f64 {
+        if self.count > 1 {
+            (self.m2 / (self.count - 1) as f64).sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    /// The smallest total at least `percent` of the rolls came to or under,
+    /// so the 50th is the median.
+    pub(crate) fn percentile(&self, percent: f64) -> i64 {
+        let rank = ((percent / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (&start, &count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return start.max(self.min);
+            }
+        }
+        self.max
+    }
+}
+
+/// Rolls `expression` `samples` times, keeping only running figures.
+pub(crate) fn accumulate(
+    expression: &Expression,
+    samples: u64,
+    rng: &mut impl DieRng,
+) -> Accumulator {
+    let mut accumulator = Accumulator::new();
+    for _ in 0..samples {
+        accumulator.add(roll_total(expression, rng));
+    }
+    accumulator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_and_validate, tests::ScriptedRng};
+
+    #[test]
+    fn test_counts() {
+        let expression = parse_and_validate("1d6+1").unwrap();
+        let rng = &mut ScriptedRng::new(&[1, 6, 6, 3]);
+        let counts = counts(&expression, 4, rng);
+        assert_eq!(counts, BTreeMap::from([(2, 1), (4, 1), (7, 2)]));
+
+        // Only the better attempt counts.
+        let expression = parse_and_validate("bo2(1d6)~3").unwrap();
+        let rng = &mut ScriptedRng::new(&[1, 2, 5, 4]);
+        assert_eq!(roll_total(&expression, rng), 3);
+        assert_eq!(roll_total(&expression, rng), 5);
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let mut accumulator = Accumulator::new();
+        for total in [2, 4, 4, 4, 5, 5, 7, 9] {
+            accumulator.add(total);
+        }
+        assert_eq!((accumulator.min, accumulator.max), (2, 9));
+        assert!((accumulator.mean() - 5.0).abs() < 1e-9);
+        assert!((accumulator.std_dev() - (32.0f64 / 7.0).sqrt()).abs() < 1e-9);
+        assert_eq!(accumulator.percentile(50.0), 4);
+        assert_eq!(accumulator.percentile(75.0), 5);
+        assert_eq!(accumulator.percentile(100.0), 9);
+
+        // A range too wide for a bucket each is grouped, never past the ends.
+        let mut accumulator = Accumulator::new();
+        for total in 0..10_000 {
+            accumulator.add(total);
+        }
+        assert!(accumulator.buckets.len() <= MAX_BUCKETS);
+        assert_eq!(accumulator.percentile(0.0), 0);
+        assert!((accumulator.percentile(50.0) - 5000).abs() <= accumulator.width);
+    }
+}
@@ -0,0 +1,783 @@
+//! The theoretical minimum, mean and maximum of an expression, for `--stats`.
+//!
+//! Each dice term is turned into the exact chance of every total it can
+//! reach, and the terms are combined by walking the expression tree. Terms
+//! whose rules are too tangled to work out this way, such as wild dice or a
+//! rolled count, fall back to rolling the expression many times.
+
+use crate::{
+    BinaryOp, Comparison, DiceRequest, Explode, Expression, Faces, Keep, MAX_EXPLOSIONS, Node,
+    Pick, Reroll, RollMode, SeededRng, simulate,
+};
+use std::collections::BTreeMap;
+
+/// Past this many steps in all, working out a distribution is left to
+/// simulation.
+const MAX_WORK: usize = 20_000_000;
+
+/// How many times an expression is rolled when it can't be worked out exactly.
+const SAMPLES: u64 = 10_000;
+
+/// Uncapped exploding chains are followed until they are less likely than this.
+const NEGLIGIBLE: f64 = 1e-12;
+
+/// The chance of every total an expression can reach.
+#[derive(Debug, Clone, PartialEq)]
+struct Distribution {
+    probabilities: BTreeMap<i64, f64>,
+    /// Whether totals past the largest listed one can happen too, left out
+    /// because an uncapped exploding die made them vanishingly unlikely
+    open_above: bool,
+    /// The same, for totals below the smallest listed one
+    open_below: bool,
+}
+
+impl Distribution {
+    fn new(probabilities: BTreeMap<i64, f64>) -> Distribution {
+        Distribution {
+            probabilities,
+            open_above: false,
+            open_below: false,
+        }
+    }
+
+    fn constant(value: i64) -> Distribution {
+        Distribution::new(BTreeMap::from([(value, 1.0)]))
+    }
+
+    /// Every entry equally likely, so repeated faces weight the outcome.
+    fn uniform(faces: impl IntoIterator<Item = i64>) -> Distribution {
+        let faces: Vec<i64> = faces.into_iter().collect();
+        let weight = 1.0 / faces.len() as f64;
+        let mut probabilities = BTreeMap::new();
+        for face in faces {
+            *probabilities.entry(face).or_insert(0.0) += weight;
+        }
+        Distribution::new(probabilities)
+    }
+
+    fn map(&self, f: impl Fn(i64) -> i64) -> Distribution {
+        let mut probabilities = BTreeMap::new();
+        for (&value, &p) in &self.probabilities {
+            *probabilities.entry(f(value)).or_insert(0.0) += p;
+        }
+        Distribution {
+            probabilities,
+            ..*self
+        }
+    }
+
+    /// The distribution of `f(a, b)` for independent `a` and `b`, or `None`
+    /// when going through the pairs would take `work`, the steps taken so
+    /// far, past `MAX_WORK`.
+    fn combine(
+        &self,
+        other: &Distribution,
+        work: &mut usize,
+        f: impl Fn(i64, i64) -> i64,
+    ) -> Option<Distribution> {
+        *work = work.saturating_add(self.probabilities.len() * other.probabilities.len());
+        if *work > MAX_WORK {
+            return None;
+        }
+        let mut probabilities = BTreeMap::new();
+        for (&a, &p) in &self.probabilities {
+            for (&b, &q) in &other.probabilities {
+                *probabilities.entry(f(a, b)).or_insert(0.0) += p * q;
+            }
+        }
+        Some(Distribution::new(probabilities))
+    }
+
+    /// Combines two parts of an expression, working out which way an
+    /// uncapped explosion can still push the result.
+    fn apply(&self, op: BinaryOp, other: &Distribution, work: &mut usize) -> Option<Distribution> {
+        let mut result = self.combine(other, work, |a, b| op.apply(a, b))?;
+        let (a, b) = (self, other);
+        match op {
+            BinaryOp::Add => {
+                result.open_above = a.open_above || b.open_above;
+                result.open_below = a.open_below || b.open_below;
+            }
+            BinaryOp::Sub => {
+                result.open_above = a.open_above || b.open_below;
+                result.open_below = a.open_below || b.open_above;
+            }
+            // A divisor is always a constant, so only the left side matters.
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::DivUp => {
+                let scales = |open: bool, d: &Distribution, positive: bool| {
+                    open && if positive { d.max() > 0 } else { d.min() < 0 }
+                };
+                let one_way = |positive: bool| {
+                    scales(a.open_above, b, positive) || scales(a.open_below, b, !positive)
+                };
+                result.open_above = one_way(true);
+                result.open_below = one_way(false);
+                if op == BinaryOp::Mul {
+                    let other_way = |positive: bool| {
+                        scales(b.open_above, a, positive) || scales(b.open_below, a, !positive)
+                    };
+                    result.open_above |= other_way(true);
+                    result.open_below |= other_way(false);
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// The sum of `count` independent copies, built up by doubling, or
+    /// `None` if that would take `work` past `MAX_WORK`.
+    fn times(&self, count: i64, work: &mut usize) -> Option<Distribution> {
+        // The steps are counted up front, from how many totals each partial
+        // sum can reach, so a sum too big to work out costs nothing.
+        let span = self.max().abs_diff(self.min()) as usize;
+        let len = self.probabilities.len();
+        let size = |copies: i64| {
+            (copies as usize)
+                .saturating_mul(span)
+                .saturating_add(1)
+                .min(len.saturating_pow(copies.min(64) as u32))
+        };
+        let (mut steps, mut summed, mut power, mut left) = (0usize, 0, 1, count.max(0));
+        while left > 0 {
+            if left % 2 == 1 {
+                steps = steps.saturating_add(size(summed).saturating_mul(size(power)));
+                summed += power;
+            }
+            left /= 2;
+            if left > 0 {
+                steps = steps.saturating_add(size(power).saturating_mul(size(power)));
+                power *= 2;
+            }
+        }
+        if work.saturating_add(steps) > MAX_WORK {
+            return None;
+        }
+        let mut sum = Distribution::constant(0);
+        let mut power = self.clone();
+        let mut left = count.max(0);
+        while left > 0 {
+            if left % 2 == 1 {
+                sum = sum.combine(&power, work, |a, b| a + b)?;
+            }
+            left /= 2;
+            if left > 0 {
+                power = power.combine(&power, work, |a, b| a + b)?;
+            }
+        }
+        sum.open_above = count > 0 && self.open_above;
+        sum.open_below = count > 0 && self.open_below;
+        Some(sum)
+    }
+
+    /// The best (or worst) of `n` independent copies, from the chance that
+    /// every copy is at most (or at least) each value.
+    fn best_of(&self, n: i64, highest: bool) -> Distribution {
+        let mut values: Vec<(i64, f64)> =
+            self.probabilities.iter().map(|(&v, &p)| (v, p)).collect();
+        if !highest {
+            values.reverse();
+        }
+        let mut probabilities = BTreeMap::new();
+        let (mut cumulative, mut previous) = (0.0, 0.0);
+        for (value, p) in values {
+            cumulative += p;
+            let all = f64::min(cumulative, 1.0).powi(n as i32);
+            probabilities.insert(value, all - previous);
+            previous = all;
+        }
+        Distribution {
+            probabilities,
+            ..*self
+        }
+    }
+
+    /// Raises everything below `floor` to it.
+    fn floored(&self, floor: i64) -> Distribution {
+        Distribution {
+            open_below: false,
+            ..self.map(|value| value.max(floor))
+        }
+    }
+
+    fn mean(&self) -> f64 {
+        self.probabilities
+            .iter()
+            .map(|(&value, &p)| value as f64 * p)
+            .sum()
+    }
+
+    fn min(&self) -> i64 {
+        self.probabilities.keys().next().copied().unwrap_or(0)
+    }
+
+    fn max(&self) -> i64 {
+        self.probabilities.keys().next_back().copied().unwrap_or(0)
+    }
+
+    fn prune(&mut self) {
+        self.probabilities.retain(|_, p| *p >= NEGLIGIBLE);
+    }
+}
+
+/// An expression's theoretical range and average.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct Summary {
+    /// `None` when a die can explode without limit below it
+    pub(crate) min: Option<i64>,
+    pub(crate) mean: f64,
+    /// `None` when a die can explode without limit
+    pub(crate) max: Option<i64>,
+    /// Whether this was worked out rather than estimated from rolls
+    pub(crate) exact: bool,
+}
+
+/// Works out the range and average of `root`, raised to `floor`. Text dice
+/// have no numeric value, so an expression with any has no summary.
+pub(crate) fn summarize(root: &Node, floor: Option<i64>) -> Option<Summary> {
+    if root
+        .dice()
+        .iter()
+        .any(|req| matches!(req.faces, Faces::Text(_)))
+    {
+        return None;
+    }
+    let (distribution, exact) = match node(root, &mut 0) {
+        Some(distribution) => (distribution, true),
+        None => (simulate(root), false),
+    };
+    let distribution = floored(distribution, floor);
+    Some(Summary {
+        min: (!distribution.open_below).then(|| distribution.min()),
+        mean: distribution.mean(),
+        max: (!distribution.open_above).then(|| distribution.max()),
+        exact,
+    })
+}
+
+/// The exact average total of one roll of `expression`, taking the better
+/// or worse attempt under `bo2(...)` or `wo2(...)`, or `None` when it can't
+/// be worked out without rolling.
+pub(crate) fn expected(expression: &Expression) -> Option<f64> {
+    match (expression.pick, expression.floor) {
+        (None, None) => mean(&expression.root),
+        _ => Some(total(expression)?.mean()),
+    }
+}
+
+/// The average of `node`. Averages add and multiply, so a sum of dice
+/// needs only one die's distribution rather than every total it can reach;
+/// keep rules and division still need the whole distribution.
+fn mean(node: &Node) -> Option<f64> {
+    match node {
+        Node::Dice(req) if req.keep.is_none() && independent(req) => {
+            Some(die(req)?.map(|value| score(req, value)).mean() * req.count as f64)
+        }
+        Node::Constant(n) => Some(*n as f64),
+        Node::Binary(BinaryOp::Add, lhs, rhs) => Some(mean(lhs)? + mean(rhs)?),
+        Node::Binary(BinaryOp::Sub, lhs, rhs) => Some(mean(lhs)? - mean(rhs)?),
+        Node::Binary(BinaryOp::Mul, lhs, rhs) => Some(mean(lhs)? * mean(rhs)?),
+        Node::Group(inner) => mean(inner),
+        node => Some(self::node(node, &mut 0)?.mean()),
+    }
+}
+
+/// The exact chance that one roll of `expression` meets `target`, or `None`
+/// when it can't be worked out without rolling.
+pub(crate) fn chance(expression: &Expression, target: Comparison) -> Option<f64> {
+    let distribution = total(expression)?;
+    Some(
+        distribution
+            .probabilities
+            .iter()
+            .filter(|&(&total, _)| target.matches(total))
+            .map(|(_, &p)| p)
+            .sum(),
+    )
+}
+
+/// The distribution of one roll's final total.
+fn total(expression: &Expression) -> Option<Distribution> {
+    let distribution = floored(node(&expression.root, &mut 0)?, expression.floor);
+    Some(match expression.pick {
+        Some(Pick::Best(n)) => distribution.best_of(n, true),
+        Some(Pick::Worst(n)) => distribution.best_of(n, false),
+        None => distribution,
+    })
+}
+
+/// `value` as a fraction in lowest terms, if one with a denominator of at
+/// most 10,000 matches it. Dice averages are always fractions, so this only
+/// fails for the rare one with a larger denominator.
+pub(crate) fn as_fraction(value: f64) -> Option<(i64, i64)> {
+    (1..=10_000).find_map(|denominator| {
+        let numerator = (value * denominator as f64).round();
+        let close = (numerator / denominator as f64 - value).abs() < 1e-9 * value.abs().max(1.0);
+        close.then_some((numerator as i64, denominator))
+    })
+}
+
+fn floored(distribution: Distribution, floor: Option<i64>) -> Distribution {
+    match floor {
+        Some(floor) => distribution.floored(floor),
+        None => distribution,
+    }
+}
+
+/// Rolls the expression `SAMPLES` times with a fixed seed, so the estimate
+/// is the same every time.
+fn simulate(root: &Node) -> Distribution {
+    let expression = Expression {
+        repeat: 1,
+        root: root.clone(),
+        label: None,
+        check: None,
+        side: None,
+        floor: None,
+        pick: None,
+    };
+    let counts = simulate::counts(&expression, SAMPLES, &mut SeededRng(0));
+    Distribution::new(
+        counts
+            .into_iter()
+            .map(|(total, count)| (total, count as f64 / SAMPLES as f64))
+            .collect(),
+    )
+}
+
+/// The distribution of `node`, or `None` if it can't be worked out or would
+/// take `work`, the steps taken so far, past `MAX_WORK`.
+fn node(node: &Node, work: &mut usize) -> Option<Distribution> {
+    match node {
+        Node::Dice(req) => term(req, work),
+        Node::Constant(n) => Some(Distribution::constant(*n)),
+        Node::Binary(op, lhs, rhs) => {
+            let lhs = self::node(lhs, work)?;
+            lhs.apply(*op, &self::node(rhs, work)?, work)
+        }
+        Node::Group(inner) => self::node(inner, work),
+    }
+}
+
+/// Whether a term's dice can be worked out one at a time: rules that tie
+/// them together, or that count each link of an exploding chain, can't.
+fn independent(req: &DiceRequest) -> bool {
+    let per_roll = matches!(req.explode, Some(Explode::Standard | Explode::Penetrating));
+    !(req.count_roll.is_some()
+        || req.wild.is_some()
+        || req.brutal
+        || req.unique
+        || (req.success.is_some() && per_roll))
+}
+
+/// What a die showing `value` adds to its term: the value itself, or its
+/// net successes for a pool.
+fn score(req: &DiceRequest, value: i64) -> i64 {
+    match req.success {
+        Some(success) => {
+            i64::from(success.matches(value))
+                - req.failure.map_or(0, |f| i64::from(f.matches(value)))
+        }
+        None => value,
+    }
+}
+
+/// One dice term's total, or its net successes for a pool.
+fn term(req: &DiceRequest, work: &mut usize) -> Option<Distribution> {
+    if !independent(req) {
+        return None;
+    }
+    let die = die(req)?;
+    let score = |value: i64| score(req, value);
+    let (kept, highest) = match req.keep {
+        None => return die.map(score).times(req.count, work),
+        Some(Keep::Highest(n)) => (n, true),
+        Some(Keep::Lowest(n)) => (n, false),
+        Some(Keep::DropLowest(n)) => (req.count - n, true),
+        Some(Keep::DropHighest(n)) => (req.count - n, false),
+        Some(Keep::Middle(_)) => return None,
+    };
+    keep_best(&die, req.count, kept, highest, score, work)
+}
+
+/// The sum of `score` over the `kept` highest (or lowest) of `count` dice,
+/// worked out face by face from the best down: of the dice not yet placed,
+/// any number may show this face, and as many as are still needed are kept.
+fn keep_best(
+    die: &Distribution,
+    count: i64,
+    kept: i64,
+    highest: bool,
+    score: impl Fn(i64) -> i64,
+    work: &mut usize,
+) -> Option<Distribution> {
+    let count = count.max(0) as usize;
+    let kept = kept.clamp(0, count as i64) as usize;
+    let mut faces: Vec<(i64, f64)> = die.probabilities.iter().map(|(&v, &p)| (v, p)).collect();
+    if highest {
+        faces.reverse();
+    }
+    let spread = faces
+        .iter()
+        .map(|&(v, _)| score(v).unsigned_abs() as usize)
+        .max()
+        .unwrap_or(0);
+    let steps = faces.len() * (count + 1) * (kept + 1) * (count + 1) * (kept * spread * 2 + 1);
+    *work = work.saturating_add(steps);
+    if *work > MAX_WORK {
+        return None;
+    }
+    // Dice left to place and dice still to keep, with the chance of each
+    // sum of the dice kept so far.
+    let mut states: BTreeMap<(usize, usize), BTreeMap<i64, f64>> = BTreeMap::new();
+    let mut finished: BTreeMap<i64, f64> = BTreeMap::new();
+    if kept == 0 {
+        finished.insert(0, 1.0);
+    } else {
+        states.insert((count, kept), BTreeMap::from([(0, 1.0)]));
+    }
+    // The chance of a face no better than the current one.
+    let mut remaining = 1.0;
+    for (i, &(value, p)) in faces.iter().enumerate() {
+        let q = if i + 1 == faces.len() {
+            1.0
+        } else {
+            (p / remaining).min(1.0)
+        };
+        remaining -= p;
+        let mut next: BTreeMap<(usize, usize), BTreeMap<i64, f64>> = BTreeMap::new();
+        for ((left, needed), sums) in states {
+            for shown in 0..=left {
+                let chance = binomial(left, shown)
+                    * q.powi(shown as i32)
+                    * (1.0 - q).powi((left - shown) as i32);
+                if chance == 0.0 {
+                    continue;
+                }
+                let taken = shown.min(needed);
+                let target = if needed == taken {
+                    &mut finished
+                } else {
+                    next.entry((left - shown, needed - taken)).or_default()
+                };
+                for (&sum, &sp) in &sums {
+                    *target
+                        .entry(sum + score(value) * taken as i64)
+                        .or_insert(0.0) += sp * chance;
+                }
+            }
+        }
+        states = next;
+    }
+    Some(Distribution {
+        probabilities: finished,
+        ..*die
+    })
+}
+
+fn binomial(n: usize, k: usize) -> f64 {
+    (0..k).fold(1.0, |acc, i| acc * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// One die's value: a throw through its reroll rule, explosions and luck,
+/// clamped and adjusted by `e`, then the best or worst of several throws
+/// under advantage or disadvantage.
+fn die(req: &DiceRequest) -> Option<Distribution> {
+    let face = face(req)?;
+    // Each way the reroll rule can go, as the first roll of the chain, the
+    // face that may explode, the sum so far and its chance.
+    let mut starts: Vec<(i64, i64, i64, f64)> = Vec::new();
+    for (&v, &p) in &face.probabilities {
+        match req.reroll {
+            Some(Reroll::Once(condition)) if condition.matches(v) => {
+                starts.extend(face.probabilities.iter().map(|(&w, &q)| (w, w, w, p * q)));
+            }
+            Some(Reroll::Add(condition)) if condition.matches(v) => {
+                starts.extend(
+                    face.probabilities
+                        .iter()
+                        .map(|(&w, &q)| (v, w, v + w, p * q)),
+                );
+            }
+            Some(Reroll::Recursive(condition)) if condition.matches(v) => {}
+            _ => starts.push((v, v, v, p)),
+        }
+    }
+    if let Some(Reroll::Recursive(_)) = req.reroll {
+        let total: f64 = starts.iter().map(|start| start.3).sum();
+        if total == 0.0 {
+            return None;
+        }
+        for start in &mut starts {
+            start.3 /= total;
+        }
+    }
+
+    let limit = req
+        .explode_cap
+        .map_or(MAX_EXPLOSIONS, |cap| (cap as usize).min(MAX_EXPLOSIONS));
+    let tail = match req.explode {
+        Some(explode) if limit > 0 => Some(explosions(req, &face, explode, limit)?),
+        _ => None,
+    };
+    // The raw chain, split by whether its first roll was a natural 1.
+    let mut ones = BTreeMap::new();
+    let mut others = BTreeMap::new();
+    for (first, last, sum, p) in starts {
+        let chain = if req.lucky && first == 1 {
+            &mut ones
+        } else {
+            &mut others
+        };
+        match &tail {
+            Some(tail) if req.explodes_on(last) => {
+                for (&extra, &q) in &tail.probabilities {
+                    *chain.entry(sum + extra).or_insert(0.0) += p * q;
+                }
+            }
+            _ => *chain.entry(sum).or_insert(0.0) += p,
+        }
+    }
+    // Luck throws away a chain starting on 1 and rolls the whole thing again.
+    let luck: f64 = ones.values().sum();
+    if luck > 0.0 {
+        let mut again = others.clone();
+        for (value, p) in ones {
+            *again.entry(value).or_insert(0.0) += p;
+        }
+        for (value, p) in again {
+            *others.entry(value).or_insert(0.0) += luck * p;
+        }
+    }
+    let raw = Distribution {
+        probabilities: others,
+        open_above: req.explode.is_some() && req.explode_cap.is_none(),
+        open_below: false,
+    };
+
+    let value = raw.map(|raw| {
+        let value = req.minimum.map_or(raw, |min| raw.max(min));
+        let value = req.maximum.map_or(value, |max| value.min(max));
+        value + req.each.unwrap_or(0)
+    });
+    let value = Distribution {
+        open_above: raw.open_above && req.maximum.is_none(),
+        ..value
+    };
+    Some(match req.mode {
+        RollMode::Normal => value,
+        RollMode::Advantage(n) => value.best_of(n, true),
+        RollMode::Disadvantage(n) => value.best_of(n, false),
+    })
+}
+
+/// What an explosion adds to a chain when up to `limit` more may follow.
+/// An uncapped chain drops the lengths that are too unlikely to matter.
+fn explosions(
+    req: &DiceRequest,
+    face: &Distribution,
+    explode: Explode,
+    limit: usize,
+) -> Option<Distribution> {
+    let penalty = i64::from(explode == Explode::Penetrating);
+    let mut tail = face.map(|w| w - penalty);
+    for _ in 1..limit {
+        if face.probabilities.len() * tail.probabilities.len() > MAX_WORK {
+            return None;
+        }
+        let mut probabilities = BTreeMap::new();
+        for (&w, &p) in &face.probabilities {
+            if req.explodes_on(w) {
+                for (&extra, &q) in &tail.probabilities {
+                    *probabilities.entry(w - penalty + extra).or_insert(0.0) += p * q;
+                }
+            } else {
+                *probabilities.entry(w - penalty).or_insert(0.0) += p;
+            }
+        }
+        tail = Distribution::new(probabilities);
+        if req.explode_cap.is_none() {
+            tail.prune();
+        }
+    }
+    Some(tail)
+}
+
+/// A single throw. Digit dice with too many combinations to list, and text
+/// dice, have none.
+fn face(req: &DiceRequest) -> Option<Distribution> {
+    if req.distinct_faces() > 100_000 {
+        return None;
+    }
+    Some(match &req.faces {
+        Faces::Standard | Faces::ZeroBased | Faces::Fate => {
+            Distribution::uniform(req.min_face()..=req.max_face())
+        }
+        Faces::Percentile => Distribution::uniform(1..=100),
+        Faces::Custom(faces) => Distribution::uniform(faces.iter().copied()),
+        Faces::Digits { base, digits } => {
+            Distribution::uniform((0..*digits).fold(vec![0], |numbers, _| {
+                numbers
+                    .iter()
+                    .flat_map(|n| (1..=*base).map(move |digit| n * 10 + digit))
+                    .collect()
+            }))
+        }
+        Faces::Text(_) => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_and_validate;
+
+    fn summary(input: &str) -> Summary {
+        let expression = parse_and_validate(input).unwrap();
+        summarize(&expression.root, expression.floor).unwrap()
+    }
+
+    fn assert_mean(input: &str, mean: f64) {
+        let summary = summary(input);
+        assert!(summary.exact, "{} should be exact", input);
+        assert!(
+            (summary.mean - mean).abs() < 1e-9,
+            "{}: {} != {}",
+            input,
+            summary.mean,
+            mean
+        );
+    }
+
+    #[test]
+    fn test_known_means() {
+        assert_mean("3d6", 10.5);
+        assert_mean("2d20kh1", 13.825);
+        assert_mean("1d20a", 13.825);
+        assert_mean("1d20d", 7.175);
+        assert_mean("1d20+5", 15.5);
+        assert_mean("2d6*10", 70.0);
+        assert_mean("4dF", 0.0);
+        assert_mean("1d6!", 4.2);
+        assert_mean("2d6ro1", 2.0 * (5.0 * 4.0 + 3.5) / 6.0);
+        assert_mean("4d6dl1", 15869.0 / 1296.0);
+        assert_mean("8d10>=7", 3.2);
+        assert_mean("1d4-3~0", 0.25);
+    }
+
+    #[test]
+    fn test_range() {
+        let s = summary("4d6dl1+2");
+        assert_eq!((s.min, s.max), (Some(5), Some(20)));
+        let s = summary("1d20-1d6!");
+        assert_eq!((s.min, s.max), (None, Some(19)));
+        let s = summary("1d6!2");
+        assert_eq!((s.min, s.max), (Some(1), Some(18)));
+        let s = summary("1d6!max10");
+        assert_eq!((s.min, s.max), (Some(1), Some(10)));
+    }
+
+    #[test]
+    fn test_times() {
+        let d6 = Distribution::uniform(1..=6);
+        let mut sum = Distribution::constant(0);
+        for count in 0..=7 {
+            let doubled = d6.times(count, &mut 0).unwrap();
+            assert_eq!(
+                doubled.probabilities.len(),
+                sum.probabilities.len(),
+                "{}",
+                count
+            );
+            for (value, p) in &sum.probabilities {
+                assert!(
+                    (doubled.probabilities[value] - p).abs() < 1e-12,
+                    "{}",
+                    count
+                );
+            }
+            sum = sum.combine(&d6, &mut 0, |a, b| a + b).unwrap();
+        }
+        // The budget covers every step, not just the largest, and every
+        // term of an expression.
+        assert!(d6.times(2000, &mut 0).is_none());
+        assert!(d6.times(10, &mut (MAX_WORK - 100)).is_none());
+        let root = parse_and_validate("10d6+10d6").unwrap().root;
+        let mut work = 0;
+        assert!(node(&root, &mut work).is_some());
+        assert!(node(&root, &mut (MAX_WORK - work + 1)).is_none());
+    }
+
+    #[test]
+    fn test_falls_back_to_simulation() {
+        let s = summary("1d8!w");
+        assert!(!s.exact);
+        assert!(s.mean > 5.0 && s.mean < 7.0);
+        assert!(summarize(&parse_and_validate("d{yes,no}").unwrap().root, None).is_none());
+    }
+
+    fn expected_value(input: &str) -> f64 {
+        expected(&parse_and_validate(input).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_expected() {
+        let close = |input: &str, value: f64| {
+            let mean = expected_value(input);
+            assert!(
+                (mean - value).abs() < 1e-9,
+                "{}: {} != {}",
+                input,
+                mean,
+                value
+            );
+        };
+        // 2d6 averages 7, plus 3.
+        close("2d6+3", 10.0);
+        // 4.5 + 7 + 4
+        close("1d8+2d6+4", 15.5);
+        // Advantage: P(max <= k) = (k/20)^2, so the mean is 20 - sum of
+        // (k/20)^2 for k in 0..20 = 20 - 2470/400.
+        close("1d20a", 20.0 - 2470.0 / 400.0);
+        // Disadvantage mirrors it: 21 - 13.825, plus a d4's 2.5.
+        close("1d20d+1d4", 7.175 + 2.5);
+        // The better of two d6s: 161/36.
+        close("bo2(1d6)", 161.0 / 36.0);
+        // Half of the totals are odd and lose 0.5 to rounding down: 3.5 - 0.25.
+        close("2d6/2", 117.0 / 36.0);
+        // A sum's average needs no distribution, however many dice.
+        close("10000d6+2*1d8", 35009.0);
+        assert!(expected(&parse_and_validate("1d8!w").unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_as_fraction() {
+        assert_eq!(as_fraction(13.825), Some((553, 40)));
+        assert_eq!(as_fraction(10.0), Some((10, 1)));
+        assert_eq!(as_fraction(-2.5), Some((-5, 2)));
+        assert_eq!(as_fraction(std::f64::consts::PI), None);
+    }
+
+    #[test]
+    fn test_chance() {
+        let chance_of = |input: &str, target: &str| {
+            let (_, target) = crate::parse_target(target).unwrap();
+            chance(&parse_and_validate(input).unwrap(), target).unwrap()
+        };
+        let close = |p: f64, q: f64| assert!((p - q).abs() < 1e-9, "{} != {}", p, q);
+        // 10 to 20 on the d20 is 11 faces.
+        close(chance_of("1d20+5", ">=15"), 0.55);
+        // Missing with advantage means both dice miss: 1 - 0.45^2.
+        close(chance_of("1d20a+5", ">=15"), 1.0 - 0.45 * 0.45);
+        // 2d6 shows 7 on six of its 36 pairs.
+        close(chance_of("2d6", "=7"), 6.0 / 36.0);
+        close(chance_of("3d6", "<=5"), 10.0 / 216.0);
+        assert!(
+            chance(
+                &parse_and_validate("1d8!w").unwrap(),
+                crate::parse_target(">=4").unwrap().1
+            )
+            .is_none()
+        );
+    }
+}